@@ -0,0 +1,465 @@
+//! Key agent keeping the portfolio key in memory, so it does not have to be typed for every command.
+//!
+//! The agent listens on a Unix socket readable only by the current user and speaks a minimal
+//! line-based protocol: `GET`, `ADD <key>`, `CLEAR` and `STOP`. Every request is answered with
+//! `OK [<key>]`, `NONE` or `ERR <message>`. The key is forgotten after the configured timeout.
+//!
+//! Socket path may be predictable, so before connecting or binding, the socket and its directory
+//! must be owned by the current user, must not be symbolic links and the directory must be private
+//! (`0700`). The peer on the other end of the socket must be the current user too.
+
+use std::os::unix::fs::{DirBuilderExt, MetadataExt, PermissionsExt};
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{UnixListener, UnixStream};
+use tokio::sync::Notify;
+use tokio::time::Instant;
+
+use crate::error;
+use crate::secret::Secret;
+use zeroize::Zeroizing;
+
+/// Maximum time a client may take to send its request.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Time to wait before accepting connections again after accepting one failed.
+const ACCEPT_RETRY_DELAY: Duration = Duration::from_millis(100);
+
+/// Path of the agent socket.
+///
+/// `PORTFEL_AGENT_SOCK` takes precedence, then `$XDG_RUNTIME_DIR/portfel/agent.sock`, falling back
+/// to a per-user directory in the system temporary directory.
+pub fn socket_path() -> PathBuf {
+    if let Ok(path) = std::env::var("PORTFEL_AGENT_SOCK") {
+        return PathBuf::from(path);
+    }
+    let dir = match std::env::var("XDG_RUNTIME_DIR") {
+        Ok(runtime_dir) => PathBuf::from(runtime_dir).join("portfel"),
+        Err(_) => std::env::temp_dir().join(format!(
+            "portfel-{}",
+            std::env::var("USER").unwrap_or_default()
+        )),
+    };
+    dir.join("agent.sock")
+}
+
+fn current_uid() -> u32 {
+    // SAFETY: getuid has no preconditions and cannot fail
+    unsafe { libc::getuid() }
+}
+
+/// Check `path` is not a symbolic link, is owned by the current user and, if `mode` is given, has
+/// exactly these permissions.
+fn check_owned(path: &Path, mode: Option<u32>) -> Result<(), error::AgentError> {
+    let insecure = |reason: String| error::AgentError::Insecure(path.to_path_buf(), reason);
+    let metadata = std::fs::symlink_metadata(path)?;
+    if metadata.file_type().is_symlink() {
+        return Err(insecure("is a symbolic link".to_string()));
+    }
+    if metadata.uid() != current_uid() {
+        return Err(insecure("is owned by another user".to_string()));
+    }
+    match mode {
+        Some(mode) if metadata.mode() & 0o777 != mode => Err(insecure(format!(
+            "has permissions {:o} instead of {:o}",
+            metadata.mode() & 0o777,
+            mode
+        ))),
+        _ => Ok(()),
+    }
+}
+
+/// Check socket directory of `path`, if any, is private to the current user.
+fn check_dir(path: &Path) -> Result<(), error::AgentError> {
+    match path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+        Some(dir) => check_owned(dir, Some(0o700)),
+        None => Ok(()),
+    }
+}
+
+/// Connect to agent at `path`, once both the socket and the agent are known to be ours.
+async fn connect(path: &Path) -> Result<UnixStream, error::AgentError> {
+    let not_running = |e: error::AgentError| match e {
+        error::AgentError::IoError(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            error::AgentError::NotRunning
+        }
+        e => e,
+    };
+    check_dir(path).map_err(not_running)?;
+    check_owned(path, None).map_err(not_running)?;
+    let stream = UnixStream::connect(path)
+        .await
+        .map_err(|_| error::AgentError::NotRunning)?;
+    if stream.peer_cred()?.uid() != current_uid() {
+        return Err(error::AgentError::Insecure(
+            path.to_path_buf(),
+            "agent is run by another user".to_string(),
+        ));
+    }
+    Ok(stream)
+}
+
+async fn request(path: &Path, request: &str) -> Result<Option<Secret>, error::AgentError> {
+    let mut stream = connect(path).await?;
+    stream
+        .write_all(Zeroizing::new(format!("{}\n", request)).as_bytes())
+        .await?;
+
+    let mut response = Zeroizing::new(String::new());
+    BufReader::new(stream).read_line(&mut response).await?;
+    let response = response.trim_end_matches('\n');
+
+    match response.split_once(' ').unwrap_or((response, "")) {
+        ("OK", "") => Ok(None),
+        ("OK", key) => Ok(Some(Secret::from(key))),
+        ("NONE", _) => Ok(None),
+        ("ERR", message) => Err(error::AgentError::ProtocolError(message.to_string())),
+        _ => Err(error::AgentError::ProtocolError(response.to_string())),
+    }
+}
+
+/// Get the key held by the agent at `path`, if the agent is running and holds one.
+pub async fn get_key_at(path: &Path) -> Option<Secret> {
+    match request(path, "GET").await {
+        Ok(key) => key,
+        Err(error::AgentError::NotRunning) => None,
+        Err(e) => {
+            log::warn!("Key agent error: {}", e);
+            None
+        }
+    }
+}
+
+pub async fn get_key() -> Option<Secret> {
+    get_key_at(&socket_path()).await
+}
+
+pub async fn add_key_at(path: &Path, key: &Secret) -> Result<(), error::AgentError> {
+    if key.expose().contains('\n') {
+        return Err(error::AgentError::ProtocolError(
+            "key must not contain new lines".to_string(),
+        ));
+    }
+    request(path, &Zeroizing::new(format!("ADD {}", key.expose())))
+        .await
+        .map(|_| ())
+}
+
+pub async fn add_key(key: &Secret) -> Result<(), error::AgentError> {
+    add_key_at(&socket_path(), key).await
+}
+
+pub async fn clear() -> Result<(), error::AgentError> {
+    request(&socket_path(), "CLEAR").await.map(|_| ())
+}
+
+pub async fn stop() -> Result<(), error::AgentError> {
+    request(&socket_path(), "STOP").await.map(|_| ())
+}
+
+/// Start agent in the background by re-running current executable with `agent serve`.
+///
+/// Returns once the agent accepts connections.
+pub async fn start(timeout: Duration) -> Result<PathBuf, error::AgentError> {
+    let path = socket_path();
+    match connect(&path).await {
+        Ok(_) => return Err(error::AgentError::AlreadyRunning(path)),
+        Err(error::AgentError::NotRunning) => {}
+        Err(e) => return Err(e),
+    }
+
+    std::process::Command::new(std::env::current_exe()?)
+        .args([
+            "agent",
+            "serve",
+            "--timeout",
+            &timeout.as_secs().to_string(),
+        ])
+        .stdin(std::process::Stdio::null())
+        .stdout(std::process::Stdio::null())
+        .stderr(std::process::Stdio::null())
+        .process_group(0)
+        .spawn()?;
+
+    for _ in 0..50 {
+        if connect(&path).await.is_ok() {
+            return Ok(path);
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    Err(error::AgentError::NotRunning)
+}
+
+/// Bind agent socket at `path`, accessible only by the current user.
+///
+/// Fails if an agent is already listening at `path`.
+async fn bind(path: &Path) -> Result<UnixListener, error::AgentError> {
+    if let Some(dir) = path
+        .parent()
+        .filter(|dir| !dir.as_os_str().is_empty() && std::fs::symlink_metadata(dir).is_err())
+    {
+        std::fs::DirBuilder::new()
+            .recursive(true)
+            .mode(0o700)
+            .create(dir)?;
+    }
+    check_dir(path)?;
+    if std::fs::symlink_metadata(path).is_ok() {
+        check_owned(path, None)?;
+        if UnixStream::connect(path).await.is_ok() {
+            return Err(error::AgentError::AlreadyRunning(path.to_path_buf()));
+        }
+        // Stale socket left by an agent that did not shut down cleanly
+        std::fs::remove_file(path)?;
+    }
+    let listener = UnixListener::bind(path)?;
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600))?;
+    Ok(listener)
+}
+
+struct HeldKey {
+    key: Secret,
+    expires_at: Instant,
+}
+
+/// State of a running agent, shared by tasks serving its connections.
+#[derive(Default)]
+struct Agent {
+    held_key: std::sync::Mutex<Option<HeldKey>>,
+    /// Held key was added or cleared, so its expiry has to be looked at again
+    changed: Notify,
+    stop: Notify,
+}
+
+/// Run the agent in foreground, serving requests on `path` until `STOP` is received.
+///
+/// Every added key is forgotten `timeout` after it was added. Every connection is served by a task
+/// of its own, so a client that never sends its request does not hold up other clients.
+pub async fn serve(path: &Path, timeout: Duration) -> Result<(), error::AgentError> {
+    let listener = bind(path).await?;
+    let owner = std::fs::metadata(path)?.uid();
+    let agent = Arc::new(Agent::default());
+
+    loop {
+        let expires_at = agent
+            .held_key
+            .lock()
+            .unwrap()
+            .as_ref()
+            .map(|held_key| held_key.expires_at);
+        tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok((stream, _)) => {
+                    tokio::spawn(serve_connection(stream, owner, agent.clone(), timeout));
+                }
+                // E.g. out of file descriptors, which is over once some connections are closed
+                Err(e) => {
+                    log::warn!("Unable to accept connection: {}", e);
+                    tokio::time::sleep(ACCEPT_RETRY_DELAY).await;
+                }
+            },
+            _ = tokio::time::sleep_until(expires_at.unwrap_or_else(Instant::now)), if expires_at.is_some() => {
+                let mut held_key = agent.held_key.lock().unwrap();
+                if held_key.as_ref().is_some_and(|held_key| held_key.expires_at <= Instant::now()) {
+                    log::info!("Key expired");
+                    *held_key = None;
+                }
+            }
+            _ = agent.changed.notified() => {}
+            _ = agent.stop.notified() => break,
+        }
+    }
+
+    std::fs::remove_file(path)?;
+    Ok(())
+}
+
+/// Answer single request of a client connected on `stream`.
+async fn serve_connection(
+    mut stream: UnixStream,
+    owner: u32,
+    agent: Arc<Agent>,
+    timeout: Duration,
+) {
+    if stream.peer_cred().map(|cred| cred.uid()).ok() != Some(owner) {
+        log::warn!("Rejected connection from another user");
+        return;
+    }
+
+    let mut line = Zeroizing::new(String::new());
+    let mut reader = BufReader::new(&mut stream);
+    match tokio::time::timeout(REQUEST_TIMEOUT, reader.read_line(&mut line)).await {
+        Ok(Ok(_)) => {}
+        _ => return,
+    }
+    let line = line.trim_end_matches('\n');
+
+    let (response, stop) = {
+        let mut held_key = agent.held_key.lock().unwrap();
+        match line.split_once(' ').unwrap_or((line, "")) {
+            ("GET", _) => match &*held_key {
+                Some(held_key) => (format!("OK {}", held_key.key.expose()), false),
+                None => ("NONE".to_string(), false),
+            },
+            ("ADD", "") => ("ERR key missing".to_string(), false),
+            ("ADD", key) => {
+                *held_key = Some(HeldKey {
+                    key: Secret::from(key),
+                    expires_at: Instant::now() + timeout,
+                });
+                agent.changed.notify_one();
+                ("OK".to_string(), false)
+            }
+            ("CLEAR", _) => {
+                *held_key = None;
+                agent.changed.notify_one();
+                ("OK".to_string(), false)
+            }
+            ("STOP", _) => ("OK".to_string(), true),
+            _ => ("ERR unknown request".to_string(), false),
+        }
+    };
+    let response = Zeroizing::new(response + "\n");
+    // Client might have gone away already, nothing to do about that
+    let _ = stream.write_all(response.as_bytes()).await;
+
+    if stop {
+        agent.stop.notify_one();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Temporary socket directory, private as required by the agent.
+    fn socket_dir() -> tempfile::TempDir {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::set_permissions(dir.path(), std::fs::Permissions::from_mode(0o700)).unwrap();
+        dir
+    }
+
+    #[tokio::test]
+    async fn add_get_clear_stop() {
+        let dir = socket_dir();
+        let path = dir.path().join("agent.sock");
+        let agent = tokio::spawn({
+            let path = path.clone();
+            async move { serve(&path, Duration::from_secs(60)).await }
+        });
+        while UnixStream::connect(&path).await.is_err() {
+            tokio::task::yield_now().await;
+        }
+        assert_eq!(
+            std::fs::metadata(&path).unwrap().permissions().mode() & 0o777,
+            0o600
+        );
+
+        assert_eq!(get_key_at(&path).await, None);
+        add_key_at(&path, &Secret::from("secret key"))
+            .await
+            .unwrap();
+        assert_eq!(get_key_at(&path).await, Some(Secret::from("secret key")));
+        request(&path, "CLEAR").await.unwrap();
+        assert_eq!(get_key_at(&path).await, None);
+
+        request(&path, "STOP").await.unwrap();
+        agent.await.unwrap().unwrap();
+        assert!(!path.exists());
+        assert!(matches!(
+            request(&path, "GET").await,
+            Err(error::AgentError::NotRunning)
+        ));
+    }
+
+    #[tokio::test]
+    async fn insecure_socket_path() {
+        let dir = socket_dir();
+        let path = dir.path().join("agent.sock");
+        let target = dir.path().join("target.sock");
+        std::fs::write(&target, "").unwrap();
+        std::os::unix::fs::symlink(&target, &path).unwrap();
+        assert!(matches!(
+            request(&path, "GET").await,
+            Err(error::AgentError::Insecure(..))
+        ));
+        assert!(matches!(
+            serve(&path, Duration::from_secs(60)).await,
+            Err(error::AgentError::Insecure(..))
+        ));
+        assert!(target.exists());
+
+        std::fs::remove_file(&path).unwrap();
+        std::fs::set_permissions(dir.path(), std::fs::Permissions::from_mode(0o755)).unwrap();
+        assert!(matches!(
+            serve(&path, Duration::from_secs(60)).await,
+            Err(error::AgentError::Insecure(..))
+        ));
+    }
+
+    #[tokio::test]
+    async fn live_socket_kept() {
+        let dir = socket_dir();
+        let path = dir.path().join("agent.sock");
+        tokio::spawn({
+            let path = path.clone();
+            async move { serve(&path, Duration::from_secs(60)).await }
+        });
+        while UnixStream::connect(&path).await.is_err() {
+            tokio::task::yield_now().await;
+        }
+        assert!(matches!(
+            serve(&path, Duration::from_secs(60)).await,
+            Err(error::AgentError::AlreadyRunning(_))
+        ));
+        request(&path, "STOP").await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn key_expires() {
+        let dir = socket_dir();
+        let path = dir.path().join("agent.sock");
+        tokio::spawn({
+            let path = path.clone();
+            async move { serve(&path, Duration::from_millis(50)).await }
+        });
+        while UnixStream::connect(&path).await.is_err() {
+            tokio::task::yield_now().await;
+        }
+
+        add_key_at(&path, &Secret::from("secret key"))
+            .await
+            .unwrap();
+        assert_eq!(get_key_at(&path).await, Some(Secret::from("secret key")));
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(get_key_at(&path).await, None);
+    }
+
+    #[tokio::test]
+    async fn idle_client_does_not_block() {
+        let dir = socket_dir();
+        let path = dir.path().join("agent.sock");
+        tokio::spawn({
+            let path = path.clone();
+            async move { serve(&path, Duration::from_secs(60)).await }
+        });
+        while UnixStream::connect(&path).await.is_err() {
+            tokio::task::yield_now().await;
+        }
+
+        // Connected, but never sends its request
+        let _idle = UnixStream::connect(&path).await.unwrap();
+        let added = tokio::time::timeout(
+            REQUEST_TIMEOUT / 2,
+            add_key_at(&path, &Secret::from("secret key")),
+        )
+        .await;
+        assert!(matches!(added, Ok(Ok(()))));
+        assert_eq!(get_key_at(&path).await, Some(Secret::from("secret key")));
+        request(&path, "STOP").await.unwrap();
+    }
+}
@@ -4,6 +4,7 @@ use strum_macros::EnumString;
 
 use crate::fx::Rates;
 
+#[allow(clippy::upper_case_acronyms)]
 #[derive(PartialEq, Eq, Hash, Clone, Copy, Debug, Deserialize, Serialize, EnumString, Display)]
 pub enum Currency {
     USD,
@@ -41,10 +42,7 @@ impl PartialEq for Amount {
 
 impl Amount {
     pub fn new(currency: Currency, value: f64) -> Amount {
-        Amount {
-            currency: currency,
-            value: value,
-        }
+        Amount { currency, value }
     }
 
     pub fn div(&self, other: &Amount, rates: &Rates) -> f64 {
@@ -71,7 +69,7 @@ impl Amount {
 
     pub fn convert(&self, currency: Currency, rates: &Rates) -> Amount {
         Amount {
-            currency: currency,
+            currency,
             value: rates.convert(self.currency, currency, self.value),
         }
     }
@@ -0,0 +1,307 @@
+//! Polish retail treasury bonds (obligacje skarbowe) indexed by inflation.
+//!
+//! A bond has nominal value of 100 PLN. It pays a fixed rate in the first year, then CPI plus
+//! a margin, where CPI is the year-on-year inflation published by GUS for the month two months
+//! before the start of each yearly interest period (never less than 0). EDO and ROD capitalize
+//! interest every year, COI pays it out, so only interest of the current period is held.
+//!
+//! Bonds redeemed before maturity pay a fee per bond, never more than the interest accrued, and
+//! 19% tax (Belka) is due on interest net of the fee. All values are rounded to grosz per bond,
+//! as the issuer does.
+
+use std::collections::BTreeMap;
+use std::path::Path;
+
+use chrono::{Datelike, Months, NaiveDate};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    amount::{Amount, Currency},
+    error,
+};
+
+const NOMINAL: f64 = 100.0;
+const TAX_RATE: f64 = 0.19;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BondType {
+    /// 10-year, interest capitalized
+    Edo,
+    /// 4-year, interest paid yearly
+    Coi,
+    /// 12-year family bond, interest capitalized
+    Rod,
+}
+
+impl BondType {
+    fn term_years(self) -> u32 {
+        match self {
+            BondType::Edo => 10,
+            BondType::Coi => 4,
+            BondType::Rod => 12,
+        }
+    }
+
+    fn capitalized(self) -> bool {
+        self != BondType::Coi
+    }
+
+    /// Early redemption fee per bond, in PLN.
+    fn redemption_fee(self) -> f64 {
+        match self {
+            BondType::Edo | BondType::Rod => 2.0,
+            BondType::Coi => 0.7,
+        }
+    }
+}
+
+/// Bonds of a single series bought on the same day.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct Bond {
+    /// E.g. `EDO0534`: type followed by month and year of maturity
+    pub series: String,
+    pub purchase_date: NaiveDate,
+    pub count: u32,
+    /// Interest rate of the first year of the series, e.g. 0.068
+    pub first_year_rate: f64,
+    /// Margin over CPI of the following years, e.g. 0.02
+    pub margin: f64,
+    /// Early redemption fee per bond, if different from the current one of the bond type
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub redemption_fee: Option<f64>,
+}
+
+/// Value of bonds if redeemed on a given day.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BondValue {
+    /// Nominal value with interest held
+    pub value: Amount,
+    pub fee: Amount,
+    /// Tax due on interest net of fee
+    pub tax: Amount,
+}
+
+impl BondValue {
+    /// Cash received on redemption.
+    pub fn net(&self) -> Amount {
+        Amount::new(
+            self.value.currency,
+            round(self.value.value - self.fee.value - self.tax.value),
+        )
+    }
+}
+
+impl std::ops::Add for BondValue {
+    type Output = Self;
+
+    fn add(self, rhs: Self) -> Self::Output {
+        Self {
+            value: self.value + rhs.value,
+            fee: self.fee + rhs.fee,
+            tax: self.tax + rhs.tax,
+        }
+    }
+}
+
+/// Year-on-year CPI per month, in percent as published by GUS (e.g. 2.5).
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct CpiTable {
+    cpi: BTreeMap<(i32, u32), f64>,
+}
+
+#[derive(Debug, Deserialize)]
+struct CpiRecord {
+    /// `YYYY-MM`
+    month: String,
+    cpi: f64,
+}
+
+impl CpiTable {
+    /// Read CSV file with `month,cpi` header.
+    pub fn from_file(path: &Path) -> Result<CpiTable, error::BondError> {
+        let mut cpi = BTreeMap::new();
+        for record in csv::Reader::from_path(path)?.deserialize() {
+            let record: CpiRecord = record?;
+            let month = NaiveDate::parse_from_str(&format!("{}-01", record.month), "%Y-%m-%d")
+                .map_err(|_| error::BondError::InvalidMonth(record.month.clone()))?;
+            cpi.insert((month.year(), month.month()), record.cpi);
+        }
+        Ok(CpiTable { cpi })
+    }
+
+    /// CPI of the month of `date`, as a fraction.
+    fn get(&self, date: NaiveDate) -> Result<f64, error::BondError> {
+        self.cpi
+            .get(&(date.year(), date.month()))
+            .map(|cpi| cpi / 100.0)
+            .ok_or(error::BondError::CpiMissing(
+                date.format("%Y-%m").to_string(),
+            ))
+    }
+}
+
+fn round(value: f64) -> f64 {
+    (value * 100.0).round() / 100.0
+}
+
+impl Bond {
+    pub fn bond_type(&self) -> Result<BondType, error::BondError> {
+        match self.series.get(..3) {
+            Some("EDO") => Ok(BondType::Edo),
+            Some("COI") => Ok(BondType::Coi),
+            Some("ROD") => Ok(BondType::Rod),
+            _ => Err(error::BondError::UnknownSeries(self.series.clone())),
+        }
+    }
+
+    fn period_start(&self, period: u32) -> NaiveDate {
+        self.purchase_date + Months::new(12 * period)
+    }
+
+    pub fn maturity(&self) -> Result<NaiveDate, error::BondError> {
+        Ok(self.period_start(self.bond_type()?.term_years()))
+    }
+
+    /// Interest rate of yearly `period`, counted from 0.
+    fn rate(&self, period: u32, cpi: &CpiTable) -> Result<f64, error::BondError> {
+        match period {
+            0 => Ok(self.first_year_rate),
+            _ => {
+                let cpi = cpi.get(self.period_start(period) - Months::new(2))?;
+                Ok(cpi.max(0.0) + self.margin)
+            }
+        }
+    }
+
+    /// Value of all bonds of the lot if redeemed on `date`.
+    pub fn value(&self, cpi: &CpiTable, date: NaiveDate) -> Result<BondValue, error::BondError> {
+        let bond_type = self.bond_type()?;
+        let maturity = self.maturity()?;
+        let date = date.clamp(self.purchase_date, maturity);
+
+        /* Value of a single bond */
+        let mut value = NOMINAL;
+        for period in 0..bond_type.term_years() {
+            let start = self.period_start(period);
+            let end = self.period_start(period + 1);
+            let rate = self.rate(period, cpi)?;
+            let elapsed = match end <= date {
+                true => 1.0,
+                false => (date - start).num_days() as f64 / (end - start).num_days() as f64,
+            };
+            if bond_type.capitalized() {
+                value = round(value * (1.0 + rate * elapsed));
+            } else {
+                // Interest of past periods was paid out already
+                value = round(NOMINAL * (1.0 + rate * elapsed));
+            }
+            if end >= date {
+                break;
+            }
+        }
+        let interest = value - NOMINAL;
+        let fee = match date < maturity {
+            true => self
+                .redemption_fee
+                .unwrap_or(bond_type.redemption_fee())
+                .min(interest),
+            false => 0.0,
+        };
+        let tax = round((interest - fee) * TAX_RATE);
+
+        let count = self.count as f64;
+        Ok(BondValue {
+            value: Amount::new(Currency::PLN, round(value * count)),
+            fee: Amount::new(Currency::PLN, round(fee * count)),
+            tax: Amount::new(Currency::PLN, round(tax * count)),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn date(value: &str) -> NaiveDate {
+        value.parse().unwrap()
+    }
+
+    fn cpi() -> CpiTable {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("cpi.csv");
+        std::fs::write(
+            &path,
+            "month,cpi\n2024-03,2.0\n2025-03,4.9\n2026-03,-1.0\n2027-03,3.0\n",
+        )
+        .unwrap();
+        CpiTable::from_file(&path).unwrap()
+    }
+
+    fn bond(series: &str) -> Bond {
+        Bond {
+            series: series.to_string(),
+            purchase_date: date("2024-05-10"),
+            count: 10,
+            first_year_rate: 0.068,
+            margin: 0.02,
+            redemption_fee: None,
+        }
+    }
+
+    #[test]
+    fn capitalized_bond() {
+        let cpi = cpi();
+        let edo = bond("EDO0534");
+        assert_eq!(edo.maturity().unwrap(), date("2034-05-10"));
+
+        // Half of the first year: 100 * (1 + 0.068 * 182 / 365) = 103.39
+        let value = edo.value(&cpi, date("2024-11-08")).unwrap();
+        assert_eq!(value.value, Amount::new(Currency::PLN, 1033.9));
+        assert_eq!(value.fee, Amount::new(Currency::PLN, 20.0));
+        // (3.39 - 2.00) * 0.19 = 0.26 per bond
+        assert_eq!(value.tax, Amount::new(Currency::PLN, 2.6));
+        assert_eq!(value.net(), Amount::new(Currency::PLN, 1011.3));
+
+        // First year capitalized: 106.80, second year at CPI of March 2025 plus margin:
+        // 106.80 * (1 + 0.069) = 114.17
+        let value = edo.value(&cpi, date("2026-05-10")).unwrap();
+        assert_eq!(value.value, Amount::new(Currency::PLN, 1141.7));
+        // Negative CPI counts as 0: 114.17 * 1.02 = 116.45
+        let value = edo.value(&cpi, date("2027-05-10")).unwrap();
+        assert_eq!(value.value, Amount::new(Currency::PLN, 1164.5));
+        assert!(matches!(
+            edo.value(&cpi, date("2028-06-01")),
+            Err(error::BondError::CpiMissing(month)) if month == "2028-03"
+        ));
+
+        // Fee never exceeds interest
+        let value = edo.value(&cpi, date("2024-05-20")).unwrap();
+        assert_eq!(value.value, Amount::new(Currency::PLN, 1001.9));
+        assert_eq!(value.fee, Amount::new(Currency::PLN, 1.9));
+        assert_eq!(value.tax, Amount::new(Currency::PLN, 0.0));
+    }
+
+    #[test]
+    fn interest_paying_bond() {
+        let cpi = cpi();
+        let coi = bond("COI0528");
+        assert_eq!(coi.maturity().unwrap(), date("2028-05-10"));
+        // Interest of the first year was paid out, second year at 0.069 for 91 of 365 days
+        let value = coi.value(&cpi, date("2025-08-09")).unwrap();
+        assert_eq!(value.value, Amount::new(Currency::PLN, 1017.2));
+        assert_eq!(value.fee, Amount::new(Currency::PLN, 7.0));
+        // (1.72 - 0.70) * 0.19 = 0.19 per bond
+        assert_eq!(value.tax, Amount::new(Currency::PLN, 1.9));
+
+        // Valued at maturity ever after, last year at 0.03 + 0.02 and without fee
+        let value = coi.value(&cpi, date("2030-01-01")).unwrap();
+        assert_eq!(value.value, Amount::new(Currency::PLN, 1050.0));
+        assert_eq!(value.fee, Amount::new(Currency::PLN, 0.0));
+        assert_eq!(value.tax, Amount::new(Currency::PLN, 9.5));
+
+        assert!(matches!(
+            bond("OTS0824").value(&cpi, date("2024-06-01")),
+            Err(error::BondError::UnknownSeries(series)) if series == "OTS0824"
+        ));
+    }
+}
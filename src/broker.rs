@@ -0,0 +1,116 @@
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    amount::Amount,
+    error,
+    history::Transaction,
+    ibkr::IbkrAccount,
+    secret::Secret,
+    xtb::{XtbAccount, XtbConfig},
+};
+
+/// Single instrument held at a broker.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Holding {
+    pub symbol: String,
+    /// International Securities Identification Number, if the broker reports it
+    pub isin: Option<String>,
+    /// Units held, negative for short positions
+    pub quantity: f64,
+    /// Bid price of one unit
+    pub price: Amount,
+    /// Market exposure of the holding
+    pub market_value: Amount,
+    /// Contribution of the holding to account equity, less than exposure when bought on margin
+    pub equity: Amount,
+}
+
+/// Source of positions held at a broker.
+///
+/// Sources are used by `Portfolio::from_file` in order: `connect`, `authenticate`, then `holdings`
+/// and `cash_balance`, finally `disconnect`. Sources without a session (e.g. files) may implement
+/// `connect`, `authenticate` and `disconnect` as no-ops.
+#[async_trait]
+pub trait PositionSource: Send {
+    async fn connect(&mut self) -> Result<(), error::SourceError>;
+
+    /// Authenticate using credentials stored in the portfolio, encrypted with `key`.
+    async fn authenticate(&mut self, key: &Secret) -> Result<(), error::SourceError>;
+
+    /// All instruments currently held, with their quantity and price.
+    async fn holdings(&mut self) -> Result<Vec<Holding>, error::SourceError>;
+
+    /// Free cash, if source reports it.
+    async fn cash_balance(&mut self) -> Result<Option<Amount>, error::SourceError>;
+
+    /// Cash operations and trades since `since`, or all of them. Sources without history report
+    /// none.
+    async fn transactions(
+        &mut self,
+        _since: Option<DateTime<Utc>>,
+    ) -> Result<Vec<Transaction>, error::SourceError> {
+        Ok(vec![])
+    }
+
+    async fn disconnect(&mut self) -> Result<(), error::SourceError>;
+}
+
+/// Position source of a group, selected by `type` in YAML.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum SourceConfig {
+    Xtb(XtbAccount),
+    /// Flex Query statement file of an Interactive Brokers account
+    Ibkr(IbkrAccount),
+}
+
+impl SourceConfig {
+    /// Create position source for this configuration.
+    pub fn open(
+        &self,
+        xtb_config: Option<&XtbConfig>,
+    ) -> Result<Box<dyn PositionSource>, error::SourceError> {
+        match self {
+            SourceConfig::Xtb(account) => {
+                let xtb_config = xtb_config.ok_or(error::SourceError::NotConfigured(
+                    account.endpoint().unwrap_or("xtb").to_string(),
+                ))?;
+                Ok(Box::new(xtb_config.for_account(account.clone())))
+            }
+            SourceConfig::Ibkr(account) => Ok(Box::new(account.clone())),
+        }
+    }
+
+    /// Identifier of the session this source opens. Groups with the same one share a session.
+    pub fn session_id(&self) -> String {
+        match self {
+            // Accounts at different endpoints (e.g. demo and real) may have the same id
+            SourceConfig::Xtb(account) => match account.endpoint() {
+                Some(endpoint) => format!("xtb:{}:{}", endpoint, account.account_id()),
+                None => format!("xtb:{}", account.account_id()),
+            },
+            SourceConfig::Ibkr(account) => format!("ibkr:{}", account.account_id()),
+        }
+    }
+
+    /// Whether credentials of this source are encrypted with the portfolio key.
+    pub fn uses_key(&self) -> bool {
+        matches!(self, SourceConfig::Xtb(_))
+    }
+
+    /// Re-encrypt credentials of this source with `new_key`.
+    pub fn change_key(
+        &self,
+        old_key: &Secret,
+        new_key: &Secret,
+    ) -> Result<Self, error::SourceError> {
+        match self {
+            SourceConfig::Xtb(account) => Ok(SourceConfig::Xtb(
+                account.decrypt(old_key)?.encrypt(new_key)?,
+            )),
+            SourceConfig::Ibkr(_) => Ok(self.clone()),
+        }
+    }
+}
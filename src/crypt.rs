@@ -1,6 +1,7 @@
 use base64::{engine::general_purpose, Engine as _};
 use openssl::symm::{decrypt, encrypt, Cipher};
 use rand::{thread_rng, Rng};
+use zeroize::Zeroizing;
 
 use crate::error;
 
@@ -11,12 +12,8 @@ fn generate_iv(size: usize) -> Vec<u8> {
 }
 
 /// Repeat the key until it reaches the desired length.
-fn match_key_length(key: &str, length: usize) -> String {
-    let mut key = key.to_string();
-    while key.len() < length {
-        key = format!("{}{}", key, key);
-    }
-    key[..length].to_string()
+fn match_key_length(key: &str, length: usize) -> Zeroizing<Vec<u8>> {
+    Zeroizing::new(key.bytes().cycle().take(length).collect())
 }
 
 pub fn encrypt_text(text: &str, key: &str) -> Result<String, error::CryptError> {
@@ -26,7 +23,7 @@ pub fn encrypt_text(text: &str, key: &str) -> Result<String, error::CryptError>
 
     let ciphertext = encrypt(
         cipher,
-        match_key_length(key, key_len).as_bytes(),
+        &match_key_length(key, key_len),
         Some(&iv),
         text.as_bytes(),
     )?;
@@ -39,20 +36,46 @@ pub fn encrypt_text(text: &str, key: &str) -> Result<String, error::CryptError>
 pub fn decrypt_text(text: &str, key: &str) -> Result<String, error::CryptError> {
     let key_len = Cipher::aes_256_cbc().key_len();
     let decoded = general_purpose::STANDARD_NO_PAD.decode(text)?;
+    if decoded.len() <= key_len {
+        return Err(error::CryptError::Truncated);
+    }
     let iv = decoded[..key_len].to_vec();
     let data = &decoded[key_len..];
 
     let cipher = Cipher::aes_256_cbc();
-    let decrypted = decrypt(
-        cipher,
-        match_key_length(key, key_len).as_bytes(),
-        Some(&iv),
-        data,
-    )?;
+    let decrypted = decrypt(cipher, &match_key_length(key, key_len), Some(&iv), data)?;
 
     Ok(String::from_utf8(decrypted)?)
 }
 
+/// First line of a portfolio file that is encrypted as a whole.
+const CONTAINER_HEADER: &str = "$PORTFEL$ENCRYPTED$V1";
+
+/// Check whether `text` is an encrypted container produced by `encrypt_container`.
+pub fn is_encrypted_container(text: &str) -> bool {
+    text.lines().next().map(str::trim) == Some(CONTAINER_HEADER)
+}
+
+/// Encrypt the whole `text` into a container: a header line followed by the ciphertext.
+pub fn encrypt_container(text: &str, key: &str) -> Result<String, error::CryptError> {
+    Ok(format!(
+        "{}\n{}\n",
+        CONTAINER_HEADER,
+        encrypt_text(text, key)?
+    ))
+}
+
+pub fn decrypt_container(text: &str, key: &str) -> Result<String, error::CryptError> {
+    let mut lines = text.lines();
+    match lines.next().map(str::trim) {
+        Some(CONTAINER_HEADER) => {
+            let ciphertext: String = lines.map(str::trim).collect();
+            decrypt_text(&ciphertext, key)
+        }
+        _ => Err(error::CryptError::NotEncrypted),
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -82,4 +105,33 @@ mod tests {
         let decrypted = decrypt_text(&encrypted, key).unwrap();
         assert_eq!(text, decrypted);
     }
+
+    #[test]
+    fn test_container_roundtrip() {
+        let key = "0123456789abcdef0123456789abcdef";
+        let text = "config:\n  base_currency: USD\n";
+        let encrypted = encrypt_container(text, key).unwrap();
+        assert!(is_encrypted_container(&encrypted));
+        assert!(!is_encrypted_container(text));
+        assert_eq!(decrypt_container(&encrypted, key).unwrap(), text);
+    }
+
+    #[test]
+    fn test_container_not_encrypted() {
+        let result = decrypt_container("config: {}\n", "key");
+        assert!(matches!(result, Err(error::CryptError::NotEncrypted)));
+    }
+
+    #[test]
+    fn test_container_truncated() {
+        let key = "0123456789abcdef0123456789abcdef";
+        let encrypted = encrypt_container("config: {}\n", key).unwrap();
+        // Header line and 30 bytes of the 32 byte IV
+        let truncated = &encrypted[..CONTAINER_HEADER.len() + 1 + 40];
+        assert!(is_encrypted_container(truncated));
+        let result = decrypt_container(truncated, key);
+        assert!(matches!(result, Err(error::CryptError::Truncated)));
+        let result = decrypt_container("$PORTFEL$ENCRYPTED$V1\n", key);
+        assert!(matches!(result, Err(error::CryptError::Truncated)));
+    }
 }
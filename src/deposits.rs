@@ -0,0 +1,192 @@
+//! Term deposits and savings accounts accruing interest.
+//!
+//! Interest accrues daily on actual/365 basis and is capitalized at the end of every compounding
+//! period, when Belka tax (19%) is withheld from it, so the value of a deposit is always net of
+//! tax. Interest of the current period is accrued net of the tax that will be withheld. A term
+//! deposit stops accruing at maturity.
+
+use chrono::{Months, NaiveDate};
+use serde::{Deserialize, Serialize};
+
+use crate::amount::Amount;
+
+const TAX_RATE: f64 = 0.19;
+const DAYS_IN_YEAR: f64 = 365.0;
+
+/// When interest is added to the deposit.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum Compounding {
+    Daily,
+    Monthly,
+    Quarterly,
+    Yearly,
+    /// Once, at maturity (or never for a savings account without maturity)
+    #[default]
+    AtMaturity,
+}
+
+impl Compounding {
+    fn months(self) -> Option<u32> {
+        match self {
+            Compounding::Monthly => Some(1),
+            Compounding::Quarterly => Some(3),
+            Compounding::Yearly => Some(12),
+            Compounding::Daily | Compounding::AtMaturity => None,
+        }
+    }
+}
+
+fn default_belka() -> bool {
+    true
+}
+
+/// Deposit of `principal` at yearly `rate`.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct Deposit {
+    pub principal: Amount,
+    /// Yearly interest rate, e.g. 0.055
+    pub rate: f64,
+    #[serde(default)]
+    pub compounding: Compounding,
+    pub start: NaiveDate,
+    /// Last day of a term deposit, none for a savings account
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub maturity: Option<NaiveDate>,
+    /// Whether Belka tax is withheld from interest (not e.g. in IKE/IKZE accounts)
+    #[serde(default = "default_belka")]
+    pub belka: bool,
+}
+
+fn round(value: f64) -> f64 {
+    (value * 100.0).round() / 100.0
+}
+
+impl Deposit {
+    /// Part of interest left after tax.
+    fn net_rate(&self) -> f64 {
+        match self.belka {
+            true => self.rate * (1.0 - TAX_RATE),
+            false => self.rate,
+        }
+    }
+
+    /// Interest of `days` on `value`, net of tax withheld on its capitalization.
+    fn net_interest(&self, value: f64, days: i64) -> f64 {
+        let interest = round(value * self.rate * days as f64 / DAYS_IN_YEAR);
+        match self.belka {
+            true => interest - round(interest * TAX_RATE),
+            false => interest,
+        }
+    }
+
+    /// Value with interest accrued until `date`, net of tax.
+    pub fn value(&self, date: NaiveDate) -> Amount {
+        let end = match self.maturity {
+            Some(maturity) => date.min(maturity),
+            None => date,
+        }
+        .max(self.start);
+        let mut value = self.principal.value;
+        match self.compounding.months() {
+            Some(months) => {
+                // Periods counted from start, so that one ending on a shorter month's last day
+                // does not move the following ones
+                let period_start = |period: u32| self.start + Months::new(months * period);
+                let mut period = 0;
+                loop {
+                    let (start, period_end) = (period_start(period), period_start(period + 1));
+                    if period_end > end {
+                        value += self.net_interest(value, (end - start).num_days());
+                        break;
+                    }
+                    value += self.net_interest(value, (period_end - start).num_days());
+                    period += 1;
+                }
+            }
+            None if self.compounding == Compounding::Daily => {
+                let days = (end - self.start).num_days() as i32;
+                value *= (1.0 + self.net_rate() / DAYS_IN_YEAR).powi(days);
+            }
+            None => value += self.net_interest(value, (end - self.start).num_days()),
+        }
+        Amount::new(self.principal.currency, round(value))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::amount::Currency;
+
+    fn date(value: &str) -> NaiveDate {
+        value.parse().unwrap()
+    }
+
+    fn deposit(compounding: Compounding, maturity: Option<&str>) -> Deposit {
+        Deposit {
+            principal: Amount::new(Currency::PLN, 10000.0),
+            rate: 0.06,
+            compounding,
+            start: date("2025-01-01"),
+            maturity: maturity.map(date),
+            belka: true,
+        }
+    }
+
+    #[test]
+    fn term_deposit() {
+        let deposit = deposit(Compounding::AtMaturity, Some("2025-07-01"));
+        // 10000 * 0.06 * 90 / 365 = 147.95, less 28.11 tax
+        assert_eq!(
+            deposit.value(date("2025-04-01")),
+            Amount::new(Currency::PLN, 10119.84)
+        );
+        // No interest before start and after maturity: 181 days, 297.53 less 56.53 tax
+        assert_eq!(
+            deposit.value(date("2024-12-01")),
+            Amount::new(Currency::PLN, 10000.0)
+        );
+        assert_eq!(
+            deposit.value(date("2026-01-01")),
+            Amount::new(Currency::PLN, 10241.0)
+        );
+        let untaxed = Deposit {
+            belka: false,
+            ..deposit
+        };
+        assert_eq!(
+            untaxed.value(date("2025-07-01")),
+            Amount::new(Currency::PLN, 10297.53)
+        );
+    }
+
+    #[test]
+    fn compounded_savings() {
+        // January: 50.96 less 9.68 tax, then February on 10041.28: 46.22 less 8.78 tax
+        let monthly = deposit(Compounding::Monthly, None);
+        assert_eq!(
+            monthly.value(date("2025-03-01")),
+            Amount::new(Currency::PLN, 10078.72)
+        );
+        // Accrued for 10 days of March on 10078.72: 16.57 less 3.15 tax
+        assert_eq!(
+            monthly.value(date("2025-03-11")),
+            Amount::new(Currency::PLN, 10092.14)
+        );
+        // Periods of 28, 31 and 30 days: 46.03, 51.15 and 49.70 less 8.75, 9.72 and 9.44 tax
+        let month_end = Deposit {
+            start: date("2025-01-31"),
+            ..monthly
+        };
+        assert_eq!(
+            month_end.value(date("2025-04-30")),
+            Amount::new(Currency::PLN, 10118.97)
+        );
+        let daily = deposit(Compounding::Daily, None);
+        assert_eq!(
+            daily.value(date("2026-01-01")),
+            Amount::new(Currency::PLN, 10497.97)
+        );
+    }
+}
@@ -1,12 +1,19 @@
-    use thiserror::Error;
+use thiserror::Error;
 
-#[derive(Debug)]
+use crate::amount::Currency;
+
+#[allow(clippy::enum_variant_names)]
+#[derive(Error, Debug)]
 pub enum FxError {
+    #[error("HTTP error: {0}")]
     HttpError(reqwest::Error),
+    #[error("JSON parsing error: {0}")]
     JsonError(reqwest::Error),
+    #[error("Unable to parse exchange rates")]
     GenericParserError,
 }
 
+#[allow(clippy::enum_variant_names)]
 #[derive(Error, Debug)]
 pub enum PortfolioReadError {
     #[error("I/O error: {0}")]
@@ -19,10 +26,19 @@ pub enum PortfolioReadError {
     DuplicateSymbolError(String),
     #[error("XTB error: {0}")]
     XtbError(#[from] XtbError),
+    #[error("Position source error: {0}")]
+    SourceError(#[from] SourceError),
     #[error("Crypt error: {0}")]
     CryptError(#[from] CryptError),
+    #[error("Quote error: {0}")]
+    QuoteError(#[from] QuoteError),
+    #[error("Position {0} has quantity but no quote")]
+    QuoteMissing(String),
+    #[error("Bond error: {0}")]
+    BondError(#[from] BondError),
 }
 
+#[allow(clippy::enum_variant_names)]
 #[derive(Error, Debug)]
 pub enum PortfolioWriteError {
     #[error("I/O error: {0}")]
@@ -31,6 +47,8 @@ pub enum PortfolioWriteError {
     JsonError(#[from] serde_yaml::Error),
     #[error("XTB error: {0}")]
     XtbError(#[from] XtbError),
+    #[error("Position source error: {0}")]
+    SourceError(#[from] SourceError),
     #[error("Crypt error: {0}")]
     CryptError(#[from] CryptError),
 }
@@ -38,7 +56,9 @@ pub enum PortfolioWriteError {
 #[derive(Error, Debug)]
 pub enum PortfolioOpsError {
     #[error("Unable to balance portfolio {0}")]
-    UnableToBalance(#[from] good_lp::solvers::ResolutionError)
+    UnableToBalance(#[from] good_lp::solvers::ResolutionError),
+    #[error("{0:.2} {1} left unassigned: no position without a fixed target to invest it in")]
+    Unassigned(f64, Currency),
 }
 
 #[derive(Error, Debug)]
@@ -49,6 +69,18 @@ pub enum XtbError {
     PasswordMissing,
     #[error("Not connected")]
     NotConnected,
+    #[error("Timed out connecting to XTB")]
+    ConnectTimeout,
+    #[error("Timed out waiting for response to {0}")]
+    ResponseTimeout(String),
+    #[error("Connection closed by XTB")]
+    ConnectionClosed,
+    #[error("Invalid response frame: {0}")]
+    InvalidFrame(String),
+    #[error("Connection to XTB unusable after an earlier failure")]
+    ConnectionBroken,
+    #[error("Reconnect failed after {0} attempts: {1}")]
+    ReconnectFailed(u32, Box<XtbError>),
     #[error("Native TLS error: {0}")]
     NativeTlsError(#[from] tokio_native_tls::native_tls::Error),
     #[error("JSON parsing error: {0}")]
@@ -57,11 +89,117 @@ pub enum XtbError {
     IoError(#[from] std::io::Error),
     #[error("Crypt error: {0}")]
     CryptError(#[from] CryptError),
+    #[error("Unknown currency: {0}")]
+    UnknownCurrency(String),
+    #[error("Invalid response: {0}")]
+    InvalidResponse(String),
     #[error("XtbApiError ({0}): {1}")]
     XtbApiError(String, String),
     #[error("Unknown error")]
     UnknownError,
 }
+
+impl XtbError {
+    /// Whether the failure is caused by the connection rather than by the request itself,
+    /// so it is worth reconnecting and trying again.
+    pub fn is_transient(&self) -> bool {
+        matches!(
+            self,
+            XtbError::IoError(_)
+                | XtbError::ConnectTimeout
+                | XtbError::ResponseTimeout(_)
+                | XtbError::ConnectionClosed
+                | XtbError::InvalidFrame(_)
+                | XtbError::ConnectionBroken
+        )
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum SourceError {
+    #[error("XTB error: {0}")]
+    XtbError(#[from] XtbError),
+    #[error("IBKR error: {0}")]
+    IbkrError(#[from] IbkrError),
+    #[error("Position source not configured: {0}")]
+    NotConfigured(String),
+}
+
+#[derive(Error, Debug)]
+pub enum IbkrError {
+    #[error("I/O error: {0}")]
+    IoError(#[from] std::io::Error),
+    #[error("XML parsing error: {0}")]
+    XmlError(#[from] quick_xml::DeError),
+    #[error("No statement of account {0} in Flex Query file")]
+    AccountNotFound(String),
+    #[error("Flex Query statement not read")]
+    NotRead,
+    #[error("Unknown currency: {0}")]
+    UnknownCurrency(String),
+    #[error("Invalid Flex Query statement: {0}")]
+    InvalidStatement(String),
+}
+
+#[derive(Error, Debug)]
+pub enum BondError {
+    #[error("I/O error: {0}")]
+    IoError(#[from] std::io::Error),
+    #[error("CSV error: {0}")]
+    CsvError(#[from] csv::Error),
+    #[error("Invalid CPI month: {0}")]
+    InvalidMonth(String),
+    #[error("CPI of {0} missing from CPI table")]
+    CpiMissing(String),
+    #[error("Unknown bond series: {0}")]
+    UnknownSeries(String),
+}
+
+#[derive(Error, Debug)]
+pub enum QuoteError {
+    #[error("I/O error: {0}")]
+    IoError(#[from] std::io::Error),
+    #[error("HTTP error: {0}")]
+    HttpError(#[from] reqwest::Error),
+    #[error("JSON parsing error: {0}")]
+    JsonError(#[from] serde_json::Error),
+    #[error("CSV error: {0}")]
+    CsvError(#[from] csv::Error),
+    #[error("No quote of {0}")]
+    NotFound(String),
+    #[error("Unexpected response to quote of {0}: {1}")]
+    InvalidResponse(String, String),
+}
+
+#[derive(Error, Debug)]
+pub enum ImportError {
+    #[error("I/O error: {0}")]
+    IoError(#[from] std::io::Error),
+    #[error("CSV error: {0}")]
+    CsvError(#[from] csv::Error),
+    #[error("Unknown import format: {0}")]
+    UnknownFormat(String),
+    #[error("Invalid import format: {0}")]
+    InvalidFormat(String),
+    #[error("Column not found: {0}")]
+    MissingColumn(String),
+    #[error("Line {0}: invalid {1}: '{2}'")]
+    InvalidValue(usize, String, String),
+    #[error("Line {0}: quantity of {1} is 0, but its value is not")]
+    ZeroQuantity(usize, String),
+    #[error("Unknown currency: {0}")]
+    UnknownCurrency(String),
+    #[error("Unknown group: {0}")]
+    UnknownGroup(String),
+    #[error("Group {0} is read from a position source")]
+    SourceGroup(String),
+    #[error("Duplicate symbol: {0}")]
+    DuplicateSymbol(String),
+    #[error("Holdings of position {0} are in different currencies")]
+    MixedCurrencies(String),
+}
+
+#[allow(clippy::enum_variant_names)]
 #[derive(Error, Debug)]
 pub enum CryptError {
     #[error("Base64 error: {0}")]
@@ -70,4 +208,22 @@ pub enum CryptError {
     Utf8Error(#[from] std::string::FromUtf8Error),
     #[error("Cipher error: {0}")]
     CipherError(#[from] openssl::error::ErrorStack),
+    #[error("Not an encrypted portfolio file")]
+    NotEncrypted,
+    #[error("Encrypted data is truncated")]
+    Truncated,
+}
+
+#[derive(Error, Debug)]
+pub enum AgentError {
+    #[error("Key agent is not running")]
+    NotRunning,
+    #[error("Key agent is already running at {0}")]
+    AlreadyRunning(std::path::PathBuf),
+    #[error("Key agent protocol error: {0}")]
+    ProtocolError(String),
+    #[error("Key agent socket path {0} is insecure: {1}")]
+    Insecure(std::path::PathBuf, String),
+    #[error("I/O error: {0}")]
+    IoError(#[from] std::io::Error),
 }
@@ -15,19 +15,11 @@ struct ExchangeRateTable {
     rates: Vec<SingleRateResponse>,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Default)]
 pub struct Rates {
     pub rates: HashMap<Currency, f64>,
 }
 
-impl Default for Rates {
-    fn default() -> Self {
-        Rates {
-            rates: HashMap::new(),
-        }
-    }
-}
-
 async fn get_rates() -> Result<Vec<SingleRateResponse>, error::FxError> {
     let url = "http://api.nbp.pl/api/exchangerates/tables/a";
     Ok(reqwest::get(url)
@@ -86,21 +78,21 @@ mod test {
             .into_iter()
             .collect(),
         };
-        assert_eq!(
-            compare_floats(rates.convert(Currency::USD, Currency::USD, 100.0), 100.0),
-            true
-        );
-        assert_eq!(
-            compare_floats(rates.convert(Currency::USD, Currency::PLN, 100.0), 402.0),
-            true
-        );
-        assert_eq!(
-            compare_floats(rates.convert(Currency::EUR, Currency::PLN, 100.0), 434.0),
-            true
-        );
-        assert_eq!(
-            compare_floats(rates.convert(Currency::EUR, Currency::USD, 100.0), 107.96),
-            true
-        );
+        assert!(compare_floats(
+            rates.convert(Currency::USD, Currency::USD, 100.0),
+            100.0
+        ));
+        assert!(compare_floats(
+            rates.convert(Currency::USD, Currency::PLN, 100.0),
+            402.0
+        ));
+        assert!(compare_floats(
+            rates.convert(Currency::EUR, Currency::PLN, 100.0),
+            434.0
+        ));
+        assert!(compare_floats(
+            rates.convert(Currency::EUR, Currency::USD, 100.0),
+            107.96
+        ));
     }
 }
@@ -0,0 +1,168 @@
+//! Glide path: target shares of positions changing over time.
+//!
+//! Shares are given for anchor dates, per position ticker. Between anchors, either the shares of
+//! the last anchor apply, or shares are interpolated linearly. Before the first anchor the first
+//! shares apply, after the last anchor the last ones. A ticker missing at an anchor has share `0`
+//! there.
+
+use std::collections::{BTreeMap, BTreeSet};
+
+use chrono::{Months, NaiveDate};
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct GlidePath {
+    /// Interpolate linearly between anchors, instead of changing shares at anchor dates
+    #[serde(default)]
+    pub interpolate: bool,
+    pub anchors: Vec<Anchor>,
+}
+
+/// Target shares effective from `date`, by position ticker.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct Anchor {
+    pub date: NaiveDate,
+    pub targets: BTreeMap<String, f64>,
+}
+
+impl GlidePath {
+    fn sorted_anchors(&self) -> Vec<&Anchor> {
+        let mut anchors: Vec<&Anchor> = self.anchors.iter().collect();
+        anchors.sort_by_key(|anchor| anchor.date);
+        anchors
+    }
+
+    /// Tickers given a share at any anchor.
+    pub fn tickers(&self) -> BTreeSet<&str> {
+        self.anchors
+            .iter()
+            .flat_map(|anchor| anchor.targets.keys())
+            .map(String::as_str)
+            .collect()
+    }
+
+    /// Target share of `ticker` on `date`, none if the glide path does not give it any.
+    pub fn target(&self, ticker: &str, date: NaiveDate) -> Option<f64> {
+        if !self.tickers().contains(ticker) {
+            return None;
+        }
+        let share = |anchor: &Anchor| anchor.targets.get(ticker).copied().unwrap_or(0.0);
+        let anchors = self.sorted_anchors();
+        let next = anchors.iter().position(|anchor| anchor.date > date);
+        match next {
+            Some(0) => Some(share(anchors[0])),
+            None => anchors.last().map(|anchor| share(anchor)),
+            Some(next) => {
+                let (from, to) = (anchors[next - 1], anchors[next]);
+                if !self.interpolate {
+                    return Some(share(from));
+                }
+                let elapsed = (date - from.date).num_days() as f64;
+                let length = (to.date - from.date).num_days() as f64;
+                Some(share(from) + (share(to) - share(from)) * elapsed / length)
+            }
+        }
+    }
+
+    /// Dates the path changes on from `date` on: `date` itself, later anchors and, if
+    /// interpolated, every year from `date` until the last anchor.
+    pub fn upcoming(&self, date: NaiveDate) -> Vec<NaiveDate> {
+        let mut dates: BTreeSet<NaiveDate> = self
+            .anchors
+            .iter()
+            .map(|anchor| anchor.date)
+            .filter(|anchor_date| *anchor_date > date)
+            .collect();
+        dates.insert(date);
+        if let Some(last) = self.sorted_anchors().last().filter(|_| self.interpolate) {
+            let mut year = date + Months::new(12);
+            while year < last.date {
+                dates.insert(year);
+                year = year + Months::new(12);
+            }
+        }
+        dates.into_iter().collect()
+    }
+
+    /// Table of target shares per ticker on `upcoming` dates from `date`.
+    pub fn format(&self, date: NaiveDate) -> String {
+        let tickers = self.tickers();
+        let mut result = format!("{:10}", "Date");
+        for ticker in &tickers {
+            result.push_str(&format!(" {:>8.8}", ticker));
+        }
+        result.push('\n');
+        for date in self.upcoming(date) {
+            result.push_str(&date.to_string());
+            for ticker in &tickers {
+                result.push_str(&format!(" {:8.2}", self.target(ticker, date).unwrap()));
+            }
+            result.push('\n');
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn date(value: &str) -> NaiveDate {
+        value.parse().unwrap()
+    }
+
+    fn glide_path(interpolate: bool) -> GlidePath {
+        GlidePath {
+            interpolate,
+            anchors: vec![
+                Anchor {
+                    date: date("2035-01-01"),
+                    targets: [("STOCKS".to_string(), 0.4), ("BONDS".to_string(), 0.6)].into(),
+                },
+                Anchor {
+                    date: date("2025-01-01"),
+                    targets: [("STOCKS".to_string(), 0.8), ("BONDS".to_string(), 0.2)].into(),
+                },
+            ],
+        }
+    }
+
+    #[test]
+    fn interpolated_targets() {
+        let path = glide_path(true);
+        assert_eq!(path.target("STOCKS", date("2020-06-01")), Some(0.8));
+        assert_eq!(path.target("STOCKS", date("2040-06-01")), Some(0.4));
+        assert_eq!(path.target("CASH", date("2030-01-01")), None);
+        // 1826 of 3652 days
+        let stocks = path.target("STOCKS", date("2030-01-01")).unwrap();
+        assert!((stocks - 0.6).abs() < 0.001);
+        let bonds = path.target("BONDS", date("2030-01-01")).unwrap();
+        assert!((stocks + bonds - 1.0).abs() < 1e-9);
+
+        let upcoming = path.upcoming(date("2032-06-01"));
+        assert_eq!(
+            upcoming,
+            vec![
+                date("2032-06-01"),
+                date("2033-06-01"),
+                date("2034-06-01"),
+                date("2035-01-01")
+            ]
+        );
+        let table = path.format(date("2032-06-01"));
+        assert_eq!(table.lines().count(), 5);
+        assert!(table.starts_with("Date          BONDS   STOCKS\n"));
+        assert!(table.ends_with("2035-01-01     0.60     0.40\n"));
+    }
+
+    #[test]
+    fn stepped_targets() {
+        let path = glide_path(false);
+        assert_eq!(path.target("STOCKS", date("2034-12-31")), Some(0.8));
+        assert_eq!(path.target("STOCKS", date("2035-01-01")), Some(0.4));
+        assert_eq!(
+            path.upcoming(date("2030-01-01")),
+            vec![date("2030-01-01"), date("2035-01-01")]
+        );
+    }
+}
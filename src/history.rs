@@ -0,0 +1,292 @@
+//! Local history of transactions imported from position sources.
+//!
+//! History is stored next to the portfolio file, as `<portfolio>.history.yaml`, and encrypted
+//! whenever the portfolio file is. Transactions are kept per source session and identified by
+//! their id at the broker, e.g. order number, so importing overlapping periods never duplicates
+//! them.
+
+use std::collections::BTreeMap;
+use std::path::Path;
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Deserializer, Serialize};
+
+use crate::{amount::Amount, error, portfolio::Portfolio, secret::Secret};
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "snake_case")]
+pub enum TransactionKind {
+    Deposit,
+    Withdrawal,
+    Buy,
+    Sell,
+}
+
+/// Single cash operation or trade reported by a position source.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct Transaction {
+    /// Id of the transaction at the broker, e.g. order number, unique per kind within a source
+    #[serde(deserialize_with = "transaction_id")]
+    pub order: String,
+    pub kind: TransactionKind,
+    pub time: DateTime<Utc>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub symbol: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub volume: Option<f64>,
+    /// Price of one unit, for trades
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub price: Option<Amount>,
+    /// Cash paid or received, always positive
+    pub amount: Amount,
+}
+
+/// Transaction id, also read from a number as written by earlier versions.
+fn transaction_id<'de, D: Deserializer<'de>>(deserializer: D) -> Result<String, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Id {
+        Number(i64),
+        Text(String),
+    }
+    Ok(match Id::deserialize(deserializer)? {
+        Id::Number(number) => number.to_string(),
+        Id::Text(text) => text,
+    })
+}
+
+/// Transactions per source session id, ordered by time.
+#[derive(Debug, Serialize, Deserialize, Default, PartialEq)]
+pub struct History {
+    pub transactions: BTreeMap<String, Vec<Transaction>>,
+}
+
+impl History {
+    /// Path of history file belonging to `portfolio_file`.
+    pub fn path(portfolio_file: &str) -> String {
+        Path::new(portfolio_file)
+            .with_extension("history.yaml")
+            .to_string_lossy()
+            .into_owned()
+    }
+
+    /// Read history from `filename`, or an empty one if it does not exist yet.
+    pub fn from_file(
+        filename: &str,
+        encryption_key: &Secret,
+    ) -> Result<History, error::PortfolioReadError> {
+        if !Path::new(filename).exists() {
+            return Ok(History::default());
+        }
+        Ok(serde_yaml::from_str(&Portfolio::read_contents(
+            filename,
+            encryption_key,
+        )?)?)
+    }
+
+    /// Write history to `filename`, encrypting it if `encryption_key` is given.
+    pub fn to_file(
+        &self,
+        filename: &str,
+        encryption_key: Option<&Secret>,
+    ) -> Result<(), error::PortfolioWriteError> {
+        Portfolio::write_contents(filename, &serde_yaml::to_string(&self)?, encryption_key)
+    }
+
+    /// Time of the latest transaction imported from `session_id`.
+    pub fn last_time(&self, session_id: &str) -> Option<DateTime<Utc>> {
+        self.transactions
+            .get(session_id)?
+            .iter()
+            .map(|transaction| transaction.time)
+            .max()
+    }
+
+    /// Whether `transaction` of `session_id` was imported already.
+    pub fn contains(&self, session_id: &str, transaction: &Transaction) -> bool {
+        self.transactions
+            .get(session_id)
+            .into_iter()
+            .flatten()
+            .any(|known| known.order == transaction.order && known.kind == transaction.kind)
+    }
+
+    /// Add `transactions` of `session_id` not imported yet. Returns number of added ones.
+    pub fn merge(&mut self, session_id: &str, transactions: Vec<Transaction>) -> usize {
+        let mut added = 0;
+        for transaction in transactions {
+            if self.contains(session_id, &transaction) {
+                continue;
+            }
+            self.transactions
+                .entry(session_id.to_string())
+                .or_default()
+                .push(transaction);
+            added += 1;
+        }
+        self.transactions
+            .entry(session_id.to_string())
+            .or_default()
+            .sort_by_key(|transaction| transaction.time);
+        added
+    }
+
+    /// Sum of amounts of `kind` transactions of `session_id`, per currency.
+    pub fn total(&self, session_id: &str, kind: TransactionKind) -> Vec<Amount> {
+        let mut totals: Vec<Amount> = vec![];
+        for transaction in self.transactions.get(session_id).into_iter().flatten() {
+            if transaction.kind != kind {
+                continue;
+            }
+            match totals
+                .iter_mut()
+                .find(|total| total.currency == transaction.amount.currency)
+            {
+                Some(total) => total.value += transaction.amount.value,
+                None => totals.push(transaction.amount.clone()),
+            }
+        }
+        totals
+    }
+}
+
+impl std::fmt::Display for Transaction {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} {:10} {:9.2} {}",
+            self.time.format("%Y-%m-%d %H:%M"),
+            format!("{:?}", self.kind),
+            self.amount.value,
+            self.amount.currency
+        )?;
+        if let Some(symbol) = &self.symbol {
+            write!(f, " {}", symbol)?;
+        }
+        if let (Some(volume), Some(price)) = (self.volume, &self.price) {
+            write!(f, " ({} at {:.2} {})", volume, price.value, price.currency)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::fmt::Display for History {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (session_id, transactions) in &self.transactions {
+            writeln!(f, "{} ({} transactions):", session_id, transactions.len())?;
+            for (kind, label) in [
+                (TransactionKind::Deposit, "Deposits"),
+                (TransactionKind::Withdrawal, "Withdrawals"),
+                (TransactionKind::Buy, "Buys"),
+                (TransactionKind::Sell, "Sells"),
+            ] {
+                let totals: Vec<String> = self
+                    .total(session_id, kind)
+                    .iter()
+                    .map(|total| format!("{:.2} {}", total.value, total.currency))
+                    .collect();
+                if !totals.is_empty() {
+                    writeln!(f, "- {:12}: {}", label, totals.join(", "))?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::amount::Currency;
+
+    fn transaction(order: i64, kind: TransactionKind, day: u32, value: f64) -> Transaction {
+        Transaction {
+            order: order.to_string(),
+            kind,
+            time: format!("2024-01-{:02}T10:00:00Z", day).parse().unwrap(),
+            symbol: None,
+            volume: None,
+            price: None,
+            amount: Amount::new(Currency::USD, value),
+        }
+    }
+
+    #[test]
+    fn merge_deduplicates_by_order() {
+        let mut history = History::default();
+        let added = history.merge(
+            "xtb:111",
+            vec![
+                transaction(2, TransactionKind::Buy, 2, 100.0),
+                transaction(1, TransactionKind::Deposit, 1, 500.0),
+            ],
+        );
+        assert_eq!(added, 2);
+        // Closing order may share number with an opening one, so kinds are told apart
+        let added = history.merge(
+            "xtb:111",
+            vec![
+                transaction(2, TransactionKind::Buy, 2, 100.0),
+                transaction(2, TransactionKind::Sell, 3, 120.0),
+            ],
+        );
+        assert_eq!(added, 1);
+        assert_eq!(
+            history.merge(
+                "xtb:222",
+                vec![transaction(1, TransactionKind::Deposit, 1, 50.0)]
+            ),
+            1
+        );
+
+        let orders: Vec<_> = history.transactions["xtb:111"]
+            .iter()
+            .map(|transaction| (transaction.order.as_str(), transaction.kind))
+            .collect();
+        assert_eq!(
+            orders,
+            vec![
+                ("1", TransactionKind::Deposit),
+                ("2", TransactionKind::Buy),
+                ("2", TransactionKind::Sell)
+            ]
+        );
+        assert_eq!(
+            history.last_time("xtb:111"),
+            Some("2024-01-03T10:00:00Z".parse().unwrap())
+        );
+        assert_eq!(
+            history.total("xtb:111", TransactionKind::Deposit),
+            vec![Amount::new(Currency::USD, 500.0)]
+        );
+    }
+
+    #[test]
+    fn history_file_roundtrip() {
+        let dir = tempfile::tempdir().unwrap();
+        let filename = History::path(dir.path().join("portfolio.yaml").to_str().unwrap());
+        assert!(filename.ends_with("portfolio.history.yaml"));
+        let key = Secret::from("key");
+        assert_eq!(
+            History::from_file(&filename, &key).unwrap(),
+            History::default()
+        );
+
+        let mut history = History::default();
+        history.merge(
+            "xtb:111",
+            vec![transaction(1, TransactionKind::Deposit, 1, 500.0)],
+        );
+        history.to_file(&filename, Some(&key)).unwrap();
+        assert_eq!(History::from_file(&filename, &key).unwrap(), history);
+        assert!(History::from_file(&filename, &Secret::from("wrong")).is_err());
+
+        // Earlier versions wrote ids as numbers
+        std::fs::write(
+            &filename,
+            "transactions:\n  xtb:111:\n  - order: 1\n    kind: deposit\n    time: 2024-01-01T10:00:00Z\n    amount:\n      currency: USD\n      value: 500.0\n",
+        )
+        .unwrap();
+        assert_eq!(History::from_file(&filename, &key).unwrap(), history);
+    }
+}
@@ -0,0 +1,460 @@
+//! Interactive Brokers position source reading Flex Query statements.
+//!
+//! IBKR has no API usable without its gateway running, so holdings are read from a Flex Query
+//! XML statement downloaded to disk (Reports → Flex Queries in Client Portal). The query should
+//! include sections Account Information, Open Positions, Cash Report, Trades and Cash
+//! Transactions; sections left out are read as empty. Statements of several accounts may share a
+//! file, the one of `account_id` is used.
+
+use async_trait::async_trait;
+use chrono::{DateTime, NaiveDate, NaiveDateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::str::FromStr;
+
+use crate::{
+    amount::{Amount, Currency},
+    broker::{Holding, PositionSource},
+    error,
+    history::{Transaction, TransactionKind},
+    secret::Secret,
+};
+
+/// IBKR account read from Flex Query statement file `flex_file`.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct IbkrAccount {
+    account_id: String,
+    flex_file: String,
+    #[serde(skip)]
+    statement: Option<FlexStatement>,
+}
+
+impl IbkrAccount {
+    #[allow(dead_code)]
+    pub fn new(account_id: String, flex_file: String) -> Self {
+        Self {
+            account_id,
+            flex_file,
+            statement: None,
+        }
+    }
+
+    pub fn account_id(&self) -> &str {
+        &self.account_id
+    }
+
+    fn statement(&self) -> Result<&FlexStatement, error::IbkrError> {
+        self.statement.as_ref().ok_or(error::IbkrError::NotRead)
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct FlexQueryResponse {
+    #[serde(rename = "FlexStatements")]
+    statements: FlexStatements,
+}
+
+#[derive(Debug, Deserialize)]
+struct FlexStatements {
+    #[serde(rename = "FlexStatement", default)]
+    statements: Vec<FlexStatement>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct FlexStatement {
+    #[serde(rename = "@accountId")]
+    account_id: String,
+    #[serde(rename = "AccountInformation")]
+    account_information: Option<AccountInformation>,
+    #[serde(rename = "OpenPositions", default)]
+    open_positions: OpenPositions,
+    #[serde(rename = "CashReport", default)]
+    cash_report: CashReport,
+    #[serde(rename = "Trades", default)]
+    trades: Trades,
+    #[serde(rename = "CashTransactions", default)]
+    cash_transactions: CashTransactions,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct AccountInformation {
+    /// Base currency of the account
+    #[serde(rename = "@currency")]
+    currency: String,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+struct OpenPositions {
+    #[serde(rename = "OpenPosition", default)]
+    positions: Vec<OpenPosition>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct OpenPosition {
+    #[serde(rename = "@symbol")]
+    symbol: String,
+    #[serde(rename = "@isin", default)]
+    isin: String,
+    #[serde(rename = "@currency")]
+    currency: String,
+    /// Quantity, negative for short positions
+    #[serde(rename = "@position")]
+    position: f64,
+    #[serde(rename = "@multiplier", default = "one")]
+    multiplier: f64,
+    #[serde(rename = "@markPrice")]
+    mark_price: f64,
+    #[serde(rename = "@positionValue")]
+    position_value: f64,
+    /// `SUMMARY` per position, `LOT` per tax lot when lots are included
+    #[serde(rename = "@levelOfDetail", default)]
+    level_of_detail: String,
+}
+
+fn one() -> f64 {
+    1.0
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+struct CashReport {
+    #[serde(rename = "CashReportCurrency", default)]
+    currencies: Vec<CashReportCurrency>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct CashReportCurrency {
+    /// Currency code, or `BASE_SUMMARY` for total of all currencies in base currency
+    #[serde(rename = "@currency")]
+    currency: String,
+    #[serde(rename = "@endingCash")]
+    ending_cash: f64,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+struct Trades {
+    #[serde(rename = "Trade", default)]
+    trades: Vec<Trade>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct Trade {
+    #[serde(rename = "@tradeID")]
+    trade_id: i64,
+    #[serde(rename = "@symbol")]
+    symbol: String,
+    #[serde(rename = "@currency")]
+    currency: String,
+    #[serde(rename = "@dateTime")]
+    date_time: String,
+    #[serde(rename = "@quantity")]
+    quantity: f64,
+    #[serde(rename = "@tradePrice")]
+    trade_price: f64,
+    /// Cash received, negative for purchases
+    #[serde(rename = "@proceeds")]
+    proceeds: f64,
+    /// `BUY` or `SELL`, cancelled trades are marked e.g. `SELL (Ca.)`
+    #[serde(rename = "@buySell")]
+    buy_sell: String,
+    /// `EXECUTION` per fill, other levels (e.g. `CLOSED_LOT`) repeat executions
+    #[serde(rename = "@levelOfDetail", default)]
+    level_of_detail: String,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+struct CashTransactions {
+    #[serde(rename = "CashTransaction", default)]
+    transactions: Vec<CashTransaction>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct CashTransaction {
+    #[serde(rename = "@transactionID")]
+    transaction_id: i64,
+    /// E.g. `Deposits/Withdrawals`, `Dividends`, `Withholding Tax`
+    #[serde(rename = "@type")]
+    kind: String,
+    #[serde(rename = "@currency")]
+    currency: String,
+    #[serde(rename = "@dateTime")]
+    date_time: String,
+    #[serde(rename = "@amount")]
+    amount: f64,
+}
+
+fn currency(code: &str) -> Result<Currency, error::IbkrError> {
+    Currency::from_str(code).map_err(|_| error::IbkrError::UnknownCurrency(code.to_string()))
+}
+
+/// Time reported by IBKR, e.g. `20240115;093000` or date only. Times are in the zone chosen in
+/// the Flex Query settings and are taken as UTC.
+fn date_time(value: &str) -> Result<DateTime<Utc>, error::IbkrError> {
+    let value = value.replace(['-', ':'], "").replace(' ', ";");
+    NaiveDateTime::parse_from_str(&value, "%Y%m%d;%H%M%S")
+        .or_else(|_| {
+            NaiveDate::parse_from_str(&value, "%Y%m%d")
+                .map(|date| date.and_time(Default::default()))
+        })
+        .map(|time| time.and_utc())
+        .map_err(|_| error::IbkrError::InvalidStatement(format!("invalid date time {}", value)))
+}
+
+impl FlexStatement {
+    fn holdings(&self) -> Result<Vec<Holding>, error::IbkrError> {
+        self.open_positions
+            .positions
+            .iter()
+            .filter(|position| position.level_of_detail != "LOT")
+            .map(|position| {
+                let currency = currency(&position.currency)?;
+                let market_value = Amount::new(currency, position.position_value);
+                Ok(Holding {
+                    symbol: position.symbol.clone(),
+                    isin: Some(position.isin.clone()).filter(|isin| !isin.is_empty()),
+                    quantity: position.position * position.multiplier,
+                    price: Amount::new(currency, position.mark_price),
+                    market_value: market_value.clone(),
+                    equity: market_value,
+                })
+            })
+            .collect()
+    }
+
+    /// Cash in base currency, or in the only currency held when base currency is not reported.
+    fn cash_balance(&self) -> Result<Option<Amount>, error::IbkrError> {
+        let (summary, currencies): (Vec<_>, Vec<_>) = self
+            .cash_report
+            .currencies
+            .iter()
+            .partition(|cash| cash.currency == "BASE_SUMMARY");
+        match (&self.account_information, summary.first(), &currencies[..]) {
+            (_, _, []) => Ok(None),
+            (_, _, [cash]) => Ok(Some(Amount::new(
+                currency(&cash.currency)?,
+                cash.ending_cash,
+            ))),
+            (Some(information), Some(summary), _) => Ok(Some(Amount::new(
+                currency(&information.currency)?,
+                summary.ending_cash,
+            ))),
+            _ => Err(error::IbkrError::InvalidStatement(
+                "cash in several currencies requires Account Information section".to_string(),
+            )),
+        }
+    }
+
+    fn transactions(
+        &self,
+        since: Option<DateTime<Utc>>,
+    ) -> Result<Vec<Transaction>, error::IbkrError> {
+        let mut transactions = vec![];
+        for trade in &self.trades.trades {
+            let kind = match (trade.level_of_detail.as_str(), trade.buy_sell.as_str()) {
+                ("EXECUTION" | "", "BUY") => TransactionKind::Buy,
+                ("EXECUTION" | "", "SELL") => TransactionKind::Sell,
+                _ => continue,
+            };
+            let currency = currency(&trade.currency)?;
+            transactions.push(Transaction {
+                order: format!("trade:{}", trade.trade_id),
+                kind,
+                time: date_time(&trade.date_time)?,
+                symbol: Some(trade.symbol.clone()),
+                volume: Some(trade.quantity.abs()),
+                price: Some(Amount::new(currency, trade.trade_price)),
+                amount: Amount::new(currency, trade.proceeds.abs()),
+            });
+        }
+        for cash in &self.cash_transactions.transactions {
+            if cash.kind != "Deposits/Withdrawals" {
+                continue;
+            }
+            transactions.push(Transaction {
+                order: format!("cash:{}", cash.transaction_id),
+                kind: match cash.amount >= 0.0 {
+                    true => TransactionKind::Deposit,
+                    false => TransactionKind::Withdrawal,
+                },
+                time: date_time(&cash.date_time)?,
+                symbol: None,
+                volume: None,
+                price: None,
+                amount: Amount::new(currency(&cash.currency)?, cash.amount.abs()),
+            });
+        }
+        transactions.retain(|transaction| since.is_none_or(|since| transaction.time >= since));
+        Ok(transactions)
+    }
+}
+
+#[async_trait]
+impl PositionSource for IbkrAccount {
+    /// Read statement of the account from the Flex Query file.
+    async fn connect(&mut self) -> Result<(), error::SourceError> {
+        let contents = std::fs::read_to_string(&self.flex_file).map_err(error::IbkrError::from)?;
+        let response: FlexQueryResponse =
+            quick_xml::de::from_str(&contents).map_err(error::IbkrError::from)?;
+        let statement = response
+            .statements
+            .statements
+            .into_iter()
+            .find(|statement| statement.account_id == self.account_id)
+            .ok_or(error::IbkrError::AccountNotFound(self.account_id.clone()))?;
+        self.statement = Some(statement);
+        Ok(())
+    }
+
+    /// Statements hold no credentials.
+    async fn authenticate(&mut self, _key: &Secret) -> Result<(), error::SourceError> {
+        Ok(())
+    }
+
+    async fn holdings(&mut self) -> Result<Vec<Holding>, error::SourceError> {
+        Ok(self.statement()?.holdings()?)
+    }
+
+    async fn cash_balance(&mut self) -> Result<Option<Amount>, error::SourceError> {
+        Ok(self.statement()?.cash_balance()?)
+    }
+
+    async fn transactions(
+        &mut self,
+        since: Option<DateTime<Utc>>,
+    ) -> Result<Vec<Transaction>, error::SourceError> {
+        Ok(self.statement()?.transactions(since)?)
+    }
+
+    async fn disconnect(&mut self) -> Result<(), error::SourceError> {
+        self.statement = None;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const STATEMENT: &str = r#"<FlexQueryResponse queryName="portfel" type="AF">
+<FlexStatements count="2">
+<FlexStatement accountId="U1111111" fromDate="20240101" toDate="20240131" period="LastMonth" whenGenerated="20240201;080000">
+<AccountInformation accountId="U1111111" currency="EUR" name="Test" />
+<OpenPositions>
+<OpenPosition accountId="U1111111" currency="USD" assetCategory="STK" symbol="CSPX" isin="IE00B5BMR087" position="10" multiplier="1" markPrice="500" positionValue="5000" levelOfDetail="SUMMARY" />
+<OpenPosition accountId="U1111111" currency="USD" assetCategory="STK" symbol="CSPX" isin="IE00B5BMR087" position="10" multiplier="1" markPrice="500" positionValue="5000" levelOfDetail="LOT" />
+<OpenPosition accountId="U1111111" currency="EUR" assetCategory="STK" symbol="EXSA" isin="" position="20" multiplier="1" markPrice="50" positionValue="1000" levelOfDetail="SUMMARY" />
+</OpenPositions>
+<CashReport>
+<CashReportCurrency accountId="U1111111" currency="BASE_SUMMARY" endingCash="1500" />
+<CashReportCurrency accountId="U1111111" currency="EUR" endingCash="400" />
+<CashReportCurrency accountId="U1111111" currency="USD" endingCash="1210" />
+</CashReport>
+<Trades>
+<Trade accountId="U1111111" currency="USD" symbol="CSPX" tradeID="101" dateTime="20240115;093000" quantity="10" tradePrice="490" proceeds="-4900" buySell="BUY" levelOfDetail="EXECUTION" />
+<Trade accountId="U1111111" currency="USD" symbol="CSPX" tradeID="101" dateTime="20240115;093000" quantity="10" tradePrice="490" proceeds="-4900" buySell="BUY" levelOfDetail="ORDER" />
+<Trade accountId="U1111111" currency="EUR" symbol="EXSA" tradeID="102" dateTime="2024-01-20;10:00:00" quantity="-5" tradePrice="52" proceeds="260" buySell="SELL" levelOfDetail="EXECUTION" />
+</Trades>
+<CashTransactions>
+<CashTransaction accountId="U1111111" currency="EUR" type="Deposits/Withdrawals" transactionID="201" dateTime="20240102" amount="6000" />
+<CashTransaction accountId="U1111111" currency="USD" type="Dividends" transactionID="202" dateTime="20240125" amount="12" />
+</CashTransactions>
+</FlexStatement>
+<FlexStatement accountId="U2222222" fromDate="20240101" toDate="20240131" period="LastMonth" whenGenerated="20240201;080000">
+<CashReport>
+<CashReportCurrency accountId="U2222222" currency="BASE_SUMMARY" endingCash="100" />
+<CashReportCurrency accountId="U2222222" currency="USD" endingCash="100" />
+</CashReport>
+</FlexStatement>
+</FlexStatements>
+</FlexQueryResponse>
+"#;
+
+    #[tokio::test]
+    async fn read_flex_statement() {
+        let dir = tempfile::tempdir().unwrap();
+        let flex_file = dir.path().join("flex.xml");
+        std::fs::write(&flex_file, STATEMENT).unwrap();
+        let flex_file = flex_file.to_str().unwrap().to_string();
+
+        let mut account = IbkrAccount::new("U1111111".to_string(), flex_file.clone());
+        account.connect().await.unwrap();
+        account.authenticate(&Secret::from("key")).await.unwrap();
+        // Tax lots repeat the summary, so they are left out
+        assert_eq!(
+            account.holdings().await.unwrap(),
+            vec![
+                Holding {
+                    symbol: "CSPX".to_string(),
+                    isin: Some("IE00B5BMR087".to_string()),
+                    quantity: 10.0,
+                    price: Amount::new(Currency::USD, 500.0),
+                    market_value: Amount::new(Currency::USD, 5000.0),
+                    equity: Amount::new(Currency::USD, 5000.0),
+                },
+                Holding {
+                    symbol: "EXSA".to_string(),
+                    isin: None,
+                    quantity: 20.0,
+                    price: Amount::new(Currency::EUR, 50.0),
+                    market_value: Amount::new(Currency::EUR, 1000.0),
+                    equity: Amount::new(Currency::EUR, 1000.0),
+                },
+            ]
+        );
+        assert_eq!(
+            account.cash_balance().await.unwrap(),
+            Some(Amount::new(Currency::EUR, 1500.0))
+        );
+
+        let transactions: Vec<_> = account
+            .transactions(None)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|transaction| {
+                (
+                    transaction.order.clone(),
+                    transaction.kind,
+                    transaction.amount,
+                )
+            })
+            .collect();
+        assert_eq!(
+            transactions,
+            vec![
+                (
+                    "trade:101".to_string(),
+                    TransactionKind::Buy,
+                    Amount::new(Currency::USD, 4900.0)
+                ),
+                (
+                    "trade:102".to_string(),
+                    TransactionKind::Sell,
+                    Amount::new(Currency::EUR, 260.0)
+                ),
+                (
+                    "cash:201".to_string(),
+                    TransactionKind::Deposit,
+                    Amount::new(Currency::EUR, 6000.0)
+                ),
+            ]
+        );
+        let since = "2024-01-16T00:00:00Z".parse().unwrap();
+        assert_eq!(account.transactions(Some(since)).await.unwrap().len(), 1);
+        account.disconnect().await.unwrap();
+
+        /* Cash of an account holding a single currency needs no base currency */
+        let mut account = IbkrAccount::new("U2222222".to_string(), flex_file.clone());
+        account.connect().await.unwrap();
+        assert!(account.holdings().await.unwrap().is_empty());
+        assert_eq!(
+            account.cash_balance().await.unwrap(),
+            Some(Amount::new(Currency::USD, 100.0))
+        );
+
+        let mut account = IbkrAccount::new("U3333333".to_string(), flex_file);
+        assert!(matches!(
+            account.connect().await,
+            Err(error::SourceError::IbkrError(error::IbkrError::AccountNotFound(account_id)))
+                if account_id == "U3333333"
+        ));
+    }
+}
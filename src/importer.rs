@@ -0,0 +1,534 @@
+//! Import of CSV exports of banks and brokers without an API.
+//!
+//! Every export looks different, so the columns to read, number and date formats and currency
+//! are described by a named `CsvFormat` in the portfolio config. A format reads either a snapshot
+//! of holdings, setting amounts of positions of its group, or transactions, added to the local
+//! history.
+
+use std::collections::{BTreeMap, HashMap};
+use std::str::FromStr;
+
+use chrono::{DateTime, NaiveDate, NaiveDateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    amount::{Amount, Currency},
+    broker::Holding,
+    error,
+    history::{Transaction, TransactionKind},
+};
+
+/// What rows of a CSV export are.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum CsvKind {
+    /// Current holdings, one instrument per row
+    Holdings,
+    /// Cash operations and trades, one per row
+    Transactions,
+}
+
+/// Header names of columns read from the export. Unused columns are left out.
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct Columns {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub symbol: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub isin: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub quantity: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub price: Option<String>,
+    /// Market value of a holding
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub value: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub currency: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub date: Option<String>,
+    /// Type of a transaction, mapped by `CsvFormat::kinds`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub kind: Option<String>,
+    /// Cash amount of a transaction, negative when paid
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub amount: Option<String>,
+    /// Unique id of a transaction, a hash of the row (and its number among identical rows) is used
+    /// if not given
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub order: Option<String>,
+}
+
+/// Layout of a CSV export.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct CsvFormat {
+    pub kind: CsvKind,
+    /// Group of positions set from a holdings snapshot
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub group: Option<String>,
+    #[serde(default = "default_delimiter")]
+    pub delimiter: char,
+    /// Decimal separator of numbers, any other characters than digits and sign are ignored
+    #[serde(default = "default_decimal_separator")]
+    pub decimal_separator: char,
+    /// `chrono` format of dates, with or without time
+    #[serde(default = "default_date_format")]
+    pub date_format: String,
+    /// Lines before the header line
+    #[serde(default)]
+    pub skip_lines: usize,
+    pub columns: Columns,
+    /// Currency of all amounts when there is no currency column
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub currency: Option<Currency>,
+    /// Transaction kind per value of kind column. Rows of other kinds (e.g. fees) are skipped.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub kinds: BTreeMap<String, TransactionKind>,
+}
+
+fn default_delimiter() -> char {
+    ','
+}
+
+fn default_decimal_separator() -> char {
+    '.'
+}
+
+fn default_date_format() -> String {
+    "%Y-%m-%d".to_string()
+}
+
+/// Single row of an export, with its line number for error messages.
+struct Row<'a> {
+    format: &'a CsvFormat,
+    headers: &'a csv::StringRecord,
+    record: csv::StringRecord,
+    line: usize,
+}
+
+impl Row<'_> {
+    /// Non-empty value of `column`, if the format reads it.
+    fn get(&self, column: &Option<String>) -> Result<Option<&str>, error::ImportError> {
+        let Some(column) = column else {
+            return Ok(None);
+        };
+        let index = self
+            .headers
+            .iter()
+            .position(|header| header.trim() == column)
+            .ok_or(error::ImportError::MissingColumn(column.clone()))?;
+        Ok(self
+            .record
+            .get(index)
+            .map(str::trim)
+            .filter(|value| !value.is_empty()))
+    }
+
+    fn required(&self, column: &Option<String>, name: &str) -> Result<&str, error::ImportError> {
+        self.get(column)?
+            .ok_or(self.invalid(column.as_deref().unwrap_or(name), ""))
+    }
+
+    fn invalid(&self, column: &str, value: &str) -> error::ImportError {
+        error::ImportError::InvalidValue(self.line, column.to_string(), value.to_string())
+    }
+
+    fn number(&self, column: &Option<String>) -> Result<Option<f64>, error::ImportError> {
+        let Some(value) = self.get(column)? else {
+            return Ok(None);
+        };
+        // Thousands separators, spaces and currency symbols are left out
+        let number: String = value
+            .chars()
+            .filter(|c| c.is_ascii_digit() || *c == '-' || *c == self.format.decimal_separator)
+            .map(|c| match c == self.format.decimal_separator {
+                true => '.',
+                false => c,
+            })
+            .collect();
+        number
+            .parse()
+            .map(Some)
+            .map_err(|_| self.invalid(column.as_deref().unwrap_or_default(), value))
+    }
+
+    fn currency(&self) -> Result<Currency, error::ImportError> {
+        match (
+            self.get(&self.format.columns.currency)?,
+            self.format.currency,
+        ) {
+            (Some(code), _) => Currency::from_str(code)
+                .map_err(|_| error::ImportError::UnknownCurrency(code.to_string())),
+            (None, Some(currency)) => Ok(currency),
+            (None, None) => Err(self.invalid("currency", "")),
+        }
+    }
+
+    fn date(&self) -> Result<DateTime<Utc>, error::ImportError> {
+        let column = &self.format.columns.date;
+        let value = self.required(column, "date")?;
+        let format = &self.format.date_format;
+        NaiveDateTime::parse_from_str(value, format)
+            .or_else(|_| {
+                NaiveDate::parse_from_str(value, format)
+                    .map(|date| date.and_time(Default::default()))
+            })
+            .map(|time| time.and_utc())
+            .map_err(|_| self.invalid(column.as_deref().unwrap_or_default(), value))
+    }
+
+    /// Stable id of the row, so that importing the same row again does not duplicate it.
+    ///
+    /// Identical rows (e.g. two equal payments on one day) are told apart by `occurrence`, the
+    /// number of identical rows before this one.
+    fn hash(&self, occurrence: usize) -> i64 {
+        let mut contents = self.record.iter().collect::<Vec<_>>().join("\x1f");
+        // First occurrence keeps the id rows had before identical ones were told apart
+        if occurrence > 0 {
+            contents.push_str(&format!("\x1e{}", occurrence));
+        }
+        // FNV-1a, unlike `DefaultHasher` guaranteed not to change between releases
+        let mut hash: u64 = 0xcbf29ce484222325;
+        for byte in contents.bytes() {
+            hash ^= byte as u64;
+            hash = hash.wrapping_mul(0x100000001b3);
+        }
+        (hash >> 1) as i64
+    }
+}
+
+impl CsvFormat {
+    fn rows<'a>(
+        &'a self,
+        headers: &'a mut csv::StringRecord,
+        contents: &str,
+    ) -> Result<Vec<Row<'a>>, error::ImportError> {
+        let contents = contents.trim_start_matches('\u{feff}');
+        let data: String = contents
+            .lines()
+            .skip(self.skip_lines)
+            .collect::<Vec<_>>()
+            .join("\n");
+        let mut delimiter = [0; 4];
+        let delimiter = self.delimiter.encode_utf8(&mut delimiter);
+        if delimiter.len() != 1 {
+            return Err(error::ImportError::InvalidFormat(format!(
+                "delimiter {} is not a single byte",
+                self.delimiter
+            )));
+        }
+        let mut reader = csv::ReaderBuilder::new()
+            .delimiter(delimiter.as_bytes()[0])
+            .flexible(true)
+            .from_reader(data.as_bytes());
+        *headers = reader.headers()?.clone();
+        let headers = &*headers;
+        let mut rows = vec![];
+        for (index, record) in reader.records().enumerate() {
+            rows.push(Row {
+                format: self,
+                headers,
+                record: record?,
+                // Header line and skipped lines precede, lines are numbered from 1
+                line: self.skip_lines + index + 2,
+            });
+        }
+        Ok(rows)
+    }
+
+    /// Holdings listed in a snapshot export. Rows without symbol and ISIN (e.g. totals) are
+    /// skipped.
+    pub fn read_holdings(&self, contents: &str) -> Result<Vec<Holding>, error::ImportError> {
+        let columns = &self.columns;
+        if columns.symbol.is_none() && columns.isin.is_none() {
+            return Err(error::ImportError::InvalidFormat(
+                "holdings need symbol or isin column".to_string(),
+            ));
+        }
+        if self.group.is_none() {
+            return Err(error::ImportError::InvalidFormat(
+                "holdings need group".to_string(),
+            ));
+        }
+        let mut headers = csv::StringRecord::new();
+        let mut holdings = vec![];
+        for row in self.rows(&mut headers, contents)? {
+            let isin = row.get(&columns.isin)?.map(str::to_string);
+            let Some(symbol) = row
+                .get(&columns.symbol)?
+                .map(str::to_string)
+                .or(isin.clone())
+            else {
+                continue;
+            };
+            let currency = row.currency()?;
+            let quantity = row.number(&columns.quantity)?;
+            let price = row.number(&columns.price)?;
+            let value = match (row.number(&columns.value)?, quantity, price) {
+                (Some(value), _, _) => value,
+                (None, Some(quantity), Some(price)) => quantity * price,
+                _ => return Err(row.invalid(columns.value.as_deref().unwrap_or("value"), "")),
+            };
+            // Export with values only lists every holding as a single unit
+            let quantity = quantity.unwrap_or(1.0);
+            let price = match price {
+                Some(price) => price,
+                None if quantity != 0.0 => value / quantity,
+                None if value == 0.0 => 0.0,
+                None => return Err(error::ImportError::ZeroQuantity(row.line, symbol)),
+            };
+            holdings.push(Holding {
+                symbol,
+                isin,
+                quantity,
+                price: Amount::new(currency, price),
+                market_value: Amount::new(currency, value),
+                equity: Amount::new(currency, value),
+            });
+        }
+        Ok(holdings)
+    }
+
+    /// Transactions listed in an export.
+    ///
+    /// Kind of a row is mapped from kind column by `kinds`. Without kind column, rows with a
+    /// symbol are buys (cash paid) or sells, other rows are deposits or withdrawals.
+    pub fn read_transactions(
+        &self,
+        contents: &str,
+    ) -> Result<Vec<Transaction>, error::ImportError> {
+        let columns = &self.columns;
+        if columns.date.is_none() || columns.amount.is_none() {
+            return Err(error::ImportError::InvalidFormat(
+                "transactions need date and amount columns".to_string(),
+            ));
+        }
+        let mut headers = csv::StringRecord::new();
+        let mut transactions = vec![];
+        let mut occurrences: HashMap<i64, usize> = HashMap::new();
+        for row in self.rows(&mut headers, contents)? {
+            // Rows of other kinds are skipped before their amount is looked at, as informational
+            // rows often have none
+            let kind = match row.get(&columns.kind)? {
+                Some(kind) => match self.kinds.get(kind) {
+                    Some(kind) => Some(*kind),
+                    None => continue,
+                },
+                None if columns.kind.is_some() => continue,
+                None => None,
+            };
+            let amount = row
+                .number(&columns.amount)?
+                .ok_or(row.invalid(columns.amount.as_deref().unwrap_or_default(), ""))?;
+            let symbol = row.get(&columns.symbol)?.map(str::to_string);
+            let kind = kind.unwrap_or(match (symbol.is_some(), amount < 0.0) {
+                (true, true) => TransactionKind::Buy,
+                (true, false) => TransactionKind::Sell,
+                (false, true) => TransactionKind::Withdrawal,
+                (false, false) => TransactionKind::Deposit,
+            });
+            let order = match row.get(&columns.order)? {
+                Some(order) => order.to_string(),
+                None => {
+                    let occurrence = occurrences.entry(row.hash(0)).or_default();
+                    *occurrence += 1;
+                    row.hash(*occurrence - 1).to_string()
+                }
+            };
+            let currency = row.currency()?;
+            transactions.push(Transaction {
+                order,
+                kind,
+                time: row.date()?,
+                symbol,
+                volume: row.number(&columns.quantity)?.map(f64::abs),
+                price: row
+                    .number(&columns.price)?
+                    .map(|price| Amount::new(currency, price)),
+                amount: Amount::new(currency, amount.abs()),
+            });
+        }
+        Ok(transactions)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn format(yaml: &str) -> CsvFormat {
+        serde_yaml::from_str(yaml).unwrap()
+    }
+
+    #[test]
+    fn read_holdings() {
+        // Degiro portfolio export
+        let format = format(
+            r#"
+kind: holdings
+group: degiro
+decimal_separator: ','
+columns:
+  symbol: Symbol/ISIN
+  quantity: Quantity
+  price: Closing
+  value: Local value
+  currency: Currency
+"#,
+        );
+        let contents = "\u{feff}Product,Symbol/ISIN,Quantity,Closing,Currency,Local value\n\
+            CASH & CASH FUND & FTX CASH (EUR),,,,EUR,\"12,50\"\n\
+            VANGUARD FTSE AW,IE00BK5BQT80,10,\"105,20\",EUR,\"1.052,00\"\n\
+            ISHARES CORE S&P 500,IE00B5BMR087,2,\"500,00\",USD,\"1000,00\"\n";
+        let holdings = format.read_holdings(contents).unwrap();
+        assert_eq!(
+            holdings,
+            vec![
+                Holding {
+                    symbol: "IE00BK5BQT80".to_string(),
+                    isin: None,
+                    quantity: 10.0,
+                    price: Amount::new(Currency::EUR, 105.2),
+                    market_value: Amount::new(Currency::EUR, 1052.0),
+                    equity: Amount::new(Currency::EUR, 1052.0),
+                },
+                Holding {
+                    symbol: "IE00B5BMR087".to_string(),
+                    isin: None,
+                    quantity: 2.0,
+                    price: Amount::new(Currency::USD, 500.0),
+                    market_value: Amount::new(Currency::USD, 1000.0),
+                    equity: Amount::new(Currency::USD, 1000.0),
+                },
+            ]
+        );
+
+        let missing = format.read_holdings("Product,Quantity\nA,1\n");
+        assert!(
+            matches!(missing, Err(error::ImportError::MissingColumn(column)) if column == "Symbol/ISIN")
+        );
+        let invalid = format.read_holdings(
+            "Symbol/ISIN,Quantity,Closing,Currency,Local value\nIE00BK5BQT80,ten,1,EUR,\n",
+        );
+        assert!(matches!(
+            invalid,
+            Err(error::ImportError::InvalidValue(2, column, value)) if column == "Quantity" && value == "ten"
+        ));
+        let zero = format.read_holdings(
+            "Symbol/ISIN,Quantity,Closing,Currency,Local value\nIE00BK5BQT80,0,,EUR,\"100,00\"\n",
+        );
+        assert!(matches!(
+            zero,
+            Err(error::ImportError::ZeroQuantity(2, symbol)) if symbol == "IE00BK5BQT80"
+        ));
+
+        let format = CsvFormat {
+            group: None,
+            ..format
+        };
+        assert!(matches!(
+            format.read_holdings(contents),
+            Err(error::ImportError::InvalidFormat(_))
+        ));
+    }
+
+    #[test]
+    fn read_transactions() {
+        // mBank account history, preceded by account details
+        let format = format(
+            r#"
+kind: transactions
+delimiter: ';'
+decimal_separator: ','
+date_format: '%d.%m.%Y'
+skip_lines: 2
+currency: PLN
+columns:
+  date: Data operacji
+  kind: Opis operacji
+  amount: Kwota
+kinds:
+  PRZELEW PRZYCHODZĄCY: deposit
+  PRZELEW WYCHODZĄCY: withdrawal
+"#,
+        );
+        let contents = "mBank S.A.\n\
+            Konto: 12 3456\n\
+            Data operacji;Opis operacji;Kwota\n\
+            02.01.2024;PRZELEW PRZYCHODZĄCY;1 000,00 PLN\n\
+            05.01.2024;OPŁATA;-5,00 PLN\n\
+            08.01.2024;BLOKADA ŚRODKÓW;\n\
+            10.01.2024;PRZELEW WYCHODZĄCY;-250,50 PLN\n";
+        let transactions = format.read_transactions(contents).unwrap();
+        let summary: Vec<_> = transactions
+            .iter()
+            .map(|transaction| (transaction.kind, transaction.amount.clone()))
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                (TransactionKind::Deposit, Amount::new(Currency::PLN, 1000.0)),
+                (
+                    TransactionKind::Withdrawal,
+                    Amount::new(Currency::PLN, 250.5)
+                ),
+            ]
+        );
+        assert_eq!(
+            transactions[0].time,
+            "2024-01-02T00:00:00Z".parse::<DateTime<Utc>>().unwrap()
+        );
+        // Rows are identified by their contents, so reading them again gives the same orders
+        assert_eq!(format.read_transactions(contents).unwrap(), transactions);
+        assert_ne!(transactions[0].order, transactions[1].order);
+        // Identical rows are still separate transactions, the first keeps its order
+        let repeated = format
+            .read_transactions(&format!(
+                "{}10.01.2024;PRZELEW WYCHODZĄCY;-250,50 PLN\n",
+                contents
+            ))
+            .unwrap();
+        assert_eq!(repeated.len(), 3);
+        assert_eq!(repeated[..2], transactions[..]);
+        assert_ne!(repeated[2].order, repeated[1].order);
+
+        /* Without kind column, kinds follow from symbol and sign of amount */
+        let format = format_without_kinds();
+        let transactions = format
+            .read_transactions(
+                "Date,Symbol,Quantity,Price,Amount,Currency,Id\n\
+                2024-01-02 10:00:00,,,,500,USD,1\n\
+                2024-01-03 11:00:00,VWRA,2,100,-200,USD,2\n",
+            )
+            .unwrap();
+        assert_eq!(
+            transactions[1],
+            Transaction {
+                order: "2".to_string(),
+                kind: TransactionKind::Buy,
+                time: "2024-01-03T11:00:00Z".parse().unwrap(),
+                symbol: Some("VWRA".to_string()),
+                volume: Some(2.0),
+                price: Some(Amount::new(Currency::USD, 100.0)),
+                amount: Amount::new(Currency::USD, 200.0),
+            }
+        );
+        assert_eq!(transactions[0].kind, TransactionKind::Deposit);
+    }
+
+    fn format_without_kinds() -> CsvFormat {
+        format(
+            r#"
+kind: transactions
+date_format: '%Y-%m-%d %H:%M:%S'
+columns:
+  date: Date
+  symbol: Symbol
+  quantity: Quantity
+  price: Price
+  amount: Amount
+  currency: Currency
+  order: Id
+"#,
+        )
+    }
+}
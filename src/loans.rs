@@ -0,0 +1,169 @@
+//! Loans (e.g. mortgages) held as liabilities, repaid in monthly installments.
+//!
+//! Interest is charged monthly at a twelfth of the yearly rate on the balance left. Installments
+//! are either equal (annuity), or repay equal parts of the principal, with interest on top. The
+//! first installment is due a month after the loan starts. All values are rounded to grosz.
+
+use chrono::{Months, NaiveDate};
+use serde::{Deserialize, Serialize};
+
+use crate::amount::Amount;
+
+/// How a loan is repaid.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum Installments {
+    /// Same installment every month
+    #[default]
+    Equal,
+    /// Same part of the principal every month, plus interest
+    Decreasing,
+}
+
+/// Loan of `principal` at yearly `rate`, repaid in `months` monthly installments.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct Loan {
+    pub principal: Amount,
+    /// Yearly interest rate, e.g. 0.072
+    pub rate: f64,
+    pub start: NaiveDate,
+    pub months: u32,
+    #[serde(default)]
+    pub installments: Installments,
+}
+
+/// Single installment of the amortization schedule, in the currency of the loan.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Installment {
+    pub date: NaiveDate,
+    pub payment: f64,
+    pub interest: f64,
+    /// Part of the principal repaid
+    pub principal: f64,
+    /// Principal left to repay after this installment
+    pub balance: f64,
+}
+
+fn round(value: f64) -> f64 {
+    (value * 100.0).round() / 100.0
+}
+
+impl Loan {
+    /// All installments, the last one repaying whatever is left after rounding.
+    pub fn schedule(&self) -> Vec<Installment> {
+        let monthly_rate = self.rate / 12.0;
+        let months = self.months as f64;
+        let annuity = match monthly_rate == 0.0 {
+            true => self.principal.value / months,
+            false => {
+                self.principal.value * monthly_rate / (1.0 - (1.0 + monthly_rate).powf(-months))
+            }
+        };
+        let mut balance = self.principal.value;
+        (1..=self.months)
+            .map(|month| {
+                let interest = round(balance * monthly_rate);
+                let principal = match (month == self.months, self.installments) {
+                    (true, _) => balance,
+                    (false, Installments::Equal) => round(annuity) - interest,
+                    (false, Installments::Decreasing) => round(self.principal.value / months),
+                };
+                balance = round(balance - principal);
+                Installment {
+                    date: self.start + Months::new(month),
+                    payment: round(principal + interest),
+                    interest,
+                    principal: round(principal),
+                    balance,
+                }
+            })
+            .collect()
+    }
+
+    /// Principal left to repay after installments due until `date`, as a negative amount.
+    pub fn value(&self, date: NaiveDate) -> Amount {
+        let balance = self
+            .schedule()
+            .iter()
+            .take_while(|installment| installment.date <= date)
+            .last()
+            .map_or(self.principal.value, |installment| installment.balance);
+        Amount::new(self.principal.currency, -balance)
+    }
+
+    /// First installment due after `date`, if any is left.
+    pub fn next_installment(&self, date: NaiveDate) -> Option<Installment> {
+        self.schedule()
+            .into_iter()
+            .find(|installment| installment.date > date)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::amount::Currency;
+
+    fn date(value: &str) -> NaiveDate {
+        value.parse().unwrap()
+    }
+
+    fn loan(installments: Installments) -> Loan {
+        Loan {
+            principal: Amount::new(Currency::PLN, 12000.0),
+            rate: 0.06,
+            start: date("2025-01-15"),
+            months: 12,
+            installments,
+        }
+    }
+
+    #[test]
+    fn equal_installments() {
+        let loan = loan(Installments::Equal);
+        let schedule = loan.schedule();
+        assert_eq!(schedule.len(), 12);
+        // 12000 * 0.005 / (1 - 1.005^-12) = 1032.80, of which 60.00 interest
+        assert_eq!(
+            schedule[0],
+            Installment {
+                date: date("2025-02-15"),
+                payment: 1032.8,
+                interest: 60.0,
+                principal: 972.8,
+                balance: 11027.2,
+            }
+        );
+        assert_eq!(schedule[11].date, date("2026-01-15"));
+        assert_eq!(schedule[11].balance, 0.0);
+        assert!((schedule[11].payment - 1032.8).abs() < 0.05);
+
+        assert_eq!(
+            loan.value(date("2025-02-14")),
+            Amount::new(Currency::PLN, -12000.0)
+        );
+        assert_eq!(
+            loan.value(date("2025-02-15")),
+            Amount::new(Currency::PLN, -11027.2)
+        );
+        assert_eq!(
+            loan.value(date("2026-06-01")),
+            Amount::new(Currency::PLN, 0.0)
+        );
+        assert_eq!(
+            loan.next_installment(date("2025-02-15")).unwrap().date,
+            date("2025-03-15")
+        );
+        assert_eq!(loan.next_installment(date("2026-01-15")), None);
+    }
+
+    #[test]
+    fn decreasing_installments() {
+        let schedule = loan(Installments::Decreasing).schedule();
+        // 1000 of principal every month, interest on the balance left
+        assert_eq!(schedule[0].payment, 1060.0);
+        assert_eq!(schedule[1].payment, 1055.0);
+        assert_eq!(schedule[11].payment, 1005.0);
+        assert_eq!(schedule[11].balance, 0.0);
+    }
+}
@@ -1,15 +1,52 @@
-use std::{env, str::FromStr};
+use std::{env, str::FromStr, time::Duration};
 
-use crate::{amount::Amount, amount::Currency};
-use clap::{Parser, Subcommand};
+use crate::{amount::Amount, amount::Currency, secret::Secret};
+use clap::{ArgGroup, Parser, Subcommand};
 
+mod agent;
 mod amount;
+mod bonds;
+mod broker;
 mod crypt;
+mod deposits;
 mod error;
 mod fx;
+mod glide;
+mod history;
+mod ibkr;
+mod importer;
+mod loans;
+mod orders;
 mod portfolio;
+mod quotes;
+mod secret;
+#[cfg(test)]
+mod testing;
+mod watch;
 mod xtb;
 
+#[derive(Subcommand)]
+enum AgentCommands {
+    /// Start the key agent in the background
+    Start {
+        /// Forget the key this many seconds after it was added
+        #[arg(short, long, default_value_t = 900)]
+        timeout: u64,
+    },
+    /// Run the key agent in the foreground
+    #[clap(hide = true)]
+    Serve {
+        #[arg(short, long, default_value_t = 900)]
+        timeout: u64,
+    },
+    /// Add portfolio key to the running key agent
+    Add,
+    /// Make the key agent forget the portfolio key
+    Clear,
+    /// Stop the key agent
+    Stop,
+}
+
 #[derive(Subcommand)]
 enum Commands {
     EncryptPassword,
@@ -18,6 +55,12 @@ enum Commands {
         portfolio: Option<String>,
         #[arg(short, long)]
         xtb_accont_id: Option<String>,
+        /// XTB account is a demo account, instead of a real one
+        #[arg(long)]
+        demo: bool,
+        /// Encrypt the whole portfolio file with the portfolio key
+        #[arg(long)]
+        encrypt: bool,
     },
     Show {
         #[clap(short, long, value_name = "YAML")]
@@ -31,6 +74,94 @@ enum Commands {
         #[arg(short, long)]
         currency: String,
     },
+    /// Place market orders at XTB for the investment suggested by `invest`
+    #[command(group(ArgGroup::new("server").required(true).args(["demo", "live"])))]
+    Execute {
+        #[clap(short, long, value_name = "YAML")]
+        portfolio: Option<String>,
+        #[arg(short, long)]
+        amount: f64,
+        #[arg(short, long)]
+        currency: String,
+        /// Use the XTB demo server
+        #[arg(long)]
+        demo: bool,
+        /// Use the XTB live server, trading with real money
+        #[arg(long)]
+        live: bool,
+        /// Also place orders at accounts whose endpoint is not a public XTB server
+        #[arg(long)]
+        custom_endpoints: bool,
+    },
+    /// Encrypt the whole portfolio file with the portfolio key
+    EncryptFile {
+        #[clap(short, long, value_name = "YAML")]
+        portfolio: Option<String>,
+    },
+    /// Decrypt an encrypted portfolio file back to plain YAML
+    DecryptFile {
+        #[clap(short, long, value_name = "YAML")]
+        portfolio: Option<String>,
+    },
+    /// Edit the portfolio file in `$EDITOR`, decrypting and re-encrypting it if needed
+    Edit {
+        #[clap(short, long, value_name = "YAML")]
+        portfolio: Option<String>,
+    },
+    /// Change the portfolio key, re-encrypting all stored credentials
+    ChangeKey {
+        #[clap(short, long, value_name = "YAML")]
+        portfolio: Option<String>,
+    },
+    /// Check shares of positions against targets, failing if any drifted beyond threshold
+    Check {
+        #[clap(short, long, value_name = "YAML")]
+        portfolio: Option<String>,
+    },
+    /// Print target shares of the glide path from today on
+    Glide {
+        #[clap(short, long, value_name = "YAML")]
+        portfolio: Option<String>,
+    },
+    /// Show allocation live, with prices streamed from XTB
+    Watch {
+        #[clap(short, long, value_name = "YAML")]
+        portfolio: Option<String>,
+    },
+    /// Import deposits, buys and sells from position sources into local transaction history
+    ImportHistory {
+        #[clap(short, long, value_name = "YAML")]
+        portfolio: Option<String>,
+        /// Import transactions since this date (YYYY-MM-DD) instead of since the last import
+        #[arg(short, long)]
+        since: Option<chrono::NaiveDate>,
+    },
+    /// Add broker holdings not held as any position to the portfolio file
+    Adopt {
+        #[clap(short, long, value_name = "YAML")]
+        portfolio: Option<String>,
+        /// Adopt only this symbol instead of all unallocated holdings
+        #[arg(short, long)]
+        symbol: Option<String>,
+    },
+    /// Import a holdings snapshot or transactions from a CSV export of a bank or broker
+    ImportCsv {
+        #[clap(short, long, value_name = "YAML")]
+        portfolio: Option<String>,
+        /// Name of the export layout in `csv_formats` of portfolio config
+        #[arg(short, long)]
+        format: String,
+        /// Show what would change without writing anything
+        #[arg(long)]
+        dry_run: bool,
+        /// CSV export to import
+        file: String,
+    },
+    /// Manage the key agent holding the portfolio key in memory
+    Agent {
+        #[clap(subcommand)]
+        command: AgentCommands,
+    },
 }
 
 #[derive(Parser)]
@@ -59,6 +190,196 @@ fn get_portfolio_file(path: &Option<String>) -> String {
     file_path
 }
 
+/// Read secret from the terminal without echoing it.
+fn prompt_secret(prompt: &str) -> Secret {
+    Secret::new(rpassword::prompt_password(prompt).unwrap())
+}
+
+/// Portfolio key, either held by the key agent or typed in by the user.
+struct PortfolioKey {
+    key: Secret,
+    /// Key was typed in, so it is not known to the key agent yet
+    prompted: bool,
+}
+
+impl PortfolioKey {
+    /// Get key from the key agent, falling back to `PORTFOLIO_KEY` and prompting the user.
+    async fn get() -> PortfolioKey {
+        if let Some(key) = agent::get_key().await {
+            return PortfolioKey {
+                key,
+                prompted: false,
+            };
+        }
+        match env::var("PORTFOLIO_KEY") {
+            Ok(key) => PortfolioKey {
+                key: Secret::new(key),
+                prompted: false,
+            },
+            Err(_) => PortfolioKey {
+                key: prompt_secret("Portfolio key: "),
+                prompted: true,
+            },
+        }
+    }
+
+    /// Hand typed-in key over to the key agent, if it is running and the key is `verified`, i.e.
+    /// something was decrypted with it.
+    async fn remember(&self, verified: bool) {
+        if self.prompted && verified {
+            match agent::add_key(&self.key).await {
+                Ok(()) | Err(error::AgentError::NotRunning) => {}
+                Err(e) => log::warn!("Unable to add key to key agent: {}", e),
+            }
+        }
+    }
+}
+
+/// Whether reading `portfolio_file` proved the key correct: the file is encrypted, or credentials
+/// of position sources of `authenticated` portfolio were decrypted with the key.
+fn key_verified(portfolio_file: &str, authenticated: Option<&portfolio::Portfolio>) -> bool {
+    matches!(
+        portfolio::Portfolio::is_encrypted_file(portfolio_file),
+        Ok(true)
+    ) || authenticated.is_some_and(|portfolio| {
+        portfolio
+            .sources()
+            .iter()
+            .any(|source_config| source_config.uses_key())
+    })
+}
+
+/// Ask for a yes/no answer on the terminal, defaulting to yes.
+fn confirm(question: &str) -> bool {
+    print!("{} [Y/n] ", question);
+    std::io::Write::flush(&mut std::io::stdout()).unwrap();
+    let mut answer = String::new();
+    std::io::stdin().read_line(&mut answer).unwrap();
+    !matches!(answer.trim().to_lowercase().as_str(), "n" | "no")
+}
+
+/// Ask for a yes/no answer on the terminal, defaulting to no.
+fn confirm_default_no(question: &str) -> bool {
+    print!("{} [y/N] ", question);
+    std::io::Write::flush(&mut std::io::stdout()).unwrap();
+    let mut answer = String::new();
+    std::io::stdin().read_line(&mut answer).unwrap();
+    matches!(answer.trim().to_lowercase().as_str(), "y" | "yes")
+}
+
+/// Open `path` in the user's editor (`$VISUAL`, `$EDITOR` or `vi`) and wait for it to exit.
+fn run_editor(path: &std::path::Path) -> std::io::Result<std::process::ExitStatus> {
+    let editor = env::var("VISUAL")
+        .or_else(|_| env::var("EDITOR"))
+        .unwrap_or_else(|_| "vi".to_owned());
+    let mut editor = editor.split_whitespace();
+    std::process::Command::new(editor.next().unwrap_or("vi"))
+        .args(editor)
+        .arg(path)
+        .status()
+}
+
+/// Let the user edit `contents` in a private temporary file until it parses as a portfolio.
+///
+/// Returns edited contents, or none if the user gave up. The temporary file is removed before
+/// returning, whatever the outcome, as `std::process::exit` would skip removing it.
+fn edit_in_scratch_file(contents: &str) -> std::io::Result<Option<String>> {
+    let scratch_dir = env::var("XDG_RUNTIME_DIR")
+        .map(std::path::PathBuf::from)
+        .unwrap_or_else(|_| env::temp_dir());
+    let scratch = tempfile::Builder::new()
+        .prefix("portfel-")
+        .suffix(".yaml")
+        .tempfile_in(scratch_dir)?;
+    let edited = edit_scratch_file(&scratch, contents);
+    scratch.close()?;
+    edited
+}
+
+fn edit_scratch_file(
+    scratch: &tempfile::NamedTempFile,
+    contents: &str,
+) -> std::io::Result<Option<String>> {
+    std::fs::write(scratch.path(), contents)?;
+    loop {
+        let status = run_editor(scratch.path())?;
+        if !status.success() {
+            return Err(std::io::Error::other(format!(
+                "editor exited with {}",
+                status
+            )));
+        }
+        let edited = std::fs::read_to_string(scratch.path())?;
+        match serde_yaml::from_str::<portfolio::Portfolio>(&edited) {
+            Ok(_) => return Ok(Some(edited)),
+            Err(e) => {
+                println!("Invalid portfolio: {}", e);
+                if !confirm("Edit again?") {
+                    return Ok(None);
+                }
+            }
+        }
+    }
+}
+
+/// Edit portfolio file in the editor.
+///
+/// Encrypted portfolios are decrypted into a private temporary file (in `$XDG_RUNTIME_DIR` when
+/// available, so plaintext stays off persistent storage) and re-encrypted with the same key on save.
+/// The file is only written back once it parses as a valid portfolio.
+async fn edit_portfolio(portfolio_file: &str) {
+    let key = match portfolio::Portfolio::is_encrypted_file(portfolio_file) {
+        Ok(true) => Some(PortfolioKey::get().await),
+        Ok(false) => None,
+        Err(e) => {
+            log::error!("Error reading portfolio file: {}", e);
+            std::process::exit(1);
+        }
+    };
+    let contents = match portfolio::Portfolio::read_contents(
+        portfolio_file,
+        &key.as_ref().map_or(Secret::from(""), |key| key.key.clone()),
+    ) {
+        Ok(contents) => contents,
+        Err(e) => {
+            log::error!("Error reading portfolio file: {}", e);
+            std::process::exit(1);
+        }
+    };
+
+    if let Some(key) = &key {
+        key.remember(true).await;
+    }
+
+    let edited = match edit_in_scratch_file(&contents) {
+        Ok(Some(edited)) => edited,
+        Ok(None) => {
+            println!("Portfolio left unchanged");
+            std::process::exit(1);
+        }
+        Err(e) => {
+            log::error!("Unable to edit portfolio, left unchanged: {}", e);
+            std::process::exit(1);
+        }
+    };
+
+    if edited == contents {
+        println!("No changes");
+        return;
+    }
+    match portfolio::Portfolio::write_contents(
+        portfolio_file,
+        &edited,
+        key.as_ref().map(|key| &key.key),
+    ) {
+        Ok(()) => println!("Saved portfolio file: {}", portfolio_file),
+        Err(e) => {
+            log::error!("Error writing portfolio file: {}", e);
+            std::process::exit(1);
+        }
+    }
+}
+
 #[tokio::main]
 async fn main() {
     let cli = Cli::parse();
@@ -71,35 +392,145 @@ async fn main() {
             portfolio,
         }) => {
             let portfolio_file = get_portfolio_file(portfolio);
-            let key = rpassword::prompt_password("Portfolio key: ").unwrap();
-            match portfolio::Portfolio::from_file(&portfolio_file, &key).await {
+            let key = PortfolioKey::get().await;
+            match portfolio::Portfolio::from_file(&portfolio_file, &key.key).await {
                 Ok(portfolio) => {
+                    key.remember(key_verified(&portfolio_file, Some(&portfolio)))
+                        .await;
                     let amount = Amount::new(
                         Currency::from_str(currency)
-                            .expect(format!("Unknown invest currency: {}!", &currency).as_str()),
+                            .unwrap_or_else(|_| panic!("Unknown invest currency: {}!", &currency)),
                         *amount,
                     );
-                    let change_request = portfolio.balance(amount);
+                    match portfolio.balance(amount) {
+                        Ok(change_request) => println!("{}", change_request.format(&portfolio)),
+                        Err(e) => {
+                            log::error!("Unable to balance portfolio: {}", e);
+                            std::process::exit(1);
+                        }
+                    }
+                }
+                Err(e) => {
+                    log::error!("Error reading portfolio file: {}", e);
+                    std::process::exit(1);
+                }
+            }
+        }
+        Some(Commands::Execute {
+            portfolio,
+            amount,
+            currency,
+            demo: _,
+            live,
+            custom_endpoints,
+        }) => {
+            let portfolio_file = get_portfolio_file(portfolio);
+            let key = PortfolioKey::get().await;
+            let server = match live {
+                true => xtb::XtbServer::Real,
+                false => xtb::XtbServer::Demo,
+            };
+            let portfolio = match portfolio::Portfolio::parse_file(&portfolio_file, &key.key) {
+                Ok(mut portfolio) => {
+                    portfolio.use_xtb_server(server);
+                    portfolio.load(&key.key, fx::Rates::load().await).await
+                }
+                Err(e) => Err(e),
+            };
+            let portfolio = match portfolio {
+                Ok(portfolio) => portfolio,
+                Err(e) => {
+                    log::error!("Error reading portfolio file: {}", e);
+                    std::process::exit(1);
+                }
+            };
+            key.remember(key_verified(&portfolio_file, Some(&portfolio)))
+                .await;
+
+            let amount = Amount::new(
+                Currency::from_str(currency)
+                    .unwrap_or_else(|_| panic!("Unknown invest currency: {}!", &currency)),
+                *amount,
+            );
+            let change_request = match portfolio.balance(amount) {
+                Ok(change_request) => change_request,
+                Err(e) => {
+                    log::error!("Unable to balance portfolio: {}", e);
+                    std::process::exit(1);
+                }
+            };
+            println!("{}", change_request.format(&portfolio));
+
+            let mut purchases = match portfolio.xtb_purchases(&change_request) {
+                Ok(purchases) => purchases,
+                Err(e) => {
+                    log::error!("Unable to prepare orders: {}", e);
+                    std::process::exit(1);
+                }
+            };
+            // Accounts at the other public server are left alone, and so are accounts at other
+            // hosts unless asked for, since they may well be real accounts behind a proxy
+            purchases.retain(|purchases| match purchases.xtb_config.server() {
+                Some(endpoint_server) if endpoint_server == server => true,
+                Some(_) => {
+                    println!(
+                        "Skipping XTB account {}, it is not at the {:?} server.",
+                        purchases.account.account_id(),
+                        server
+                    );
+                    false
+                }
+                None if *custom_endpoints => true,
+                None => {
                     println!(
-                        "{}",
-                        &change_request
-                            .expect("Unable to balance portfolio!")
-                            .format(&portfolio)
+                        "Skipping XTB account {}, its endpoint is not a public XTB server (use --custom-endpoints to trade there).",
+                        purchases.account.account_id()
                     );
+                    false
                 }
+            });
+            if purchases.is_empty() {
+                println!("Nothing to buy at XTB.");
+                return;
+            }
+            let account_orders = match orders::plan(purchases, &key.key, portfolio.rates()).await {
+                Ok(account_orders) => account_orders,
                 Err(e) => {
-                    log::error!("Error reading portfolio file: {}", e);
+                    log::error!("Unable to prepare orders: {}", e);
                     std::process::exit(1);
                 }
+            };
+            for account_orders in &account_orders {
+                println!(
+                    "Market orders at XTB account {} ({} endpoint):",
+                    account_orders.account.account_id(),
+                    account_orders.account.endpoint().unwrap_or("xtb"),
+                );
+                for order in &account_orders.orders {
+                    println!("- {}", order);
+                }
+                for symbol in &account_orders.leveraged {
+                    println!("- [{:8.8}] leveraged, not bought, buy it manually", symbol);
+                }
+            }
+            if !confirm_default_no("\nPlace these orders?") {
+                println!("No orders placed.");
+                return;
+            }
+
+            println!("\nOrders:");
+            for fill in orders::place(&account_orders, &key.key).await {
+                println!("- {}", fill);
             }
         }
         Some(Commands::Show { portfolio }) => {
             let portfolio_file = get_portfolio_file(portfolio);
-            let key = env::var("PORTFOLIO_KEY")
-                .unwrap_or_else(|_| rpassword::prompt_password("Portfolio key: ").unwrap());
+            let key = PortfolioKey::get().await;
 
-            match portfolio::Portfolio::from_file(&portfolio_file, &key).await {
+            match portfolio::Portfolio::from_file(&portfolio_file, &key.key).await {
                 Ok(portfolio) => {
+                    key.remember(key_verified(&portfolio_file, Some(&portfolio)))
+                        .await;
                     println!("{}", &portfolio);
                 }
                 Err(e) => {
@@ -111,24 +542,38 @@ async fn main() {
         Some(Commands::Init {
             portfolio,
             xtb_accont_id: xtb_account_id,
+            demo,
+            encrypt,
         }) => {
             let portfolio_file = get_portfolio_file(portfolio);
-            let (xtb_config, xtb_account) = if let Some(xtb_account_id) = xtb_account_id {
-                let key = rpassword::prompt_password("Portfolio key: ").unwrap();
-                let xtb_password = rpassword::prompt_password("XTB password: ").unwrap();
-                let xtb_config = Some(xtb::XtbConfig::new("xapi.xtb.com".to_owned(), 5112));
+            let key = if xtb_account_id.is_some() || *encrypt {
+                Some(PortfolioKey::get().await.key)
+            } else {
+                None
+            };
+            let (endpoint, xtb_account) = if let Some(xtb_account_id) = xtb_account_id {
+                let key = key.clone().unwrap();
+                let xtb_password = prompt_secret("XTB password: ");
+                let (endpoint, server) = match demo {
+                    true => ("demo", xtb::XtbServer::Demo),
+                    false => ("real", xtb::XtbServer::Real),
+                };
                 let xtb_account = Some(
                     xtb::XtbAccount::new(xtb_account_id.clone(), None, Some(xtb_password))
-                        .encrypt(key.as_str())
+                        .at_endpoint(endpoint.to_string())
+                        .encrypt(&key)
                         .expect("Failed to encrypt password!"),
                 );
-                (xtb_config, xtb_account)
+                (
+                    Some((endpoint.to_string(), xtb::XtbConfig::for_server(server))),
+                    xtb_account,
+                )
             } else {
                 (None, None)
             };
 
-            match portfolio::Portfolio::example(xtb_config, xtb_account)
-                .to_file(&portfolio_file)
+            match portfolio::Portfolio::example(endpoint, xtb_account)
+                .to_file(&portfolio_file, key.as_ref().filter(|_| *encrypt))
                 .await
             {
                 Ok(filename) => {
@@ -141,11 +586,455 @@ async fn main() {
             }
         }
         Some(Commands::EncryptPassword) => {
-            let password = rpassword::prompt_password("Password to encrypt: ").unwrap();
-            let key = rpassword::prompt_password("Portfolio key: ").unwrap();
-            let encrypted = crypt::encrypt_text(&password, &key).unwrap();
+            let password = prompt_secret("Password to encrypt: ");
+            let key = PortfolioKey::get().await;
+            let encrypted = crypt::encrypt_text(password.expose(), key.key.expose()).unwrap();
             println!("Encrypted password: {}", encrypted);
         }
+        Some(Commands::EncryptFile { portfolio }) => {
+            let portfolio_file = get_portfolio_file(portfolio);
+            let contents = match std::fs::read_to_string(&portfolio_file) {
+                Ok(contents) if crypt::is_encrypted_container(&contents) => {
+                    log::error!("Portfolio file is already encrypted: {}", portfolio_file);
+                    std::process::exit(1);
+                }
+                Ok(contents) => contents,
+                Err(e) => {
+                    log::error!("Error reading portfolio file: {}", e);
+                    std::process::exit(1);
+                }
+            };
+            if let Err(e) = serde_yaml::from_str::<portfolio::Portfolio>(&contents) {
+                log::error!("Error reading portfolio file: {}", e);
+                std::process::exit(1);
+            }
+            let key = PortfolioKey::get().await;
+            if key.prompted && key.key != prompt_secret("Repeat portfolio key: ") {
+                log::error!("Portfolio keys do not match!");
+                std::process::exit(1);
+            }
+            match portfolio::Portfolio::write_contents(&portfolio_file, &contents, Some(&key.key)) {
+                Ok(()) => {
+                    key.remember(true).await;
+                    println!("Encrypted portfolio file: {}", portfolio_file)
+                }
+                Err(e) => {
+                    log::error!("Error writing portfolio file: {}", e);
+                    std::process::exit(1);
+                }
+            }
+        }
+        Some(Commands::DecryptFile { portfolio }) => {
+            let portfolio_file = get_portfolio_file(portfolio);
+            if !matches!(
+                portfolio::Portfolio::is_encrypted_file(&portfolio_file),
+                Ok(true)
+            ) {
+                log::error!("Portfolio file is not encrypted: {}", portfolio_file);
+                std::process::exit(1);
+            }
+            let key = PortfolioKey::get().await;
+            let contents = match portfolio::Portfolio::read_contents(&portfolio_file, &key.key) {
+                Ok(contents) => contents,
+                Err(e) => {
+                    log::error!("Error decrypting portfolio file: {}", e);
+                    std::process::exit(1);
+                }
+            };
+            match portfolio::Portfolio::write_contents(&portfolio_file, &contents, None) {
+                Ok(()) => println!("Decrypted portfolio file: {}", portfolio_file),
+                Err(e) => {
+                    log::error!("Error writing portfolio file: {}", e);
+                    std::process::exit(1);
+                }
+            }
+        }
+        Some(Commands::Edit { portfolio }) => {
+            let portfolio_file = get_portfolio_file(portfolio);
+            edit_portfolio(&portfolio_file).await;
+        }
+        Some(Commands::ChangeKey { portfolio }) => {
+            let portfolio_file = get_portfolio_file(portfolio);
+            let encrypted = match portfolio::Portfolio::is_encrypted_file(&portfolio_file) {
+                Ok(encrypted) => encrypted,
+                Err(e) => {
+                    log::error!("Error reading portfolio file: {}", e);
+                    std::process::exit(1);
+                }
+            };
+            let old_key = PortfolioKey::get().await;
+            let mut portfolio: portfolio::Portfolio =
+                match portfolio::Portfolio::read_contents(&portfolio_file, &old_key.key)
+                    .and_then(|contents| Ok(serde_yaml::from_str(&contents)?))
+                {
+                    Ok(portfolio) => portfolio,
+                    Err(e) => {
+                        log::error!("Error reading portfolio file: {}", e);
+                        std::process::exit(1);
+                    }
+                };
+            // History is encrypted with the portfolio key too, so it is re-encrypted along
+            let history_file = history::History::path(&portfolio_file);
+            let history = match std::path::Path::new(&history_file).exists() {
+                true => match history::History::from_file(&history_file, &old_key.key) {
+                    Ok(history) => Some(history),
+                    Err(e) => {
+                        log::error!("Error reading transaction history: {}", e);
+                        std::process::exit(1);
+                    }
+                },
+                false => None,
+            };
+
+            let new_key = prompt_secret("New portfolio key: ");
+            if new_key != prompt_secret("Repeat new portfolio key: ") {
+                log::error!("Portfolio keys do not match!");
+                std::process::exit(1);
+            }
+            if let Err(e) = portfolio.change_key(&old_key.key, &new_key) {
+                log::error!("Unable to re-encrypt portfolio: {}", e);
+                std::process::exit(1);
+            }
+            let written = match &history {
+                Some(history) => {
+                    portfolio
+                        .to_file_with_history(
+                            &portfolio_file,
+                            history,
+                            encrypted.then_some(&new_key),
+                        )
+                        .await
+                }
+                None => {
+                    portfolio
+                        .to_file(&portfolio_file, encrypted.then_some(&new_key))
+                        .await
+                }
+            };
+            match written {
+                Ok(filename) => println!("Changed key of portfolio file: {}", filename),
+                Err(e) => {
+                    log::error!("Error writing portfolio file: {}", e);
+                    std::process::exit(1);
+                }
+            }
+
+            // Key agent would otherwise keep serving the old key
+            if !old_key.prompted {
+                match agent::add_key(&new_key).await {
+                    Ok(()) | Err(error::AgentError::NotRunning) => {}
+                    Err(e) => log::warn!("Unable to add key to key agent: {}", e),
+                }
+            }
+        }
+        Some(Commands::Check { portfolio }) => {
+            let portfolio_file = get_portfolio_file(portfolio);
+            let key = PortfolioKey::get().await;
+
+            let portfolio = match portfolio::Portfolio::from_file(&portfolio_file, &key.key).await {
+                Ok(portfolio) => portfolio,
+                Err(e) => {
+                    log::error!("Error reading portfolio file: {}", e);
+                    std::process::exit(1);
+                }
+            };
+            key.remember(key_verified(&portfolio_file, Some(&portfolio)))
+                .await;
+            let allocation = portfolio.allocation();
+            for allocation in &allocation {
+                println!(
+                    "- {} [{:4.2} ({:4.2})]{}",
+                    allocation.position,
+                    allocation.share,
+                    allocation.target,
+                    if allocation.drifted { " drifted" } else { "" }
+                );
+            }
+            let drifted = allocation
+                .iter()
+                .filter(|allocation| allocation.drifted)
+                .count();
+            if drifted > 0 {
+                println!("{} position(s) drifted beyond threshold", drifted);
+                std::process::exit(1);
+            }
+            println!("All positions within drift threshold");
+        }
+        Some(Commands::Glide { portfolio }) => {
+            let portfolio_file = get_portfolio_file(portfolio);
+            let key = PortfolioKey::get().await;
+
+            let portfolio = match portfolio::Portfolio::parse_file(&portfolio_file, &key.key) {
+                Ok(portfolio) => portfolio,
+                Err(e) => {
+                    log::error!("Error reading portfolio file: {}", e);
+                    std::process::exit(1);
+                }
+            };
+            key.remember(key_verified(&portfolio_file, None)).await;
+            match portfolio.glide_path() {
+                Some(glide_path) => {
+                    print!("{}", glide_path.format(chrono::Local::now().date_naive()))
+                }
+                None => println!("No glide path in portfolio config"),
+            }
+        }
+        Some(Commands::Watch { portfolio }) => {
+            let portfolio_file = get_portfolio_file(portfolio);
+            let key = PortfolioKey::get().await;
+
+            let portfolio = match portfolio::Portfolio::from_file(&portfolio_file, &key.key).await {
+                Ok(portfolio) => portfolio,
+                Err(e) => {
+                    log::error!("Error reading portfolio file: {}", e);
+                    std::process::exit(1);
+                }
+            };
+            key.remember(key_verified(&portfolio_file, Some(&portfolio)))
+                .await;
+            if let Err(e) = watch::run(portfolio, &key.key).await {
+                log::error!("Error streaming prices: {}", e);
+                std::process::exit(1);
+            }
+        }
+        Some(Commands::ImportHistory { portfolio, since }) => {
+            let portfolio_file = get_portfolio_file(portfolio);
+            let history_file = history::History::path(&portfolio_file);
+            let key = PortfolioKey::get().await;
+            let (encrypted, portfolio, mut history) = match portfolio::Portfolio::is_encrypted_file(
+                &portfolio_file,
+            )
+            .and_then(|encrypted| {
+                Ok((
+                    encrypted,
+                    portfolio::Portfolio::parse_file(&portfolio_file, &key.key)?,
+                    history::History::from_file(&history_file, &key.key)?,
+                ))
+            }) {
+                Ok(result) => result,
+                Err(e) => {
+                    log::error!("Error reading portfolio file: {}", e);
+                    std::process::exit(1);
+                }
+            };
+            let since = since.map(|since| since.and_time(chrono::NaiveTime::MIN).and_utc());
+            let added = match portfolio
+                .import_history(&key.key, &mut history, since)
+                .await
+            {
+                Ok(added) => added,
+                Err(e) => {
+                    log::error!("Error importing transactions: {}", e);
+                    std::process::exit(1);
+                }
+            };
+            key.remember(key_verified(&portfolio_file, Some(&portfolio)))
+                .await;
+            // History of an encrypted portfolio is as sensitive as the portfolio itself
+            if let Err(e) = history.to_file(&history_file, encrypted.then_some(&key.key)) {
+                log::error!("Error writing history file: {}", e);
+                std::process::exit(1);
+            }
+            println!(
+                "Imported {} new transactions into history file: {}",
+                added, history_file
+            );
+            print!("{}", history);
+        }
+        Some(Commands::Adopt { portfolio, symbol }) => {
+            let portfolio_file = get_portfolio_file(portfolio);
+            let key = PortfolioKey::get().await;
+            let loaded = match portfolio::Portfolio::from_file(&portfolio_file, &key.key).await {
+                Ok(loaded) => loaded,
+                Err(e) => {
+                    log::error!("Error reading portfolio file: {}", e);
+                    std::process::exit(1);
+                }
+            };
+            key.remember(key_verified(&portfolio_file, Some(&loaded)))
+                .await;
+            // Loaded portfolio holds automatic positions and amounts read from brokers, so the
+            // file is parsed again to save only what the user wrote
+            let (encrypted, mut saved) = match portfolio::Portfolio::is_encrypted_file(
+                &portfolio_file,
+            )
+            .and_then(|encrypted| {
+                Ok((
+                    encrypted,
+                    portfolio::Portfolio::parse_file(&portfolio_file, &key.key)?,
+                ))
+            }) {
+                Ok(result) => result,
+                Err(e) => {
+                    log::error!("Error reading portfolio file: {}", e);
+                    std::process::exit(1);
+                }
+            };
+
+            let adopted = saved.adopt(&loaded, symbol.as_deref());
+            if adopted.is_empty() {
+                println!("No unallocated holdings to adopt.");
+                return;
+            }
+            match saved
+                .to_file(&portfolio_file, encrypted.then_some(&key.key))
+                .await
+            {
+                Ok(filename) => {
+                    println!(
+                        "Adopted {} into portfolio file: {}",
+                        adopted.join(", "),
+                        filename
+                    );
+                    println!("Targets of adopted positions are 0, edit the file to set them.");
+                }
+                Err(e) => {
+                    log::error!("Error writing portfolio file: {}", e);
+                    std::process::exit(1);
+                }
+            }
+        }
+        Some(Commands::ImportCsv {
+            portfolio,
+            format,
+            dry_run,
+            file,
+        }) => {
+            let portfolio_file = get_portfolio_file(portfolio);
+            let history_file = history::History::path(&portfolio_file);
+            let key = PortfolioKey::get().await;
+            let (encrypted, mut saved) = match portfolio::Portfolio::is_encrypted_file(
+                &portfolio_file,
+            )
+            .and_then(|encrypted| {
+                Ok((
+                    encrypted,
+                    portfolio::Portfolio::parse_file(&portfolio_file, &key.key)?,
+                ))
+            }) {
+                Ok(result) => result,
+                Err(e) => {
+                    log::error!("Error reading portfolio file: {}", e);
+                    std::process::exit(1);
+                }
+            };
+            key.remember(key_verified(&portfolio_file, None)).await;
+            let Some(csv_format) = saved.csv_format(format).cloned() else {
+                log::error!("{}", error::ImportError::UnknownFormat(format.clone()));
+                std::process::exit(1);
+            };
+            // Exports are not always UTF-8, undecodable characters are only a problem in
+            // columns that are read
+            let contents = match std::fs::read(file) {
+                Ok(contents) => String::from_utf8_lossy(&contents).into_owned(),
+                Err(e) => {
+                    log::error!("Error reading CSV file: {}", e);
+                    std::process::exit(1);
+                }
+            };
+
+            match csv_format.kind {
+                importer::CsvKind::Holdings => {
+                    let group = csv_format.group.clone().unwrap_or_default();
+                    let (changes, unmatched) = match csv_format
+                        .read_holdings(&contents)
+                        .and_then(|holdings| saved.import_snapshot(&group, &holdings))
+                    {
+                        Ok(result) => result,
+                        Err(e) => {
+                            log::error!("Error importing CSV file: {}", e);
+                            std::process::exit(1);
+                        }
+                    };
+                    println!("Amounts of positions in group {}:", group);
+                    for change in &changes {
+                        let old = change.old.as_ref().map_or("none".to_string(), |old| {
+                            format!("{:.2} {}", old.value, old.currency)
+                        });
+                        println!(
+                            "- {}: {} -> {:.2} {}",
+                            change.position, old, change.new.value, change.new.currency
+                        );
+                    }
+                    if !unmatched.is_empty() {
+                        println!("Not held as any position: {}", unmatched.join(", "));
+                    }
+                    if *dry_run || changes.is_empty() {
+                        println!("Portfolio file not changed.");
+                        return;
+                    }
+                    match saved
+                        .to_file(&portfolio_file, encrypted.then_some(&key.key))
+                        .await
+                    {
+                        Ok(filename) => println!(
+                            "Updated {} positions in portfolio file: {}",
+                            changes.len(),
+                            filename
+                        ),
+                        Err(e) => {
+                            log::error!("Error writing portfolio file: {}", e);
+                            std::process::exit(1);
+                        }
+                    }
+                }
+                importer::CsvKind::Transactions => {
+                    let session_id = format!("csv:{}", format);
+                    let (transactions, mut history) = match csv_format
+                        .read_transactions(&contents)
+                        .map_err(|e| e.to_string())
+                        .and_then(|transactions| {
+                            history::History::from_file(&history_file, &key.key)
+                                .map(|history| (transactions, history))
+                                .map_err(|e| e.to_string())
+                        }) {
+                        Ok(result) => result,
+                        Err(e) => {
+                            log::error!("Error importing CSV file: {}", e);
+                            std::process::exit(1);
+                        }
+                    };
+                    let new: Vec<_> = transactions
+                        .into_iter()
+                        .filter(|transaction| !history.contains(&session_id, transaction))
+                        .collect();
+                    println!("New transactions of {}:", session_id);
+                    for transaction in &new {
+                        println!("- {}", transaction);
+                    }
+                    if *dry_run || new.is_empty() {
+                        println!("History file not changed.");
+                        return;
+                    }
+                    let added = history.merge(&session_id, new);
+                    if let Err(e) = history.to_file(&history_file, encrypted.then_some(&key.key)) {
+                        log::error!("Error writing history file: {}", e);
+                        std::process::exit(1);
+                    }
+                    println!(
+                        "Imported {} new transactions into history file: {}",
+                        added, history_file
+                    );
+                }
+            }
+        }
+        Some(Commands::Agent { command }) => {
+            let result = match command {
+                AgentCommands::Start { timeout } => agent::start(Duration::from_secs(*timeout))
+                    .await
+                    .map(|path| println!("Key agent listening on {}", path.display())),
+                AgentCommands::Serve { timeout } => {
+                    agent::serve(&agent::socket_path(), Duration::from_secs(*timeout)).await
+                }
+                AgentCommands::Add => agent::add_key(&prompt_secret("Portfolio key: ")).await,
+                AgentCommands::Clear => agent::clear().await,
+                AgentCommands::Stop => agent::stop().await,
+            };
+            if let Err(e) = result {
+                log::error!("Key agent error: {}", e);
+                std::process::exit(1);
+            }
+        }
         None => {
             log::warn!("No command specified!");
             std::process::exit(1);
@@ -0,0 +1,469 @@
+//! Market orders at XTB for purchases suggested by `Portfolio::balance`.
+//!
+//! Orders are sized in whole units at the current ask price, so a little of every purchase
+//! is usually left uninvested. Leveraged instruments (CFDs) are left out, since the margin
+//! they take is not what their price suggests. Each order is sent once and its status polled
+//! until XTB accepts or rejects it. If the connection fails once an order is sent, the order
+//! may or may not have been placed, so its status is reported as unknown rather than failed.
+
+use std::str::FromStr;
+
+use crate::{
+    amount::{Amount, Currency},
+    broker::PositionSource,
+    error,
+    fx::Rates,
+    portfolio::AccountPurchases,
+    secret::Secret,
+    xtb::{command::trade_transaction_status, XtbAccount, XtbConfig},
+};
+
+/// Market buy order of whole units of a single symbol.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Order {
+    pub symbol: String,
+    /// Amount requested to invest, in symbol currency
+    pub amount: Amount,
+    /// Ask price of one unit when the order was planned
+    pub price: Amount,
+    /// Number of whole units to buy
+    pub volume: f64,
+}
+
+impl Order {
+    pub fn value(&self) -> Amount {
+        Amount::new(self.price.currency, self.price.value * self.volume)
+    }
+}
+
+impl std::fmt::Display for Order {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let value = self.value();
+        write!(
+            f,
+            "[{:8.8}] {:6} x {:9.2} {} = {:9.2} {} (of {:9.2} {})",
+            self.symbol,
+            self.volume,
+            self.price.value,
+            self.price.currency,
+            value.value,
+            value.currency,
+            self.amount.value,
+            self.amount.currency,
+        )
+    }
+}
+
+/// Orders to be placed at a single XTB account.
+#[derive(Debug)]
+pub struct AccountOrders {
+    /// Endpoint the account is at
+    pub xtb_config: XtbConfig,
+    pub account: XtbAccount,
+    pub orders: Vec<Order>,
+    /// Symbols of leveraged instruments left out, to be bought manually
+    pub leveraged: Vec<String>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Outcome {
+    Accepted,
+    Rejected(String),
+    /// XTB did not decide about the order in time, check it in the XTB app
+    Pending,
+    Failed(String),
+    /// Order may have been placed, but the connection failed before XTB confirmed it
+    Unknown(String),
+    /// Amount is lower than price of a single unit
+    Skipped,
+}
+
+/// Result of placing an order.
+#[derive(Debug)]
+pub struct Fill {
+    pub order: Order,
+    pub order_number: Option<i64>,
+    pub outcome: Outcome,
+}
+
+impl std::fmt::Display for Fill {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} -> ", self.order)?;
+        match &self.outcome {
+            Outcome::Accepted => write!(f, "accepted")?,
+            Outcome::Rejected(message) => write!(f, "rejected: {}", message)?,
+            Outcome::Pending => write!(f, "still pending")?,
+            Outcome::Failed(message) => write!(f, "failed: {}", message)?,
+            Outcome::Unknown(message) => {
+                write!(f, "status unknown, check the platform: {}", message)?
+            }
+            Outcome::Skipped => write!(f, "skipped, amount below price of one unit")?,
+        }
+        if let Some(order_number) = self.order_number {
+            write!(f, " (order {})", order_number)?;
+        }
+        Ok(())
+    }
+}
+
+async fn open(
+    xtb_config: &XtbConfig,
+    account: &XtbAccount,
+    encryption_key: &Secret,
+) -> Result<XtbConfig, error::SourceError> {
+    let mut xtb = xtb_config.for_account(account.clone());
+    PositionSource::connect(&mut xtb).await?;
+    PositionSource::authenticate(&mut xtb, encryption_key).await?;
+    Ok(xtb)
+}
+
+/// Size orders for `account_purchases` in whole units at current ask prices.
+pub async fn plan(
+    account_purchases: Vec<AccountPurchases>,
+    encryption_key: &Secret,
+    rates: &Rates,
+) -> Result<Vec<AccountOrders>, error::SourceError> {
+    let mut account_orders = vec![];
+    for AccountPurchases {
+        xtb_config,
+        account,
+        purchases,
+    } in account_purchases
+    {
+        let mut xtb = open(&xtb_config, &account, encryption_key).await?;
+        let mut orders = vec![];
+        let mut leveraged = vec![];
+        for (symbol, amount) in purchases {
+            let symbol_record = xtb.get_symbol(&symbol).await?;
+            if symbol_record.leveraged() {
+                leveraged.push(symbol);
+                continue;
+            }
+            let currency =
+                Currency::from_str(&symbol_record.currency_profit_symbol).map_err(|_| {
+                    error::XtbError::UnknownCurrency(symbol_record.currency_profit_symbol.clone())
+                })?;
+            let amount = amount.convert(currency, rates);
+            let volume = match symbol_record.ask > 0.0 {
+                true => (amount.value / symbol_record.ask).floor().max(0.0),
+                false => 0.0,
+            };
+            orders.push(Order {
+                symbol,
+                amount,
+                price: Amount::new(currency, symbol_record.ask),
+                volume,
+            });
+        }
+        PositionSource::disconnect(&mut xtb).await?;
+        account_orders.push(AccountOrders {
+            xtb_config,
+            account,
+            orders,
+            leveraged,
+        });
+    }
+    Ok(account_orders)
+}
+
+async fn place_order(xtb: &XtbConfig, order: &Order) -> (Option<i64>, Outcome) {
+    let order_number = match xtb
+        .buy_market(&order.symbol, order.volume, order.price.value)
+        .await
+    {
+        Ok(order_number) => order_number,
+        Err(
+            e @ (error::XtbError::ResponseTimeout(_)
+            | error::XtbError::ConnectionClosed
+            | error::XtbError::InvalidFrame(_)
+            | error::XtbError::IoError(_)),
+        ) => return (None, Outcome::Unknown(e.to_string())),
+        Err(e) => return (None, Outcome::Failed(e.to_string())),
+    };
+    let outcome = match xtb.wait_for_order(order_number).await {
+        Ok(status) => match status.request_status {
+            trade_transaction_status::ACCEPTED => Outcome::Accepted,
+            trade_transaction_status::PENDING => Outcome::Pending,
+            trade_transaction_status::REJECTED => {
+                Outcome::Rejected(status.message.unwrap_or_default())
+            }
+            _ => Outcome::Failed(status.message.unwrap_or_default()),
+        },
+        // Order was placed, only its status could not be read
+        Err(e) => Outcome::Unknown(e.to_string()),
+    };
+    (Some(order_number), outcome)
+}
+
+/// Place all orders, waiting for XTB to accept or reject each of them.
+///
+/// Failures are reported per order rather than returned, so that orders already placed are
+/// always reported.
+pub async fn place(account_orders: &[AccountOrders], encryption_key: &Secret) -> Vec<Fill> {
+    let mut fills = vec![];
+    for AccountOrders {
+        xtb_config,
+        account,
+        orders,
+        ..
+    } in account_orders
+    {
+        let mut xtb = match open(xtb_config, account, encryption_key).await {
+            Ok(xtb) => xtb,
+            Err(e) => {
+                fills.extend(orders.iter().map(|order| Fill {
+                    order: order.clone(),
+                    order_number: None,
+                    outcome: Outcome::Failed(e.to_string()),
+                }));
+                continue;
+            }
+        };
+        for order in orders {
+            let (order_number, outcome) = match order.volume > 0.0 {
+                true => place_order(&xtb, order).await,
+                false => (None, Outcome::Skipped),
+            };
+            fills.push(Fill {
+                order: order.clone(),
+                order_number,
+                outcome,
+            });
+        }
+        if let Err(e) = PositionSource::disconnect(&mut xtb).await {
+            log::warn!("Unable to log out of XTB: {}", e);
+        }
+    }
+    fills
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::PortfolioFile;
+    use crate::xtb::mock::MockXtb;
+
+    fn mock_rates() -> Rates {
+        Rates {
+            rates: vec![(Currency::USD, 1.0), (Currency::EUR, 1.2)]
+                .into_iter()
+                .collect(),
+        }
+    }
+
+    #[tokio::test]
+    async fn execute_change_request() {
+        let server = MockXtb::new()
+            .account("111", "password", "USD")
+            .position("111", "SPXS.UK", 1.0)
+            .stock("SPXS.UK", 100.0, "USD")
+            .stock("IMAE.NL", 30.0, "EUR")
+            .stock("EIMI.UK", 10.0, "USD")
+            .reject("EIMI.UK", "Market closed")
+            .start()
+            .await;
+        let file = PortfolioFile::new();
+        let key = &file.key;
+        file.write(&format!(
+            r#"
+config:
+  base_currency: USD
+  xtb: {endpoint}
+groups:
+- id: xtb
+  currency: USD
+  source:
+    type: xtb
+    account_id: '111'
+    encrypted_password: {password}
+- id: bank
+  currency: USD
+positions:
+- name: S&P 500
+  group: xtb
+  ticker: SPXS.UK
+  target: 0.5
+- name: Europe
+  group: xtb
+  ticker: IMAE.NL
+  target: 0.2
+- name: Emerging markets
+  group: xtb
+  ticker: EIMI.UK
+  target: 0.1
+- name: Cash
+  group: bank
+  ticker: CASH
+  amount:
+    currency: USD
+    value: 100.0
+  target: 0.2
+"#,
+            endpoint = server.endpoint(),
+            password = file.encrypt("password"),
+        ));
+
+        let portfolio = file.read(mock_rates()).await.unwrap();
+        // Total after investment is 1000 USD: buy 400 USD of SPXS.UK, 200 USD of IMAE.NL,
+        // 100 USD of EIMI.UK and 100 USD of cash at the bank
+        let change_request = portfolio
+            .balance(Amount::new(Currency::USD, 800.0))
+            .unwrap();
+        let purchases = portfolio.xtb_purchases(&change_request).unwrap();
+        assert_eq!(purchases.len(), 1);
+        assert_eq!(purchases[0].purchases.len(), 3);
+
+        let account_orders = plan(purchases, key, portfolio.rates()).await.unwrap();
+        let volumes: Vec<_> = account_orders[0]
+            .orders
+            .iter()
+            .map(|order| (order.symbol.as_str(), order.volume))
+            .collect();
+        // 200 USD is 166.67 EUR, enough for 5 units at 30 EUR
+        assert_eq!(
+            volumes,
+            vec![("SPXS.UK", 4.0), ("IMAE.NL", 5.0), ("EIMI.UK", 10.0)]
+        );
+
+        let fills = place(&account_orders, key).await;
+        let outcomes: Vec<_> = fills.iter().map(|fill| fill.outcome.clone()).collect();
+        assert_eq!(
+            outcomes,
+            vec![
+                Outcome::Accepted,
+                Outcome::Accepted,
+                Outcome::Rejected("Market closed".to_string())
+            ]
+        );
+        assert!(fills.iter().all(|fill| fill.order_number.is_some()));
+
+        let orders = server.orders();
+        assert_eq!(orders.len(), 3);
+        assert_eq!(orders[0]["symbol"], "SPXS.UK");
+        assert_eq!(orders[0]["cmd"], 0);
+        assert_eq!(orders[0]["volume"], 4.0);
+    }
+
+    #[tokio::test]
+    async fn order_failures_reported() {
+        let server = MockXtb::new()
+            .account("111", "password", "USD")
+            .stock("SPXS.UK", 100.0, "USD")
+            .fail("tradeTransaction", "BE101", "Market closed")
+            .start()
+            .await;
+        let key = Secret::from("key");
+        let account = XtbAccount::new(
+            "111".to_string(),
+            Some(crate::crypt::encrypt_text("password", key.expose()).unwrap()),
+            None,
+        );
+        let order = |symbol: &str, volume| Order {
+            symbol: symbol.to_string(),
+            amount: Amount::new(Currency::USD, 100.0),
+            price: Amount::new(Currency::USD, 100.0),
+            volume,
+        };
+        let account_orders = vec![AccountOrders {
+            xtb_config: server.config(),
+            account,
+            orders: vec![order("SPXS.UK", 1.0), order("SPXS.UK", 0.0)],
+            leveraged: vec![],
+        }];
+
+        let fills = place(&account_orders, &key).await;
+        assert!(matches!(
+            &fills[0].outcome,
+            Outcome::Failed(message) if message.contains("BE101")
+        ));
+        assert_eq!(fills[1].outcome, Outcome::Skipped);
+    }
+
+    #[tokio::test]
+    async fn leveraged_symbols_left_out() {
+        let server = MockXtb::new()
+            .account("111", "password", "USD")
+            .stock("SPXS.UK", 100.0, "USD")
+            .symbol(serde_json::json!({
+                "symbol": "US500",
+                "bid": 5000.0,
+                "ask": 5001.0,
+                "currency": "USD",
+                "currencyProfit": "USD",
+                "categoryName": "IND",
+                "contractSize": 50,
+                "leverage": 5.0,
+            }))
+            .start()
+            .await;
+        let key = Secret::from("key");
+        let account = XtbAccount::new(
+            "111".to_string(),
+            Some(crate::crypt::encrypt_text("password", key.expose()).unwrap()),
+            None,
+        );
+        let purchases = vec![AccountPurchases {
+            xtb_config: server.config(),
+            account,
+            purchases: vec![
+                ("US500".to_string(), Amount::new(Currency::USD, 10000.0)),
+                ("SPXS.UK".to_string(), Amount::new(Currency::USD, 300.0)),
+            ],
+        }];
+
+        // Sized by ask price alone, 10000 USD would buy a lot of 50 units worth 250050 USD
+        let account_orders = plan(purchases, &key, &mock_rates()).await.unwrap();
+        let symbols: Vec<_> = account_orders[0]
+            .orders
+            .iter()
+            .map(|order| order.symbol.as_str())
+            .collect();
+        assert_eq!(symbols, vec!["SPXS.UK"]);
+        assert_eq!(account_orders[0].leveraged, vec!["US500"]);
+    }
+
+    #[tokio::test]
+    async fn order_status_unknown_on_lost_connection() {
+        let server = MockXtb::new()
+            .account("111", "password", "USD")
+            .stock("SPXS.UK", 100.0, "USD")
+            .start()
+            .await;
+        server.drop_connection("tradeTransaction", 1);
+        let key = Secret::from("key");
+        let account = XtbAccount::new(
+            "111".to_string(),
+            Some(crate::crypt::encrypt_text("password", key.expose()).unwrap()),
+            None,
+        );
+        let order = Order {
+            symbol: "SPXS.UK".to_string(),
+            amount: Amount::new(Currency::USD, 100.0),
+            price: Amount::new(Currency::USD, 100.0),
+            volume: 1.0,
+        };
+        let account_orders = vec![AccountOrders {
+            xtb_config: server.config(),
+            account,
+            orders: vec![order.clone(), order],
+            leveraged: vec![],
+        }];
+
+        let fills = place(&account_orders, &key).await;
+        assert!(matches!(&fills[0].outcome, Outcome::Unknown(_)));
+        assert!(fills[0]
+            .to_string()
+            .contains("status unknown, check the platform"));
+        // Next order is sent on a new connection, but the lost one is never sent again
+        assert_eq!(fills[1].outcome, Outcome::Accepted);
+        assert_eq!(
+            server
+                .received_commands()
+                .iter()
+                .filter(|command| *command == "tradeTransaction")
+                .count(),
+            2
+        );
+        assert_eq!(server.orders().len(), 1);
+    }
+}
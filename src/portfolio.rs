@@ -1,22 +1,29 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::path::Path;
 
 use crate::{
     amount::Amount,
     amount::Currency,
+    bonds::{Bond, BondValue, CpiTable},
+    broker::{Holding, SourceConfig},
+    crypt,
+    deposits::Deposit,
     error,
     fx::Rates,
-    xtb::{self, XtbAccount, XtbConfig},
+    glide::GlidePath,
+    history::History,
+    importer::CsvFormat,
+    loans::Loan,
+    quotes::{QuoteSource, Quotes},
+    secret::Secret,
+    xtb::{self, XtbAccount, XtbConfig, XtbServer},
 };
+use chrono::{DateTime, Local, NaiveDate, Utc};
+use futures::future::try_join_all;
 use good_lp::{constraint, default_solver, Expression, Solution, SolverModel};
 use serde::{Deserialize, Serialize};
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
-struct InvestmentGroup {
-    id: String,
-    currency: Currency,
-}
-
-#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Default)]
 struct Position {
     name: String,
     group: String,
@@ -29,7 +36,150 @@ struct Position {
     ///
     /// Any subsequent usages of `amount` should expect it to be `Some` and panic otherwise.
     amount: Option<Amount>,
-    target: f64,
+    target: Target,
+    /// International Securities Identification Number, matched against holdings reporting one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    isin: Option<String>,
+    /// Broker symbols held as this position, per group id, in addition to `ticker` held in
+    /// position's own group. The same instrument held in several groups (e.g. at two brokers or
+    /// listed on two exchanges) is counted as this single position.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    symbols: BTreeMap<String, Vec<String>>,
+    /// Set if the position was created automatically from data reported by a position source.
+    /// Such positions are never saved to file.
+    #[serde(skip)]
+    automatic: Option<Automatic>,
+    /// Largest difference between share and target not highlighted by `watch`, overriding
+    /// `drift_threshold` of the portfolio config
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    drift_threshold: Option<f64>,
+    /// Units held, priced by `quote` instead of giving `amount`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    quantity: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    quote: Option<QuoteSource>,
+    /// Treasury bonds held as this position, valued instead of giving `amount`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    bonds: Vec<Bond>,
+    /// Deposit held as this position, accrued to today instead of giving `amount`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    deposit: Option<Deposit>,
+    #[serde(default, skip_serializing_if = "PositionKind::is_investable")]
+    kind: PositionKind,
+    /// Loan repaid as this liability, valued by its amortization schedule instead of `amount`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    loan: Option<Loan>,
+    /// Holdings `amount` was read from, empty if given in file
+    #[serde(skip)]
+    held: Vec<Held>,
+    /// Date of the quote `amount` was priced by
+    #[serde(skip)]
+    price_date: Option<NaiveDate>,
+    /// Value of `bonds` if redeemed today
+    #[serde(skip)]
+    redemption: Option<BondValue>,
+}
+
+/// Holding valued as (a part of) a position, kept to revalue the position on new prices.
+#[derive(Debug, Clone, PartialEq)]
+struct Held {
+    session_id: String,
+    symbol: String,
+    quantity: f64,
+    bid_price: Amount,
+    /// Value of the holding when loaded, at `loaded_bid`
+    value: Amount,
+    loaded_bid: f64,
+}
+
+/// Amount of a position set by `Portfolio::import_snapshot`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AmountChange {
+    /// Position, as displayed by `show`
+    pub position: String,
+    pub old: Option<Amount>,
+    pub new: Amount,
+}
+
+/// Share of a position in the portfolio, as listed by `Portfolio::allocation`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Allocation {
+    /// Position, as displayed by `show`
+    pub position: String,
+    pub share: f64,
+    pub target: f64,
+    /// Share is further from target than drift threshold
+    pub drifted: bool,
+}
+
+/// Target of a position: a share of the portfolio, or a fixed amount.
+///
+/// Fixed amounts are filled by `balance` first, in order of priority, and shares are of what is
+/// left, e.g. an emergency fund of 20 000 PLN and 0.6 of the rest in stocks.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[serde(untagged)]
+enum Target {
+    Share(f64),
+    Fixed(FixedTarget),
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+struct FixedTarget {
+    currency: Currency,
+    value: f64,
+    /// Rank among fixed targets, lowest filled first; unranked ones are filled last
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    priority: Option<u32>,
+}
+
+impl Default for Target {
+    fn default() -> Self {
+        Target::Share(0.0)
+    }
+}
+
+impl Target {
+    fn fixed(&self) -> Option<Amount> {
+        match self {
+            Target::Share(_) => None,
+            Target::Fixed(fixed) => Some(Amount::new(fixed.currency, fixed.value)),
+        }
+    }
+
+    /// Share of what is left after fixed amounts, none for a fixed target.
+    fn share(&self) -> Option<f64> {
+        match self {
+            Target::Share(share) => Some(*share),
+            Target::Fixed(_) => None,
+        }
+    }
+}
+
+/// Whether a position is part of the investable portfolio, or of net worth only.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+enum PositionKind {
+    /// Counted toward allocation and balanced
+    #[default]
+    Investable,
+    /// Held but never bought or sold, e.g. real estate or private equity
+    Asset,
+    /// Owed, e.g. a mortgage, always of negative value
+    Liability,
+}
+
+impl PositionKind {
+    fn is_investable(&self) -> bool {
+        *self == PositionKind::Investable
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Automatic {
+    /// Cash balance held at position source with given session id
+    Cash(String),
+    /// Holding not held as any position of the portfolio
+    Unallocated,
 }
 
 impl std::ops::Sub for Position {
@@ -50,7 +200,75 @@ impl std::ops::Sub for Position {
             ticker: self.ticker,
             amount: Some(self_amount - rhs_amount),
             target: self.target,
+            isin: self.isin,
+            symbols: self.symbols,
+            automatic: self.automatic,
+            drift_threshold: self.drift_threshold,
+            quantity: self.quantity,
+            quote: self.quote,
+            bonds: self.bonds,
+            deposit: self.deposit,
+            kind: self.kind,
+            loan: self.loan,
+            held: self.held,
+            price_date: self.price_date,
+            redemption: self.redemption,
+        }
+    }
+}
+
+impl Position {
+    /// Session of the position source holding this automatic cash position.
+    fn cash_session(&self) -> Option<&String> {
+        match &self.automatic {
+            Some(Automatic::Cash(session_id)) => Some(session_id),
+            _ => None,
+        }
+    }
+
+    /// Broker symbols held as this position in group `group_id`, mapped ones first.
+    fn symbols_in(&self, group_id: &str) -> Vec<&str> {
+        let mut symbols: Vec<&str> = self
+            .symbols
+            .get(group_id)
+            .map(|symbols| symbols.iter().map(String::as_str).collect())
+            .unwrap_or_default();
+        if group_id == self.group {
+            symbols.push(&self.ticker);
         }
+        symbols
+    }
+
+    /// Whether `holding` from position source of group `group_id` is held as this position.
+    fn holds(&self, group_id: &str, holding: &Holding) -> bool {
+        if self.symbols_in(group_id).contains(&holding.symbol.as_str()) {
+            return true;
+        }
+        let in_group = group_id == self.group || self.symbols.contains_key(group_id);
+        in_group && self.isin.is_some() && holding.isin == self.isin
+    }
+}
+
+impl Held {
+    fn new(session_id: &str, holding: &Holding, valuation: Valuation) -> Self {
+        Self {
+            session_id: session_id.to_string(),
+            symbol: holding.symbol.clone(),
+            quantity: holding.quantity,
+            bid_price: holding.price.clone(),
+            value: valuation.of(holding).clone(),
+            loaded_bid: holding.price.value,
+        }
+    }
+
+    /// Value at current bid price. Exposure and equity both change by the same amount with price,
+    /// so leveraged holdings are revalued by the change only.
+    fn value(&self, currency: Currency, rates: &Rates) -> Amount {
+        let change = Amount::new(
+            self.bid_price.currency,
+            self.quantity * (self.bid_price.value - self.loaded_bid),
+        );
+        self.value.convert(currency, rates) + change.convert(currency, rates)
     }
 }
 
@@ -70,28 +288,114 @@ impl std::fmt::Display for Position {
 }
 
 #[derive(Debug, Serialize, Deserialize)]
+#[serde(from = "GroupRepr")]
 struct Group {
     id: String,
     currency: Currency,
-    xtb: Option<xtb::XtbAccount>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    source: Option<SourceConfig>,
+}
+
+/// `Group` as stored in portfolio file, accepting legacy `xtb` account.
+#[derive(Deserialize)]
+struct GroupRepr {
+    id: String,
+    currency: Currency,
+    #[serde(default)]
+    source: Option<SourceConfig>,
+    /// Before position sources were introduced, XTB account was configured directly
+    #[serde(default)]
+    xtb: Option<XtbAccount>,
+}
+
+impl From<GroupRepr> for Group {
+    fn from(group: GroupRepr) -> Self {
+        Group {
+            id: group.id,
+            currency: group.currency,
+            source: group.source.or(group.xtb.map(SourceConfig::Xtb)),
+        }
+    }
 }
 
 impl Group {
     #[allow(dead_code)]
     pub fn new(id: String, currency: Currency) -> Group {
         Group {
-            id: id,
-            currency: currency,
-            xtb: None,
+            id,
+            currency,
+            source: None,
         }
     }
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct Config {
+    /// Endpoint of XTB accounts not naming one of `endpoints`
+    #[serde(skip_serializing_if = "Option::is_none")]
     xtb: Option<xtb::XtbConfig>,
+    /// Named XTB endpoints, e.g. demo and real server, referenced by accounts of groups
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    endpoints: BTreeMap<String, xtb::XtbConfig>,
     #[serde(default = "Currency::native")]
     base_currency: Currency,
+    /// Largest difference between share and target of a position not highlighted by `watch`
+    #[serde(default = "default_drift_threshold")]
+    drift_threshold: f64,
+    /// Value of holdings counted toward allocation
+    #[serde(default)]
+    valuation: Valuation,
+    /// Layouts of CSV exports read by `import-csv`, by name
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    csv_formats: BTreeMap<String, CsvFormat>,
+    /// CSV file of monthly CPI, used to value inflation-indexed bonds
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    cpi_file: Option<String>,
+    /// Targets changing over time, overriding targets of positions it gives one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    glide_path: Option<GlidePath>,
+}
+
+fn default_drift_threshold() -> f64 {
+    0.05
+}
+
+/// Value of a holding bought on margin (e.g. CFD) counted toward allocation.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Valuation {
+    /// Contribution to account equity, i.e. profit of a leveraged holding
+    #[default]
+    Equity,
+    /// Market exposure, i.e. nominal value of a leveraged holding
+    Exposure,
+}
+
+impl Valuation {
+    fn of(self, holding: &Holding) -> &Amount {
+        match self {
+            Valuation::Equity => &holding.equity,
+            Valuation::Exposure => &holding.market_value,
+        }
+    }
+}
+
+impl Config {
+    /// Endpoint `account` is at, if configured.
+    fn xtb_endpoint(&self, account: &XtbAccount) -> Option<&xtb::XtbConfig> {
+        match account.endpoint() {
+            Some(endpoint) => self.endpoints.get(endpoint),
+            None => self.xtb.as_ref(),
+        }
+    }
+
+    /// Endpoint of position source, if it needs one.
+    fn endpoint(&self, source_config: &SourceConfig) -> Option<&xtb::XtbConfig> {
+        match source_config {
+            SourceConfig::Xtb(account) => self.xtb_endpoint(account),
+            SourceConfig::Ibkr(_) => None,
+        }
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -102,14 +406,33 @@ pub struct Portfolio {
     /* Saved fields */
     config: Config,
     groups: Vec<Group>,
+    #[serde(serialize_with = "serialize_saved_positions")]
     positions: Vec<Position>,
 }
 
+/// Serialize positions, leaving out automatic cash positions.
+fn serialize_saved_positions<S: serde::Serializer>(
+    positions: &[Position],
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    serializer.collect_seq(
+        positions
+            .iter()
+            .filter(|position| position.automatic.is_none()),
+    )
+}
+
 impl Default for Config {
     fn default() -> Self {
         Self {
             xtb: None,
+            endpoints: BTreeMap::new(),
             base_currency: Currency::native(),
+            drift_threshold: default_drift_threshold(),
+            valuation: Valuation::default(),
+            csv_formats: BTreeMap::new(),
+            cpi_file: None,
+            glide_path: None,
         }
     }
 }
@@ -122,18 +445,95 @@ impl std::fmt::Display for Portfolio {
             self.total_value(self.config.base_currency).value,
             self.config.base_currency
         )?;
+        if self.config.glide_path.is_some() {
+            writeln!(f, "Targets of glide path on {}", Local::now().date_naive())?;
+        }
         writeln!(f, "Positions:")?;
-        for position in &self.positions {
+        let (unallocated, positions): (Vec<_>, Vec<_>) = self
+            .positions
+            .iter()
+            .partition(|position| position.automatic == Some(Automatic::Unallocated));
+        let (positions, held): (Vec<_>, Vec<_>) = positions
+            .into_iter()
+            .partition(|position| position.kind.is_investable());
+        for position in positions {
             let position_amount = position.amount.clone().unwrap();
             let position_share =
                 position_amount.value / self.total_value(position_amount.currency).value;
 
-            writeln!(
+            write!(
                 f,
                 "- {} [{:4.2} ({:4.2})]",
-                position, position_share, position.target
+                position,
+                position_share,
+                self.target_share(&self.target(position), &self.value())
+            )?;
+            if let Some(date) = position.price_date {
+                write!(f, " price of {}", date)?;
+            }
+            if let Some(redemption) = &position.redemption {
+                let net = redemption.net();
+                write!(
+                    f,
+                    " redeemed now {:.2} {} (fee {:.2}, tax {:.2})",
+                    net.value, net.currency, redemption.fee.value, redemption.tax.value
+                )?;
+            }
+            writeln!(f)?;
+        }
+        if !held.is_empty() {
+            writeln!(f, "Assets and liabilities (not balanced):")?;
+            let today = Local::now().date_naive();
+            for position in held {
+                write!(f, "- {}", position)?;
+                if let Some(installment) = position
+                    .loan
+                    .as_ref()
+                    .and_then(|loan| loan.next_installment(today))
+                {
+                    write!(
+                        f,
+                        " next installment {:.2} on {}",
+                        installment.payment, installment.date
+                    )?;
+                }
+                writeln!(f)?;
+            }
+            let net_worth = self.net_worth();
+            writeln!(
+                f,
+                "Net worth: {:.2} {:?}",
+                net_worth.value, net_worth.currency
             )?;
         }
+        let mut maturities: Vec<_> = self
+            .positions
+            .iter()
+            .filter_map(|position| Some((position.deposit.as_ref()?.maturity?, position)))
+            .collect();
+        if !maturities.is_empty() {
+            maturities.sort_by_key(|(maturity, _)| *maturity);
+            let today = Local::now().date_naive();
+            writeln!(f, "Upcoming maturities:")?;
+            for (maturity, position) in maturities {
+                match maturity < today {
+                    true => writeln!(f, "- {} {} matured", maturity, position)?,
+                    false => writeln!(
+                        f,
+                        "- {} {} in {} days",
+                        maturity,
+                        position,
+                        (maturity - today).num_days()
+                    )?,
+                }
+            }
+        }
+        if !unallocated.is_empty() {
+            writeln!(f, "Unallocated (not in portfolio, see `adopt`):")?;
+            for position in unallocated {
+                writeln!(f, "- {} [{}]", position, position.group)?;
+            }
+        }
         Ok(())
     }
 }
@@ -153,13 +553,10 @@ impl PositionChange {
         }
     }
 
-    fn format(&self, rates: &Rates, total_portfolio_value: Amount) -> String {
-        let position_share = self.new_value().div(&total_portfolio_value, &rates);
+    fn format(&self, rates: &Rates, total_portfolio_value: Amount, target_share: f64) -> String {
+        let position_share = self.new_value().div(&total_portfolio_value, rates);
         // Use regular display method, but add share
-        format!(
-            "{} [{:4.2} ({:4.2})]",
-            self, position_share, self.position.target
-        )
+        format!("{} [{:4.2} ({:4.2})]", self, position_share, target_share)
     }
 }
 impl std::fmt::Display for PositionChange {
@@ -181,9 +578,44 @@ impl std::fmt::Display for PositionChange {
     }
 }
 
+/// Purchases requested at a single XTB account, as symbol and amount in group currency.
+#[derive(Debug)]
+pub struct AccountPurchases {
+    /// Endpoint the account is at
+    pub xtb_config: XtbConfig,
+    pub account: XtbAccount,
+    pub purchases: Vec<(String, Amount)>,
+}
+
 #[derive(Debug)]
 pub struct ChangeRequest {
     changes: Vec<PositionChange>,
+    /// Currency of the investment balanced
+    currency: Currency,
+}
+
+/// Exchange of the investment currency into the currency of groups.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Exchange {
+    pub from: Amount,
+    pub to: Amount,
+}
+
+/// Deposit into a group, in group currency.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Transfer {
+    pub group: String,
+    pub amount: Amount,
+}
+
+/// Money to move for a change request: currencies to exchange first, then transfers to groups.
+///
+/// Groups whose change is covered by cash already held at their source need no transfer. Cash of
+/// a source funds any group of the source, not only the one its automatic position is in.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FundingPlan {
+    pub exchanges: Vec<Exchange>,
+    pub transfers: Vec<Transfer>,
 }
 
 impl ChangeRequest {
@@ -196,25 +628,46 @@ impl ChangeRequest {
         let new_value = current_value + total_change;
 
         result.push_str("Change requests:\n");
+        let new_total = portfolio.total_value(portfolio.config.base_currency).add(
+            &self.total_change(&portfolio.rates, portfolio.config.base_currency),
+            &portfolio.rates,
+        );
         for change in &self.changes {
             result.push_str(&format!(
                 "{}\n",
                 change.format(
                     &portfolio.rates,
-                    portfolio.total_value(portfolio.config.base_currency).add(
-                        &self.total_change(&portfolio.rates, portfolio.config.base_currency),
-                        &portfolio.rates,
-                    )
+                    new_total.clone(),
+                    portfolio.target_share(&portfolio.target(&change.position), &new_total)
                 )
             ));
         }
         result.push_str("\nChange per group:\n");
-        for (group, amount) in self.change_per_group() {
+        for (group, amount) in self.change_per_group(portfolio) {
             result.push_str(&format!(
                 "- {:16.47}: + {:9.2} {}\n",
                 group, amount.value, amount.currency
             ));
         }
+        let plan = self.funding_plan(portfolio);
+        if !plan.exchanges.is_empty() || !plan.transfers.is_empty() {
+            result.push_str("\nFunding plan:\n");
+        }
+        for exchange in &plan.exchanges {
+            result.push_str(&format!(
+                "- Exchange {:9.2} {} to {:9.2} {}\n",
+                exchange.from.value,
+                exchange.from.currency,
+                exchange.to.value,
+                exchange.to.currency
+            ));
+        }
+        for transfer in &plan.transfers {
+            result.push_str(&format!(
+                "- Transfer {:9.2} {} to {}\n",
+                transfer.amount.value, transfer.amount.currency, transfer.group
+            ));
+        }
         result.push_str(&format!(
             "\nTotal: {:9.2} + {:9.2} = {:9.2} {}\n",
             current_value, total_change, new_value, portfolio.config.base_currency,
@@ -225,20 +678,98 @@ impl ChangeRequest {
 }
 
 impl ChangeRequest {
-    pub fn change_per_group(&self) -> HashMap<String, Amount> {
-        // TODO: Use actual group config for currency, not position currency
-        let mut change_per_group = HashMap::new();
-        for change in &self.changes {
+    /// Total change of positions of each group, in currency of the group.
+    pub fn change_per_group(&self, portfolio: &Portfolio) -> BTreeMap<String, Amount> {
+        Self::sum_per_group(portfolio, self.changes.iter())
+    }
+
+    /// Total of `changes` per group, in currency of the group.
+    fn sum_per_group<'a>(
+        portfolio: &Portfolio,
+        changes: impl Iterator<Item = &'a PositionChange>,
+    ) -> BTreeMap<String, Amount> {
+        let mut change_per_group = BTreeMap::new();
+        for change in changes {
             let group = change.position.group.clone();
-            let amount = change.amount.clone();
+            let currency = portfolio
+                .group_currency(&group)
+                .unwrap_or(change.amount.currency);
             let entry = change_per_group
                 .entry(group)
-                .or_insert(Amount::new(amount.currency, 0.0));
-            entry.value += amount.value;
+                .or_insert(Amount::new(currency, 0.0));
+            entry.value +=
+                portfolio
+                    .rates
+                    .convert(change.amount.currency, currency, change.amount.value);
         }
         change_per_group
     }
 
+    /// Exchanges and transfers funding the change of each group from the investment currency.
+    pub fn funding_plan(&self, portfolio: &Portfolio) -> FundingPlan {
+        let (cash_spent, changes): (Vec<_>, Vec<_>) = self.changes.iter().partition(|change| {
+            change.position.cash_session().is_some() && change.amount.value < 0.0
+        });
+        let mut cash_per_session: HashMap<String, Amount> = HashMap::new();
+        for change in cash_spent {
+            cash_per_session
+                .entry(change.position.cash_session().unwrap().clone())
+                .or_insert(Amount::new(self.currency, 0.0))
+                .value -=
+                portfolio
+                    .rates
+                    .convert(change.amount.currency, self.currency, change.amount.value);
+        }
+        let mut change_per_group = Self::sum_per_group(portfolio, changes.into_iter());
+        for (group, amount) in change_per_group.iter_mut() {
+            let Some(cash) = portfolio
+                .source_session(group)
+                .and_then(|session_id| cash_per_session.get_mut(&session_id))
+            else {
+                continue;
+            };
+            if amount.value <= 0.0 {
+                continue;
+            }
+            let covered = portfolio
+                .rates
+                .convert(cash.currency, amount.currency, cash.value)
+                .min(amount.value);
+            amount.value -= covered;
+            cash.value -= portfolio
+                .rates
+                .convert(amount.currency, cash.currency, covered);
+        }
+        let transfers: Vec<Transfer> = change_per_group
+            .into_iter()
+            // Rounding leftovers of the solver are not worth a transfer
+            .filter(|(_, amount)| amount.value >= 0.01)
+            .map(|(group, amount)| Transfer { group, amount })
+            .collect();
+        let mut per_currency: BTreeMap<String, Amount> = BTreeMap::new();
+        for transfer in &transfers {
+            let currency = transfer.amount.currency;
+            if currency == self.currency {
+                continue;
+            }
+            per_currency
+                .entry(currency.to_string())
+                .or_insert(Amount::new(currency, 0.0))
+                .value += transfer.amount.value;
+        }
+        let exchanges = per_currency
+            .into_values()
+            .map(|to| Exchange {
+                from: to.convert(self.currency, &portfolio.rates),
+                to,
+            })
+            .collect();
+        FundingPlan {
+            exchanges,
+            transfers,
+        }
+    }
+
     pub fn total_change(&self, rates: &Rates, currency: Currency) -> Amount {
         let mut total_change = Amount::new(currency, 0.0);
         for change in &self.changes {
@@ -261,23 +792,34 @@ impl Portfolio {
     }
 
     /// Initialize portfolio with example data
-    pub fn example(xtb_config: Option<XtbConfig>, xtb_account: Option<XtbAccount>) -> Portfolio {
+    ///
+    /// XTB account, if given, is at endpoint `endpoint` (name and config).
+    pub fn example(
+        endpoint: Option<(String, XtbConfig)>,
+        xtb_account: Option<XtbAccount>,
+    ) -> Portfolio {
         Portfolio {
             rates: Rates::default(),
             config: Config {
-                xtb: xtb_config,
+                xtb: None,
+                endpoints: endpoint.into_iter().collect(),
                 base_currency: Currency::USD,
+                drift_threshold: default_drift_threshold(),
+                valuation: Valuation::default(),
+                csv_formats: BTreeMap::new(),
+                cpi_file: None,
+                glide_path: None,
             },
             groups: vec![
                 Group {
                     id: "xtb_usd".to_string(),
                     currency: Currency::USD,
-                    xtb: xtb_account.clone(),
+                    source: xtb_account.clone().map(SourceConfig::Xtb),
                 },
                 Group {
                     id: "cash_eur".to_string(),
                     currency: Currency::EUR,
-                    xtb: None,
+                    source: None,
                 },
             ],
             positions: vec![
@@ -295,7 +837,20 @@ impl Portfolio {
                             })
                         }
                     },
-                    target: 0.5,
+                    target: Target::Share(0.5),
+                    isin: None,
+                    symbols: BTreeMap::new(),
+                    automatic: None,
+                    drift_threshold: None,
+                    quantity: None,
+                    quote: None,
+                    bonds: vec![],
+                    deposit: None,
+                    kind: PositionKind::Investable,
+                    loan: None,
+                    held: vec![],
+                    price_date: None,
+                    redemption: None,
                 },
                 Position {
                     name: "Cash".to_string(),
@@ -305,7 +860,20 @@ impl Portfolio {
                         currency: Currency::EUR,
                         value: 100.0,
                     }),
-                    target: 0.5,
+                    target: Target::Share(0.5),
+                    isin: None,
+                    symbols: BTreeMap::new(),
+                    automatic: None,
+                    drift_threshold: None,
+                    quantity: None,
+                    quote: None,
+                    bonds: vec![],
+                    deposit: None,
+                    kind: PositionKind::Investable,
+                    loan: None,
+                    held: vec![],
+                    price_date: None,
+                    redemption: None,
                 },
             ],
         }
@@ -313,149 +881,941 @@ impl Portfolio {
 
     pub async fn from_file(
         filename: &str,
-        encryption_key: &str,
+        encryption_key: &Secret,
+    ) -> Result<Portfolio, error::PortfolioReadError> {
+        Self::from_file_with_rates(filename, encryption_key, Rates::load().await).await
+    }
+
+    /// Same as `from_file`, but exchange rates are provided by `rates` instead of loaded from NBP.
+    pub async fn from_file_with_rates(
+        filename: &str,
+        encryption_key: &Secret,
+        rates: Rates,
+    ) -> Result<Portfolio, error::PortfolioReadError> {
+        Self::parse_file(filename, encryption_key)?
+            .load(encryption_key, rates)
+            .await
+    }
+
+    /// Parse portfolio file without reading position sources.
+    ///
+    /// Amounts of positions held at position sources stay `None` until `load` is called.
+    pub fn parse_file(
+        filename: &str,
+        encryption_key: &Secret,
+    ) -> Result<Portfolio, error::PortfolioReadError> {
+        Ok(serde_yaml::from_str(&Self::read_contents(
+            filename,
+            encryption_key,
+        )?)?)
+    }
+
+    /// Set exchange `rates` and read values of positions from position sources.
+    pub async fn load(
+        self,
+        encryption_key: &Secret,
+        rates: Rates,
     ) -> Result<Portfolio, error::PortfolioReadError> {
-        let file = std::fs::File::open(filename)?;
-        let mut portfolio: Portfolio = serde_yaml::from_reader(file)?;
+        let mut portfolio = self;
 
         /* Load rates */
-        portfolio.rates = Rates::load().await;
-
-        /* Read market values from xtb */
-        let mut xtb_position_market_values: HashMap<String, Amount> = HashMap::new();
-        if let Some(mut xtb) = portfolio.config.xtb.clone() {
-            // Get market values for all positions from XTB for each group
-            for group in &mut portfolio.groups {
-                if let Some(xtb_account) = group.xtb.clone() {
-                    xtb.connect().await?;
-                    xtb.login(&xtb_account.decrypt(&encryption_key)?).await?;
-
-                    let group_position_market_values: Result<Vec<_>, _> = xtb
-                        .get_position_market_values()
-                        .await?
-                        .into_iter()
-                        /* Filter only position from this group */
-                        .filter(|xtb_position| {
-                            portfolio.positions.iter().any(|position| {
-                                position.ticker == xtb_position.symbol && position.group == group.id
-                            })
-                        })
-                        .map(|x| {
-                            // Currently same positions in different groups are not supported
-                            // Check if position with this symbol already exists in global xtb_position_market_values
-                            if xtb_position_market_values.contains_key(&x.symbol) {
-                                return Err(error::PortfolioReadError::DuplicateSymbolError(
-                                    x.symbol,
-                                ));
-                            } else {
-                                return Ok((
-                                    x.symbol,
-                                    x.market_value.convert(group.currency, &portfolio.rates),
-                                ));
-                            }
-                        })
-                        .collect();
-                    xtb.disconnect().await?;
-
-                    for (symbol, market_value) in group_position_market_values? {
-                        if xtb_position_market_values.contains_key(&symbol) {
-                            log::info!(
-                                "Duplicate symbol: {} in group: {}, adding",
-                                symbol,
-                                group.id.to_string()
-                            );
-                            xtb_position_market_values.insert(
-                                symbol.clone(),
-                                market_value + xtb_position_market_values[&symbol].clone(),
-                            );
-                        } else {
-                            xtb_position_market_values.insert(symbol, market_value);
+        portfolio.rates = rates;
+
+        /* Price positions given by quantity */
+        let quotes = Quotes::default();
+        for position in &mut portfolio.positions {
+            let Some(quantity) = position.quantity else {
+                continue;
+            };
+            let quote = position
+                .quote
+                .as_ref()
+                .ok_or(error::PortfolioReadError::QuoteMissing(
+                    position.name.clone(),
+                ))?;
+            let quote = quotes.quote(quote, &position.ticker).await?;
+            position.amount = Some(Amount::new(
+                quote.price.currency,
+                quantity * quote.price.value,
+            ));
+            position.price_date = Some(quote.date);
+        }
+
+        /* Value bonds as if redeemed today */
+        let today = Local::now().date_naive();
+        let mut cpi: Option<CpiTable> = None;
+        for position in &mut portfolio.positions {
+            if position.bonds.is_empty() {
+                continue;
+            }
+            let cpi = match &mut cpi {
+                Some(cpi) => cpi,
+                // Bonds in their first year need no CPI
+                None => cpi.insert(match &portfolio.config.cpi_file {
+                    Some(cpi_file) => CpiTable::from_file(Path::new(cpi_file))?,
+                    None => CpiTable::default(),
+                }),
+            };
+            let mut redemption: Option<BondValue> = None;
+            for bond in &position.bonds {
+                let value = bond.value(cpi, today)?;
+                redemption = Some(match redemption {
+                    Some(redemption) => redemption + value,
+                    None => value,
+                });
+            }
+            position.amount = redemption
+                .as_ref()
+                .map(|redemption| redemption.value.clone());
+            position.redemption = redemption;
+        }
+
+        /* Accrue interest of deposits until today */
+        for position in &mut portfolio.positions {
+            if let Some(deposit) = &position.deposit {
+                position.amount = Some(deposit.value(today));
+            }
+        }
+
+        /* Value liabilities, by amortization schedule of loans */
+        for position in &mut portfolio.positions {
+            if let Some(loan) = &position.loan {
+                position.amount = Some(loan.value(today));
+            }
+            if position.kind == PositionKind::Liability {
+                // Owed amounts may be given as positive in file
+                if let Some(amount) = &mut position.amount {
+                    amount.value = -amount.value.abs();
+                }
+            }
+        }
+
+        /* Read holdings from position sources */
+        // Groups may share a source (e.g. same XTB account), so open a single session per
+        // distinct source and query all of them concurrently
+        let mut source_configs: HashMap<String, &SourceConfig> = HashMap::new();
+        for group in &portfolio.groups {
+            if let Some(source_config) = &group.source {
+                source_configs.insert(source_config.session_id(), source_config);
+            }
+        }
+        let config = &portfolio.config;
+        let source_results = try_join_all(source_configs.into_iter().map(
+            |(session_id, source_config)| async move {
+                let mut source = source_config.open(config.endpoint(source_config))?;
+                source.connect().await?;
+                source.authenticate(encryption_key).await?;
+                let holdings = source.holdings().await;
+                let cash = source.cash_balance().await;
+                source.disconnect().await?;
+                Ok::<_, error::PortfolioReadError>((session_id, holdings?, cash?))
+            },
+        ))
+        .await?;
+        let mut holdings_per_source: HashMap<String, Vec<Holding>> = HashMap::new();
+        let mut cash_per_source: HashMap<String, Amount> = HashMap::new();
+        for (session_id, holdings, cash) in source_results {
+            if let Some(cash) = cash {
+                cash_per_source.insert(session_id.clone(), cash);
+            }
+            holdings_per_source.insert(session_id, holdings);
+        }
+
+        /* Set position market values from holdings if `amount` is none */
+        // Every holding may be held as a single position only, shared sources are queried once.
+        // Positions with `amount` given still hold their holdings, so they are not unallocated.
+        let mut held_as: HashMap<(String, usize), usize> = HashMap::new();
+        let valuation = portfolio.config.valuation;
+        for (position_index, position) in portfolio.positions.iter_mut().enumerate() {
+            let position_group = portfolio
+                .groups
+                .iter()
+                .find(|group| group.id == position.group);
+            // Positions of groups with a source that are not held (yet) are worth nothing
+            let mut from_source = position_group.is_some_and(|group| group.source.is_some());
+            let currency =
+                position_group.map_or(portfolio.config.base_currency, |group| group.currency);
+            let mut market_value = Amount::new(currency, 0.0);
+            let mut held = vec![];
+
+            for group in &portfolio.groups {
+                let Some(source_config) = &group.source else {
+                    continue;
+                };
+                let session_id = source_config.session_id();
+                for (holding_index, holding) in holdings_per_source[&session_id].iter().enumerate()
+                {
+                    if !position.holds(&group.id, holding) {
+                        continue;
+                    }
+                    from_source = true;
+                    match held_as.insert((session_id.clone(), holding_index), position_index) {
+                        // Same holding mapped through another group sharing the source
+                        Some(index) if index == position_index => continue,
+                        Some(_) => {
+                            return Err(error::PortfolioReadError::DuplicateSymbolError(
+                                holding.symbol.clone(),
+                            ))
                         }
+                        None => {}
                     }
+                    market_value =
+                        market_value + valuation.of(holding).convert(currency, &portfolio.rates);
+                    held.push(Held::new(&session_id, holding, valuation));
                 }
             }
-        }
 
-        /* Set position market values from xtb if `amount` is none. */
-        for position in &mut portfolio.positions {
             if position.amount.is_none() {
-                let position_market_value = xtb_position_market_values
-                    .get(&position.ticker)
-                    .ok_or(error::PortfolioReadError::AmountMissing)?;
-                position.amount = Some(position_market_value.clone());
+                if position_group.is_none() || !from_source {
+                    return Err(error::PortfolioReadError::AmountMissing);
+                }
+                position.amount = Some(market_value);
+                position.held = held;
             }
         }
 
-        Ok(portfolio)
-    }
+        /* Add holdings not held as any position as automatic unallocated positions */
+        // Holdings of a source shared by several groups are added to the first of them only
+        let mut reported_sessions = HashSet::new();
+        for group in &portfolio.groups {
+            let Some(source_config) = &group.source else {
+                continue;
+            };
+            let session_id = source_config.session_id();
+            if !reported_sessions.insert(session_id.clone()) {
+                continue;
+            }
+            let mut unallocated: BTreeMap<String, (Amount, Vec<Held>)> = BTreeMap::new();
+            for (holding_index, holding) in holdings_per_source[&session_id].iter().enumerate() {
+                if held_as.contains_key(&(session_id.clone(), holding_index)) {
+                    continue;
+                }
+                let market_value = valuation
+                    .of(holding)
+                    .convert(group.currency, &portfolio.rates);
+                let (total, held) = unallocated
+                    .entry(holding.symbol.clone())
+                    .or_insert((Amount::new(group.currency, 0.0), vec![]));
+                *total = total.clone() + market_value;
+                held.push(Held::new(&session_id, holding, valuation));
+            }
+            for (symbol, (amount, held)) in unallocated {
+                portfolio.positions.push(Position {
+                    name: symbol.clone(),
+                    group: group.id.clone(),
+                    ticker: symbol,
+                    amount: Some(amount),
+                    target: Target::Share(0.0),
+                    isin: None,
+                    symbols: BTreeMap::new(),
+                    automatic: Some(Automatic::Unallocated),
+                    drift_threshold: None,
+                    quantity: None,
+                    quote: None,
+                    bonds: vec![],
+                    deposit: None,
+                    kind: PositionKind::Investable,
+                    loan: None,
+                    held,
+                    price_date: None,
+                    redemption: None,
+                });
+            }
+        }
 
-    pub async fn to_file(&self, filename: &str) -> Result<String, error::PortfolioWriteError> {
-        let mut file = std::fs::File::create(filename)?;
-        serde_yaml::to_writer(&mut file, &self)?;
+        /* Add cash held at position sources as automatic cash positions */
+        // Cash of a source shared by several groups is added to the first of them only
+        for group in &portfolio.groups {
+            if let Some(source_config) = &group.source {
+                let session_id = source_config.session_id();
+                match cash_per_source.remove(&session_id) {
+                    Some(cash) if cash.value != 0.0 => portfolio.positions.push(Position {
+                        name: format!("Cash ({})", group.id),
+                        group: group.id.clone(),
+                        ticker: "CASH".to_string(),
+                        amount: Some(cash.convert(group.currency, &portfolio.rates)),
+                        target: Target::Share(0.0),
+                        isin: None,
+                        symbols: BTreeMap::new(),
+                        automatic: Some(Automatic::Cash(session_id)),
+                        drift_threshold: None,
+                        quantity: None,
+                        quote: None,
+                        bonds: vec![],
+                        deposit: None,
+                        kind: PositionKind::Investable,
+                        loan: None,
+                        held: vec![],
+                        price_date: None,
+                        redemption: None,
+                    }),
+                    _ => {}
+                }
+            }
+        }
 
-        Ok(filename.to_string())
+        Ok(portfolio)
     }
 
-    fn total_value(&self, currency: Currency) -> Amount {
-        let mut amount = Amount {
-            currency,
-            value: 0.0,
-        };
+    pub fn rates(&self) -> &Rates {
+        &self.rates
+    }
 
-        for position in &self.positions {
-            amount.value += self.rates.convert(
-                position.amount.clone().unwrap().currency,
-                currency,
-                position.amount.clone().unwrap().value,
-            );
-        }
-        amount
+    /// Endpoint of XTB `account`, if configured.
+    pub fn xtb_config(&self, account: &XtbAccount) -> Option<&XtbConfig> {
+        self.config.xtb_endpoint(account)
     }
 
-    /// Balance portfolio to given investment
-    /// Returns a list of changes to be made to the portfolio
-    pub fn balance(&self, investment: Amount) -> Result<ChangeRequest, error::PortfolioOpsError> {
-        let mut problem_variables = good_lp::ProblemVariables::new();
+    /// Connect accounts without a named endpoint to one of the public XTB servers instead of
+    /// the configured one.
+    ///
+    /// Legacy config with explicit host or port is left as it is, as it may point at a proxy
+    /// of either server.
+    pub fn use_xtb_server(&mut self, server: XtbServer) {
+        self.config.xtb = self.config.xtb.take().map(|xtb| match xtb.has_address() {
+            true => xtb,
+            false => xtb.with_server(server),
+        });
+    }
 
-        let current_portfolio_value = self.total_value(investment.currency).value;
-        let mut per_position_investments = vec![];
-        for _position in &self.positions {
-            per_position_investments
-                .push(problem_variables.add(good_lp::variable().min(0).max(investment.value)))
+    /// Purchases requested by `change_request` in groups held at XTB, per XTB account.
+    ///
+    /// Changes of automatic cash positions and changes that are not purchases are left out.
+    pub fn xtb_purchases(
+        &self,
+        change_request: &ChangeRequest,
+    ) -> Result<Vec<AccountPurchases>, error::SourceError> {
+        let mut account_purchases: Vec<AccountPurchases> = vec![];
+        for change in &change_request.changes {
+            if change.position.automatic.is_some() || change.amount.value <= 0.0 {
+                continue;
+            }
+            let Some(SourceConfig::Xtb(account)) = self
+                .groups
+                .iter()
+                .find(|group| group.id == change.position.group)
+                .and_then(|group| group.source.as_ref())
+            else {
+                continue;
+            };
+            let symbol = change.position.symbols_in(&change.position.group)[0];
+            let purchase = (symbol.to_string(), change.amount.clone());
+            match account_purchases.iter_mut().find(|purchases| {
+                purchases.account.account_id() == account.account_id()
+                    && purchases.account.endpoint() == account.endpoint()
+            }) {
+                Some(purchases) => purchases.purchases.push(purchase),
+                None => account_purchases.push(AccountPurchases {
+                    xtb_config: self
+                        .config
+                        .xtb_endpoint(account)
+                        .ok_or(error::SourceError::NotConfigured(
+                            account.endpoint().unwrap_or("xtb").to_string(),
+                        ))?
+                        .clone(),
+                    account: account.clone(),
+                    purchases: vec![purchase],
+                }),
+            }
         }
+        Ok(account_purchases)
+    }
 
-        let total_investment: Expression = per_position_investments.iter().sum();
-        let new_portfolio_value = investment.value + current_portfolio_value;
-
-        let mut total_objective: Expression = 0.into();
+    /// Total value of all positions, in base currency.
+    pub fn value(&self) -> Amount {
+        self.total_value(self.config.base_currency)
+    }
 
-        let objectives: Vec<_> = self
-            .positions
-            .clone()
-            .into_iter()
-            .zip(per_position_investments.clone().into_iter())
-            .map(|(position, position_investment)| {
-                // this position value in investment currency
-                let position_value = self.rates.convert(
-                    position.amount.clone().unwrap().currency,
-                    investment.currency,
-                    position.amount.clone().unwrap().value,
-                );
+    /// Position sources of all groups, one per session.
+    pub fn sources(&self) -> Vec<&SourceConfig> {
+        let mut sessions = HashSet::new();
+        self.groups
+            .iter()
+            .filter_map(|group| group.source.as_ref())
+            .filter(|source_config| sessions.insert(source_config.session_id()))
+            .collect()
+    }
 
-                // Objective for specific position - minimize the imbalance
-                let mut position_objective = ((position_value + position_investment)
-                    / new_portfolio_value)
-                    - position.target;
+    /// Symbols of holdings read from position source of `session_id`.
+    pub fn held_symbols(&self, session_id: &str) -> BTreeSet<String> {
+        self.positions
+            .iter()
+            .flat_map(|position| &position.held)
+            .filter(|held| held.session_id == session_id)
+            .map(|held| held.symbol.clone())
+            .collect()
+    }
 
-                // If current share < target share, negate the objective (approaching from below 0)
-                let current_share = position_value / current_portfolio_value;
-                if current_share < position.target {
-                    position_objective = -position_objective;
+    /// Set bid price of `symbol` held at `session_id` and revalue positions holding it.
+    ///
+    /// Returns whether value of any position changed.
+    pub fn update_bid_price(&mut self, session_id: &str, symbol: &str, bid_price: f64) -> bool {
+        let mut changed = false;
+        for position in &mut self.positions {
+            let mut revalue = false;
+            for held in &mut position.held {
+                if held.session_id == session_id
+                    && held.symbol == symbol
+                    && held.bid_price.value != bid_price
+                {
+                    held.bid_price.value = bid_price;
+                    revalue = true;
                 }
+            }
+            if !revalue {
+                continue;
+            }
+            let currency = position.amount.clone().unwrap().currency;
+            let mut amount = Amount::new(currency, 0.0);
+            for held in &position.held {
+                amount = amount + held.value(currency, &self.rates);
+            }
+            position.amount = Some(amount);
+            changed = true;
+        }
+        changed
+    }
 
-                // Add this position objective to total objective
-                total_objective += position_objective.clone();
-                position_objective
+    /// Shares of positions compared to their targets, unallocated holdings left out.
+    pub fn allocation(&self) -> Vec<Allocation> {
+        self.positions
+            .iter()
+            .filter(|position| {
+                position.automatic != Some(Automatic::Unallocated) && position.kind.is_investable()
+            })
+            .map(|position| {
+                let position_amount = position.amount.clone().unwrap();
+                let share =
+                    position_amount.value / self.total_value(position_amount.currency).value;
+                let drift_threshold = position
+                    .drift_threshold
+                    .unwrap_or(self.config.drift_threshold);
+                let target = self.target_share(&self.target(position), &self.value());
+                Allocation {
+                    position: position.to_string(),
+                    share,
+                    target,
+                    drifted: (share - target).abs() > drift_threshold,
+                }
+            })
+            .collect()
+    }
+
+    /// Import transactions of all position sources into `history`. Returns number of new ones.
+    ///
+    /// Each source is asked for transactions since `since`, or since the latest transaction
+    /// already imported from it.
+    pub async fn import_history(
+        &self,
+        encryption_key: &Secret,
+        history: &mut History,
+        since: Option<DateTime<Utc>>,
+    ) -> Result<usize, error::PortfolioReadError> {
+        let mut added = 0;
+        for source_config in self.sources() {
+            let session_id = source_config.session_id();
+            let mut source = source_config.open(self.config.endpoint(source_config))?;
+            source.connect().await?;
+            source.authenticate(encryption_key).await?;
+            let transactions = source
+                .transactions(since.or(history.last_time(&session_id)))
+                .await;
+            source.disconnect().await?;
+            added += history.merge(&session_id, transactions?);
+        }
+        Ok(added)
+    }
+
+    /// Add unallocated holdings of `loaded` portfolio as positions with target `0`.
+    ///
+    /// Only holding `symbol` is adopted if given. Returns symbols of adopted positions.
+    pub fn adopt(&mut self, loaded: &Portfolio, symbol: Option<&str>) -> Vec<String> {
+        let mut adopted = vec![];
+        for position in &loaded.positions {
+            if position.automatic != Some(Automatic::Unallocated)
+                || symbol.is_some_and(|symbol| symbol != position.ticker)
+            {
+                continue;
+            }
+            self.positions.push(Position {
+                name: position.name.clone(),
+                group: position.group.clone(),
+                ticker: position.ticker.clone(),
+                amount: None,
+                target: Target::Share(0.0),
+                isin: None,
+                symbols: BTreeMap::new(),
+                automatic: None,
+                drift_threshold: None,
+                quantity: None,
+                quote: None,
+                bonds: vec![],
+                deposit: None,
+                kind: PositionKind::Investable,
+                loan: None,
+                held: vec![],
+                price_date: None,
+                redemption: None,
+            });
+            adopted.push(position.ticker.clone());
+        }
+        adopted
+    }
+
+    /// CSV export layout named `name` in config.
+    pub fn csv_format(&self, name: &str) -> Option<&CsvFormat> {
+        self.config.csv_formats.get(name)
+    }
+
+    /// Set amounts of positions of group `group_id` to `holdings` of a snapshot.
+    ///
+    /// Positions of the group missing from the snapshot are no longer held, so their amount is
+    /// set to 0. Returns changed amounts and symbols of holdings not held as any position.
+    pub fn import_snapshot(
+        &mut self,
+        group_id: &str,
+        holdings: &[Holding],
+    ) -> Result<(Vec<AmountChange>, Vec<String>), error::ImportError> {
+        let group = self
+            .groups
+            .iter()
+            .find(|group| group.id == group_id)
+            .ok_or(error::ImportError::UnknownGroup(group_id.to_string()))?;
+        // Amounts of groups with a source are read from it on every load
+        if group.source.is_some() {
+            return Err(error::ImportError::SourceGroup(group_id.to_string()));
+        }
+        let mut held = vec![false; holdings.len()];
+        let mut changes = vec![];
+        for position in &mut self.positions {
+            if position.group != group_id || position.automatic.is_some() {
+                continue;
+            }
+            let currency = position
+                .amount
+                .as_ref()
+                .map_or(group.currency, |amount| amount.currency);
+            let mut amount: Option<Amount> = None;
+            for (index, holding) in holdings.iter().enumerate() {
+                if !position.holds(group_id, holding) {
+                    continue;
+                }
+                if held[index] {
+                    return Err(error::ImportError::DuplicateSymbol(holding.symbol.clone()));
+                }
+                held[index] = true;
+                amount = match amount {
+                    None => Some(holding.market_value.clone()),
+                    Some(amount) if amount.currency == holding.market_value.currency => {
+                        Some(amount + holding.market_value.clone())
+                    }
+                    Some(_) => {
+                        return Err(error::ImportError::MixedCurrencies(position.name.clone()))
+                    }
+                };
+            }
+            let amount = amount.unwrap_or(Amount::new(currency, 0.0));
+            if position.amount.as_ref() != Some(&amount) {
+                changes.push(AmountChange {
+                    position: format!("[{:8.8}] {}", position.ticker, position.name),
+                    old: position.amount.replace(amount.clone()),
+                    new: amount,
+                });
+            }
+        }
+        let unmatched = holdings
+            .iter()
+            .zip(held)
+            .filter(|(_, held)| !held)
+            .map(|(holding, _)| holding.symbol.clone())
+            .collect();
+        Ok((changes, unmatched))
+    }
+
+    /// Write portfolio to `filename`, encrypting the whole file if `encryption_key` is given.
+    pub async fn to_file(
+        &self,
+        filename: &str,
+        encryption_key: Option<&Secret>,
+    ) -> Result<String, error::PortfolioWriteError> {
+        Self::write_contents(filename, &serde_yaml::to_string(&self)?, encryption_key)?;
+
+        Ok(filename.to_string())
+    }
+
+    /// Write portfolio to `filename` and its transaction `history` next to it, both or neither.
+    ///
+    /// Both files are encrypted with the same key, so they must never be left with different ones.
+    pub async fn to_file_with_history(
+        &self,
+        filename: &str,
+        history: &History,
+        encryption_key: Option<&Secret>,
+    ) -> Result<String, error::PortfolioWriteError> {
+        Self::write_all_contents(
+            &[
+                (filename, serde_yaml::to_string(&self)?),
+                (&History::path(filename), serde_yaml::to_string(history)?),
+            ],
+            encryption_key,
+        )?;
+
+        Ok(filename.to_string())
+    }
+
+    /// Re-encrypt all credentials stored in the portfolio with `new_key`.
+    ///
+    /// Every secret is first decrypted with `old_key`, so a wrong old key fails before anything
+    /// is changed.
+    pub fn change_key(
+        &mut self,
+        old_key: &Secret,
+        new_key: &Secret,
+    ) -> Result<(), error::PortfolioWriteError> {
+        let mut sources = vec![];
+        for group in &self.groups {
+            if let Some(source) = &group.source {
+                sources.push(Some(source.change_key(old_key, new_key)?));
+            } else {
+                sources.push(None);
+            }
+        }
+        for (group, source) in self.groups.iter_mut().zip(sources) {
+            group.source = source;
+        }
+        Ok(())
+    }
+
+    /// Check whether portfolio file is stored as an encrypted container.
+    pub fn is_encrypted_file(filename: &str) -> Result<bool, error::PortfolioReadError> {
+        Ok(crypt::is_encrypted_container(&std::fs::read_to_string(
+            filename,
+        )?))
+    }
+
+    /// Read raw YAML contents of portfolio file, transparently decrypting encrypted containers.
+    pub fn read_contents(
+        filename: &str,
+        encryption_key: &Secret,
+    ) -> Result<String, error::PortfolioReadError> {
+        let contents = std::fs::read_to_string(filename)?;
+        if crypt::is_encrypted_container(&contents) {
+            Ok(crypt::decrypt_container(
+                &contents,
+                encryption_key.expose(),
+            )?)
+        } else {
+            Ok(contents)
+        }
+    }
+
+    /// Write raw YAML contents of portfolio file, encrypting it if `encryption_key` is given.
+    ///
+    /// Contents are written to a temporary file next to `filename` first and then moved in place,
+    /// so the portfolio file is never left half-written.
+    pub fn write_contents(
+        filename: &str,
+        contents: &str,
+        encryption_key: Option<&Secret>,
+    ) -> Result<(), error::PortfolioWriteError> {
+        Self::write_all_contents(&[(filename, contents.to_string())], encryption_key)
+    }
+
+    /// Write raw contents of several files, encrypting them if `encryption_key` is given.
+    ///
+    /// All files are written to temporary files first and only then moved in place. If moving
+    /// any of them fails, files moved before it are restored, so either all files are written
+    /// or none.
+    pub fn write_all_contents(
+        files: &[(&str, String)],
+        encryption_key: Option<&Secret>,
+    ) -> Result<(), error::PortfolioWriteError> {
+        let mut staged = vec![];
+        for (filename, contents) in files {
+            let contents = match encryption_key {
+                Some(key) => crypt::encrypt_container(contents, key.expose())?,
+                None => contents.to_string(),
+            };
+            let path = Path::new(filename);
+            staged.push((path, stage_file(path, contents.as_bytes())?));
+        }
+
+        let mut written: Vec<(&Path, Option<Vec<u8>>)> = vec![];
+        for (path, file) in staged {
+            let original = std::fs::read(path).ok();
+            if let Err(e) = file.persist(path) {
+                for (path, original) in written.into_iter().rev() {
+                    let restored = match original {
+                        Some(original) => stage_file(path, &original)
+                            .and_then(|file| file.persist(path).map_err(|e| e.error))
+                            .map(|_| ()),
+                        None => std::fs::remove_file(path),
+                    };
+                    if let Err(restore_error) = restored {
+                        log::error!("Unable to restore {}: {}", path.display(), restore_error);
+                    }
+                }
+                return Err(e.error.into());
+            }
+            written.push((path, original));
+        }
+
+        Ok(())
+    }
+
+    /// Total value of investable positions, in `currency`.
+    fn total_value(&self, currency: Currency) -> Amount {
+        self.sum_value(currency, |position| position.kind.is_investable())
+    }
+
+    /// Investable positions plus assets less liabilities, in base currency.
+    pub fn net_worth(&self) -> Amount {
+        self.sum_value(self.config.base_currency, |_| true)
+    }
+
+    fn sum_value(&self, currency: Currency, counted: impl Fn(&Position) -> bool) -> Amount {
+        let mut amount = Amount {
+            currency,
+            value: 0.0,
+        };
+
+        for position in self.positions.iter().filter(|position| counted(position)) {
+            amount.value += self.rates.convert(
+                position.amount.clone().unwrap().currency,
+                currency,
+                position.amount.clone().unwrap().value,
+            );
+        }
+        amount
+    }
+
+    /// Session of the position source of `group_id`, if the group has one.
+    fn source_session(&self, group_id: &str) -> Option<String> {
+        self.groups
+            .iter()
+            .find(|group| group.id == group_id)
+            .and_then(|group| group.source.as_ref())
+            .map(|source| source.session_id())
+    }
+
+    /// Target of `position` effective today, by glide path if it gives one.
+    fn target(&self, position: &Position) -> Target {
+        self.config
+            .glide_path
+            .as_ref()
+            .and_then(|glide_path| glide_path.target(&position.ticker, Local::now().date_naive()))
+            .map_or(position.target.clone(), Target::Share)
+    }
+
+    /// Glide path of targets, if configured.
+    pub fn glide_path(&self) -> Option<&GlidePath> {
+        self.config.glide_path.as_ref()
+    }
+
+    /// Currency of group `group_id`, if there is such group.
+    fn group_currency(&self, group_id: &str) -> Option<Currency> {
+        self.groups
+            .iter()
+            .find(|group| group.id == group_id)
+            .map(|group| group.currency)
+    }
+
+    /// Share of investable positions worth `total` targeted by `target`.
+    ///
+    /// Fixed amounts are shares of `total`, other shares are of what is left after them.
+    fn target_share(&self, target: &Target, total: &Amount) -> f64 {
+        let fixed_share = |fixed: Amount| fixed.div(total, &self.rates).min(1.0);
+        match (target.share(), target.fixed()) {
+            (Some(share), _) => {
+                let fixed_total: f64 = self
+                    .positions
+                    .iter()
+                    .filter(|position| position.kind.is_investable())
+                    .filter_map(|position| self.target(position).fixed())
+                    .map(fixed_share)
+                    .sum();
+                share * (1.0 - fixed_total.min(1.0))
+            }
+            (None, Some(fixed)) => fixed_share(fixed),
+            (None, None) => 0.0,
+        }
+    }
+
+    /// Balance portfolio to given investment
+    /// Returns a list of changes to be made to the portfolio
+    ///
+    /// Positions with fixed-amount targets are filled up to their amount first, by priority, as
+    /// long as the investment lasts. What is left is invested in the other positions by share.
+    ///
+    /// Cash held at position sources may be spent (negative change) on positions of groups using
+    /// the same source, before new money is invested.
+    pub fn balance(&self, investment: Amount) -> Result<ChangeRequest, error::PortfolioOpsError> {
+        // Assets and liabilities are never bought or sold
+        let positions: Vec<&Position> = self
+            .positions
+            .iter()
+            .filter(|position| position.kind.is_investable())
+            .collect();
+
+        /* Fill fixed amounts */
+        let mut fixed: Vec<(usize, Amount)> = positions
+            .iter()
+            .enumerate()
+            .filter_map(|(index, position)| Some((index, self.target(position).fixed()?)))
+            .collect();
+        // Stable, so that positions of the same priority are filled in order of the file
+        fixed.sort_by_key(|(index, _)| {
+            match self.target(positions[*index]) {
+                Target::Fixed(fixed) => fixed.priority,
+                Target::Share(_) => None,
+            }
+            .unwrap_or(u32::MAX)
+        });
+        let mut remaining = investment.value;
+        let mut fills = HashMap::new();
+        for (index, target) in fixed {
+            let amount = positions[index].amount.clone().unwrap();
+            let missing = target.value
+                - self
+                    .rates
+                    .convert(amount.currency, target.currency, amount.value);
+            let missing = self
+                .rates
+                .convert(target.currency, investment.currency, missing)
+                .max(0.0);
+            let fill = missing.min(remaining);
+            remaining -= fill;
+            fills.insert(index, fill);
+        }
+
+        /* Invest the rest by share */
+        let shared: Vec<Position> = positions
+            .iter()
+            .enumerate()
+            .filter(|(index, _)| !fills.contains_key(index))
+            .map(|(_, position)| (*position).clone())
+            .collect();
+        // Automatic positions are never bought, so without any other position there is nothing
+        // the solver could put the rest in
+        let mut shared_changes = if shared.iter().all(|position| position.automatic.is_some()) {
+            if remaining >= 0.01 {
+                return Err(error::PortfolioOpsError::Unassigned(
+                    remaining,
+                    investment.currency,
+                ));
+            }
+            shared
+                .into_iter()
+                .map(|position| {
+                    let currency = position.amount.clone().unwrap().currency;
+                    PositionChange {
+                        position,
+                        amount: Amount::new(currency, 0.0),
+                    }
+                })
+                .collect::<Vec<_>>()
+        } else {
+            self.balance_shares(shared, Amount::new(investment.currency, remaining))?
+        }
+        .into_iter();
+
+        let changes = positions
+            .iter()
+            .enumerate()
+            .map(|(index, position)| match fills.get(&index) {
+                Some(fill) => {
+                    let currency = position.amount.clone().unwrap().currency;
+                    PositionChange {
+                        position: (*position).clone(),
+                        amount: Amount::new(
+                            currency,
+                            self.rates.convert(investment.currency, currency, *fill),
+                        ),
+                    }
+                }
+                None => shared_changes.next().unwrap(),
+            })
+            .collect();
+
+        Ok(ChangeRequest {
+            changes,
+            currency: investment.currency,
+        })
+    }
+
+    /// Invest `investment` in `positions` so that their shares get closest to their targets.
+    fn balance_shares(
+        &self,
+        positions: Vec<Position>,
+        investment: Amount,
+    ) -> Result<Vec<PositionChange>, error::PortfolioOpsError> {
+        let mut problem_variables = good_lp::ProblemVariables::new();
+
+        let current_portfolio_value: f64 = positions
+            .iter()
+            .map(|position| {
+                let amount = position.amount.clone().unwrap();
+                self.rates
+                    .convert(amount.currency, investment.currency, amount.value)
+            })
+            .sum();
+        let position_value = |position: &Position| {
+            let amount = position.amount.clone().unwrap();
+            self.rates
+                .convert(amount.currency, investment.currency, amount.value)
+        };
+        let spendable_cash: f64 = positions
+            .iter()
+            .filter(|position| position.cash_session().is_some())
+            .map(|position| position_value(position).max(0.0))
+            .sum();
+        let mut per_position_investments = vec![];
+        for position in &positions {
+            let variable = match position.automatic {
+                // Negative cash (e.g. owed margin) can't be spent
+                Some(Automatic::Cash(_)) => good_lp::variable()
+                    .min((-position_value(position)).min(0.0))
+                    .max(0),
+                // Unallocated holdings are not part of the plan
+                Some(Automatic::Unallocated) => good_lp::variable().min(0).max(0),
+                None => good_lp::variable()
+                    .min(0)
+                    .max(investment.value + spendable_cash),
+            };
+            per_position_investments.push(problem_variables.add(variable))
+        }
+
+        let total_investment: Expression = per_position_investments.iter().sum();
+        let new_portfolio_value = investment.value + current_portfolio_value;
+
+        let mut total_objective: Expression = 0.into();
+
+        let objectives: Vec<_> = positions
+            .clone()
+            .into_iter()
+            .zip(per_position_investments.clone())
+            .map(|(position, position_investment)| {
+                // this position value in investment currency
+                let position_value = self.rates.convert(
+                    position.amount.clone().unwrap().currency,
+                    investment.currency,
+                    position.amount.clone().unwrap().value,
+                );
+
+                // Objective for specific position - minimize the imbalance
+                let target = self.target(&position).share().unwrap_or_default();
+                let mut position_objective =
+                    ((position_value + position_investment) / new_portfolio_value) - target;
+
+                // If current share < target share, negate the objective (approaching from below 0)
+                let current_share = position_value / current_portfolio_value;
+                if current_share < target {
+                    position_objective = -position_objective;
+                }
+
+                // Add this position objective to total objective
+                total_objective += position_objective.clone();
+                position_objective
             })
             .collect();
 
@@ -473,18 +1833,34 @@ impl Portfolio {
             problem = problem.with(constraint!(this.clone() >= 0.0));
         }
 
+        // Constraint cash spent at each source to what is invested in groups of the same source
+        for (cash_position, &cash_spent) in positions.iter().zip(&per_position_investments) {
+            if let Some(session_id) = cash_position.cash_session() {
+                let invested_at_source: Expression = positions
+                    .iter()
+                    .zip(&per_position_investments)
+                    .filter(|(position, _)| {
+                        position.automatic.is_none()
+                            && self.source_session(&position.group).as_ref() == Some(session_id)
+                    })
+                    .map(|(_, &variable)| variable)
+                    .sum();
+                problem = problem.with(constraint!(-cash_spent <= invested_at_source));
+            }
+        }
+
         // Solve
         let solution = problem.solve()?;
 
-        let changes: Vec<_> = self
-            .positions
+        let changes: Vec<_> = positions
             .clone()
             .into_iter()
-            .zip(per_position_investments.into_iter())
+            .zip(per_position_investments)
             .map(|(position, variable)| {
-                let new_value = solution.value(variable.clone());
+                let new_value = solution.value(variable);
                 let position_currency = position.clone().amount.unwrap().currency;
-                let position_change = PositionChange {
+
+                PositionChange {
                     position: position.clone(),
                     amount: Amount {
                         currency: position.amount.unwrap().currency,
@@ -494,18 +1870,32 @@ impl Portfolio {
                             new_value,
                         ),
                     },
-                };
-                position_change
+                }
             })
             .collect();
 
-        Ok(ChangeRequest { changes })
+        Ok(changes)
     }
 }
 
+/// Temporary file next to `path` holding `contents`, with permissions of `path` if it exists.
+fn stage_file(path: &Path, contents: &[u8]) -> std::io::Result<tempfile::NamedTempFile> {
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    let mut file = tempfile::NamedTempFile::new_in(dir)?;
+    std::io::Write::write_all(&mut file, contents)?;
+    if let Ok(metadata) = std::fs::metadata(path) {
+        file.as_file().set_permissions(metadata.permissions())?;
+    }
+    Ok(file)
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::testing::PortfolioFile;
 
     fn mock_rates() -> Rates {
         Rates {
@@ -526,7 +1916,7 @@ mod test {
         let rates = mock_rates();
 
         let mut portfolio = Portfolio {
-            rates: rates,
+            rates,
             config: Config::default(),
             groups: vec![
                 Group::new("TEST1".to_string(), Currency::USD),
@@ -543,7 +1933,8 @@ mod test {
                 currency: Currency::USD,
                 value: 100.0,
             }),
-            target: 0.5,
+            target: Target::Share(0.5),
+            ..Default::default()
         });
         portfolio.positions.push(Position {
             name: "Test".to_string(),
@@ -553,7 +1944,8 @@ mod test {
                 currency: Currency::EUR,
                 value: 100.0,
             }),
-            target: 0.5,
+            target: Target::Share(0.5),
+            ..Default::default()
         });
         assert_eq!(
             portfolio.total_value(Currency::USD),
@@ -574,7 +1966,7 @@ mod test {
                 Group::new("TEST1".to_string(), Currency::USD),
                 Group::new("TEST2".to_string(), Currency::EUR),
             ],
-            rates: rates,
+            rates,
             positions: vec![
                 Position {
                     name: "Test 1".to_string(),
@@ -584,7 +1976,8 @@ mod test {
                         currency: Currency::USD,
                         value: 0.0,
                     }),
-                    target: 0.3,
+                    target: Target::Share(0.3),
+                    ..Default::default()
                 },
                 Position {
                     name: "Test 2".to_string(),
@@ -594,7 +1987,8 @@ mod test {
                         currency: Currency::EUR,
                         value: 0.0,
                     }),
-                    target: 0.7,
+                    target: Target::Share(0.7),
+                    ..Default::default()
                 },
             ],
         };
@@ -616,7 +2010,8 @@ mod test {
                             currency: Currency::USD,
                             value: 0.0,
                         }),
-                        target: 0.3,
+                        target: Target::Share(0.3),
+                        ..Default::default()
                     },
                     amount: Amount {
                         currency: Currency::USD,
@@ -632,7 +2027,8 @@ mod test {
                             currency: Currency::EUR,
                             value: 0.0,
                         }),
-                        target: 0.7,
+                        target: Target::Share(0.7),
+                        ..Default::default()
                     },
                     amount: Amount {
                         currency: Currency::EUR,
@@ -652,7 +2048,7 @@ mod test {
         let portfolio = Portfolio {
             config: Config::default(),
             groups: vec![Group::new("TEST1".to_string(), Currency::USD)],
-            rates: rates,
+            rates,
             positions: vec![
                 Position {
                     name: "Test 1".to_string(),
@@ -662,7 +2058,8 @@ mod test {
                         currency: Currency::USD,
                         value: 500.0,
                     }),
-                    target: 0.5,
+                    target: Target::Share(0.5),
+                    ..Default::default()
                 },
                 Position {
                     name: "Test 2".to_string(),
@@ -672,7 +2069,8 @@ mod test {
                         currency: Currency::USD,
                         value: 500.0,
                     }),
-                    target: 0.5,
+                    target: Target::Share(0.5),
+                    ..Default::default()
                 },
             ],
         };
@@ -694,7 +2092,8 @@ mod test {
                             currency: Currency::USD,
                             value: 500.0,
                         }),
-                        target: 0.5,
+                        target: Target::Share(0.5),
+                        ..Default::default()
                     },
                     amount: Amount {
                         currency: Currency::USD,
@@ -710,7 +2109,8 @@ mod test {
                             currency: Currency::USD,
                             value: 500.0,
                         }),
-                        target: 0.5,
+                        target: Target::Share(0.5),
+                        ..Default::default()
                     },
                     amount: Amount {
                         currency: Currency::USD,
@@ -721,6 +2121,198 @@ mod test {
         );
     }
 
+    #[test]
+    fn test_balance_fixed_targets() {
+        let mut portfolio: Portfolio = serde_yaml::from_str(
+            r#"
+config:
+  base_currency: PLN
+groups:
+- id: pln
+  currency: PLN
+positions:
+- name: Stocks
+  group: pln
+  ticker: STOCKS
+  amount:
+    currency: PLN
+    value: 6000.0
+  target: 0.6
+- name: Holiday
+  group: pln
+  ticker: HOLIDAY
+  amount:
+    currency: PLN
+    value: 0.0
+  target:
+    currency: PLN
+    value: 3000.0
+    priority: 2
+- name: Emergency fund
+  group: pln
+  ticker: EMERGENCY
+  amount:
+    currency: PLN
+    value: 15000.0
+  target:
+    currency: PLN
+    value: 20000.0
+    priority: 1
+- name: Bonds
+  group: pln
+  ticker: BONDS
+  amount:
+    currency: PLN
+    value: 4000.0
+  target: 0.4
+"#,
+        )
+        .unwrap();
+        portfolio.rates = mock_rates();
+        let changes = |investment: f64| -> Vec<f64> {
+            portfolio
+                .balance(Amount::new(Currency::PLN, investment))
+                .unwrap()
+                .changes
+                .iter()
+                .map(|change| change.amount.value)
+                .collect()
+        };
+
+        // Emergency fund is filled first, then holiday, and the rest goes by shares
+        let invested = changes(10000.0);
+        let expected = [1200.0, 3000.0, 5000.0, 800.0];
+        for (invested, expected) in invested.iter().zip(expected) {
+            assert!((invested - expected).abs() < 0.01, "{:?}", invested);
+        }
+        let invested = changes(6000.0);
+        let expected = [0.0, 1000.0, 5000.0, 0.0];
+        for (invested, expected) in invested.iter().zip(expected) {
+            assert!((invested - expected).abs() < 0.01, "{:?}", invested);
+        }
+
+        // Shares are of what is left after fixed amounts: 23000 of 25000 is fixed
+        let allocation = portfolio.allocation();
+        assert!((allocation[0].target - 0.6 * 0.08).abs() < 1e-9);
+        assert!((allocation[2].target - 0.8).abs() < 1e-9);
+        assert!(serde_yaml::to_string(&portfolio)
+            .unwrap()
+            .contains("priority: 1"));
+    }
+
+    #[test]
+    fn test_balance_fixed_targets_only() {
+        let mut portfolio: Portfolio = serde_yaml::from_str(
+            r#"
+config:
+  base_currency: PLN
+groups:
+- id: pln
+  currency: PLN
+positions:
+- name: Holiday
+  group: pln
+  ticker: HOLIDAY
+  amount:
+    currency: PLN
+    value: 1000.0
+  target:
+    currency: PLN
+    value: 3000.0
+- name: Emergency fund
+  group: pln
+  ticker: EMERGENCY
+  amount:
+    currency: PLN
+    value: 20000.0
+  target:
+    currency: PLN
+    value: 20000.0
+"#,
+        )
+        .unwrap();
+        portfolio.rates = mock_rates();
+
+        let balanced = portfolio
+            .balance(Amount::new(Currency::PLN, 1500.0))
+            .unwrap();
+        let invested: Vec<f64> = balanced
+            .changes
+            .iter()
+            .map(|change| change.amount.value)
+            .collect();
+        assert_eq!(invested, vec![1500.0, 0.0]);
+
+        // Only 2000 is missing, and no position takes the rest by share
+        let result = portfolio.balance(Amount::new(Currency::PLN, 2500.0));
+        assert!(matches!(
+            result,
+            Err(error::PortfolioOpsError::Unassigned(remaining, Currency::PLN))
+                if (remaining - 500.0).abs() < 0.01
+        ));
+    }
+
+    #[test]
+    fn test_balance_glide_path() {
+        let today = Local::now().date_naive();
+        let mut portfolio: Portfolio = serde_yaml::from_str(&format!(
+            r#"
+config:
+  base_currency: PLN
+  glide_path:
+    interpolate: true
+    anchors:
+    - date: {}
+      targets:
+        STOCKS: 0.8
+        BONDS: 0.2
+    - date: {}
+      targets:
+        STOCKS: 0.4
+        BONDS: 0.6
+groups:
+- id: pln
+  currency: PLN
+positions:
+- name: Stocks
+  group: pln
+  ticker: STOCKS
+  amount:
+    currency: PLN
+    value: 600.0
+  target: 1.0
+- name: Bonds
+  group: pln
+  ticker: BONDS
+  amount:
+    currency: PLN
+    value: 400.0
+  target: 0.0
+"#,
+            today - chrono::Days::new(1000),
+            today + chrono::Days::new(1000)
+        ))
+        .unwrap();
+        portfolio.rates = mock_rates();
+
+        // Half way between anchors, targets of the file are overridden
+        let allocation = portfolio.allocation();
+        assert!((allocation[0].target - 0.6).abs() < 1e-9);
+        assert!((allocation[1].target - 0.4).abs() < 1e-9);
+        assert!(allocation.iter().all(|allocation| !allocation.drifted));
+        let changes = portfolio
+            .balance(Amount::new(Currency::PLN, 1000.0))
+            .unwrap()
+            .changes;
+        assert!((changes[0].amount.value - 600.0).abs() < 0.01);
+        assert!((changes[1].amount.value - 400.0).abs() < 0.01);
+        assert!(portfolio.to_string().contains("Targets of glide path"));
+        // Glide path targets are never saved as targets of positions
+        assert!(serde_yaml::to_string(&portfolio)
+            .unwrap()
+            .contains("target: 1.0"));
+    }
+
     #[test]
     fn test_balance_unbalancable() {
         let rates = Rates {
@@ -730,7 +2322,7 @@ mod test {
         let portfolio = Portfolio {
             config: Config::default(),
             groups: vec![Group::new("TEST1".to_string(), Currency::USD)],
-            rates: rates,
+            rates,
             positions: vec![
                 Position {
                     name: "Test 1".to_string(),
@@ -740,7 +2332,8 @@ mod test {
                         currency: Currency::USD,
                         value: 100.0,
                     }),
-                    target: 0.5,
+                    target: Target::Share(0.5),
+                    ..Default::default()
                 },
                 Position {
                     name: "Test 2".to_string(),
@@ -750,7 +2343,8 @@ mod test {
                         currency: Currency::USD,
                         value: 500.0,
                     }),
-                    target: 0.5,
+                    target: Target::Share(0.5),
+                    ..Default::default()
                 },
             ],
         };
@@ -772,7 +2366,8 @@ mod test {
                             currency: Currency::USD,
                             value: 100.0,
                         }),
-                        target: 0.5,
+                        target: Target::Share(0.5),
+                        ..Default::default()
                     },
                     amount: Amount {
                         currency: Currency::USD,
@@ -788,7 +2383,8 @@ mod test {
                             currency: Currency::USD,
                             value: 500.0,
                         }),
-                        target: 0.5,
+                        target: Target::Share(0.5),
+                        ..Default::default()
                     },
                     amount: Amount {
                         currency: Currency::USD,
@@ -798,4 +2394,1299 @@ mod test {
             ]
         );
     }
+
+    #[test]
+    fn test_balance_spends_source_cash() {
+        let xtb_source = SourceConfig::Xtb(XtbAccount::new("111".to_string(), None, None));
+        let portfolio = Portfolio {
+            config: Config::default(),
+            groups: vec![
+                Group {
+                    source: Some(xtb_source.clone()),
+                    ..Group::new("xtb".to_string(), Currency::USD)
+                },
+                Group::new("bank".to_string(), Currency::USD),
+            ],
+            rates: mock_rates(),
+            positions: vec![
+                Position {
+                    name: "Stocks".to_string(),
+                    ticker: "STOCKS".to_string(),
+                    group: "xtb".to_string(),
+                    amount: Some(Amount::new(Currency::USD, 100.0)),
+                    target: Target::Share(0.5),
+                    ..Default::default()
+                },
+                Position {
+                    name: "Deposit".to_string(),
+                    ticker: "DEPOSIT".to_string(),
+                    group: "bank".to_string(),
+                    amount: Some(Amount::new(Currency::USD, 200.0)),
+                    target: Target::Share(0.5),
+                    ..Default::default()
+                },
+                Position {
+                    name: "Cash (xtb)".to_string(),
+                    ticker: "CASH".to_string(),
+                    group: "xtb".to_string(),
+                    amount: Some(Amount::new(Currency::USD, 100.0)),
+                    target: Target::Share(0.0),
+                    automatic: Some(Automatic::Cash(xtb_source.session_id())),
+                    ..Default::default()
+                },
+            ],
+        };
+
+        let change_request = portfolio
+            .balance(Amount::new(Currency::USD, 100.0))
+            .unwrap();
+        let changes: Vec<_> = change_request
+            .changes
+            .iter()
+            .map(|change| (change.amount.value * 100.0).round() / 100.0)
+            .collect();
+        // All cash is spent at XTB, only the rest of investment needs a new deposit
+        assert_eq!(changes, vec![150.0, 50.0, -100.0]);
+        let change_per_group = change_request.change_per_group(&portfolio);
+        assert_eq!(change_per_group["xtb"].value.round(), 50.0);
+        assert_eq!(change_per_group["bank"].value.round(), 50.0);
+    }
+
+    #[test]
+    fn test_funding_plan() {
+        let portfolio = Portfolio {
+            config: Config {
+                base_currency: Currency::USD,
+                ..Config::default()
+            },
+            groups: vec![
+                Group::new("xtb_eur".to_string(), Currency::EUR),
+                Group::new("usd".to_string(), Currency::USD),
+                Group::new("gbp".to_string(), Currency::GBP),
+            ],
+            rates: mock_rates(),
+            positions: vec![],
+        };
+        let change = |group: &str, amount: Amount| PositionChange {
+            position: Position {
+                group: group.to_string(),
+                amount: Some(Amount::new(amount.currency, 0.0)),
+                ..Default::default()
+            },
+            amount,
+        };
+        let change_request = ChangeRequest {
+            changes: vec![
+                // USD listed position held at EUR account
+                change("xtb_eur", Amount::new(Currency::USD, 120.0)),
+                change("xtb_eur", Amount::new(Currency::EUR, 50.0)),
+                change("usd", Amount::new(Currency::USD, 200.0)),
+                change("usd", Amount::new(Currency::USD, -300.0)),
+                change("gbp", Amount::new(Currency::GBP, 130.0)),
+            ],
+            currency: Currency::USD,
+        };
+
+        let change_per_group = change_request.change_per_group(&portfolio);
+        assert_eq!(
+            change_per_group["xtb_eur"],
+            Amount::new(Currency::EUR, 150.0)
+        );
+        assert_eq!(change_per_group["usd"], Amount::new(Currency::USD, -100.0));
+        assert_eq!(change_per_group["gbp"], Amount::new(Currency::GBP, 130.0));
+
+        // Cash left at the USD group needs no transfer
+        let plan = change_request.funding_plan(&portfolio);
+        assert_eq!(
+            plan.exchanges,
+            vec![
+                Exchange {
+                    from: Amount::new(Currency::USD, 180.0),
+                    to: Amount::new(Currency::EUR, 150.0),
+                },
+                Exchange {
+                    from: Amount::new(Currency::USD, 169.0),
+                    to: Amount::new(Currency::GBP, 130.0),
+                },
+            ]
+        );
+        assert_eq!(
+            plan.transfers,
+            vec![
+                Transfer {
+                    group: "gbp".to_string(),
+                    amount: Amount::new(Currency::GBP, 130.0),
+                },
+                Transfer {
+                    group: "xtb_eur".to_string(),
+                    amount: Amount::new(Currency::EUR, 150.0),
+                },
+            ]
+        );
+        assert!(change_request
+            .format(&portfolio)
+            .contains("- Exchange    180.00 USD to    150.00 EUR"));
+    }
+
+    #[test]
+    fn test_funding_plan_shared_source_cash() {
+        let xtb_source = SourceConfig::Xtb(XtbAccount::new("111".to_string(), None, None));
+        let portfolio = Portfolio {
+            config: Config::default(),
+            groups: vec![
+                Group {
+                    source: Some(xtb_source.clone()),
+                    ..Group::new("stocks".to_string(), Currency::USD)
+                },
+                Group {
+                    source: Some(xtb_source.clone()),
+                    ..Group::new("bonds".to_string(), Currency::USD)
+                },
+            ],
+            rates: mock_rates(),
+            positions: vec![],
+        };
+        let change = |group: &str, value: f64, automatic: Option<Automatic>| PositionChange {
+            position: Position {
+                group: group.to_string(),
+                amount: Some(Amount::new(Currency::USD, 0.0)),
+                automatic,
+                ..Default::default()
+            },
+            amount: Amount::new(Currency::USD, value),
+        };
+        // Account cash is shown in the first group of the account
+        let change_request = ChangeRequest {
+            changes: vec![
+                change("stocks", 30.0, None),
+                change("bonds", 100.0, None),
+                change(
+                    "stocks",
+                    -100.0,
+                    Some(Automatic::Cash(xtb_source.session_id())),
+                ),
+            ],
+            currency: Currency::USD,
+        };
+
+        let change_per_group = change_request.change_per_group(&portfolio);
+        assert_eq!(
+            change_per_group["stocks"],
+            Amount::new(Currency::USD, -70.0)
+        );
+        assert_eq!(change_per_group["bonds"], Amount::new(Currency::USD, 100.0));
+
+        // Account cash covers 100 of 130 bought in both groups, only the rest is transferred
+        let plan = change_request.funding_plan(&portfolio);
+        assert_eq!(plan.exchanges, vec![]);
+        assert_eq!(
+            plan.transfers,
+            vec![Transfer {
+                group: "stocks".to_string(),
+                amount: Amount::new(Currency::USD, 30.0),
+            }]
+        );
+    }
+
+    #[test]
+    fn test_balance_cash_stays_at_source() {
+        let xtb_source = SourceConfig::Xtb(XtbAccount::new("111".to_string(), None, None));
+        let portfolio = Portfolio {
+            config: Config::default(),
+            groups: vec![
+                Group {
+                    source: Some(xtb_source.clone()),
+                    ..Group::new("xtb".to_string(), Currency::USD)
+                },
+                Group::new("bank".to_string(), Currency::USD),
+            ],
+            rates: mock_rates(),
+            positions: vec![
+                Position {
+                    name: "Deposit".to_string(),
+                    ticker: "DEPOSIT".to_string(),
+                    group: "bank".to_string(),
+                    amount: Some(Amount::new(Currency::USD, 0.0)),
+                    target: Target::Share(1.0),
+                    ..Default::default()
+                },
+                Position {
+                    name: "Cash (xtb)".to_string(),
+                    ticker: "CASH".to_string(),
+                    group: "xtb".to_string(),
+                    amount: Some(Amount::new(Currency::USD, 100.0)),
+                    target: Target::Share(0.0),
+                    automatic: Some(Automatic::Cash(xtb_source.session_id())),
+                    ..Default::default()
+                },
+            ],
+        };
+
+        // Cash at XTB can't be moved to the bank
+        let change_request = portfolio
+            .balance(Amount::new(Currency::USD, 100.0))
+            .unwrap();
+        let changes: Vec<_> = change_request
+            .changes
+            .iter()
+            .map(|change| change.amount.value.round())
+            .collect();
+        assert_eq!(changes, vec![100.0, 0.0]);
+    }
+
+    #[test]
+    fn test_balance_negative_source_cash() {
+        let xtb_source = SourceConfig::Xtb(XtbAccount::new("111".to_string(), None, None));
+        let portfolio = Portfolio {
+            config: Config::default(),
+            groups: vec![Group {
+                source: Some(xtb_source.clone()),
+                ..Group::new("xtb".to_string(), Currency::USD)
+            }],
+            rates: mock_rates(),
+            positions: vec![
+                Position {
+                    name: "Stocks".to_string(),
+                    ticker: "STOCKS".to_string(),
+                    group: "xtb".to_string(),
+                    amount: Some(Amount::new(Currency::USD, 300.0)),
+                    target: Target::Share(1.0),
+                    ..Default::default()
+                },
+                Position {
+                    name: "Cash (xtb)".to_string(),
+                    ticker: "CASH".to_string(),
+                    group: "xtb".to_string(),
+                    amount: Some(Amount::new(Currency::USD, -50.0)),
+                    target: Target::Share(0.0),
+                    automatic: Some(Automatic::Cash(xtb_source.session_id())),
+                    ..Default::default()
+                },
+            ],
+        };
+
+        // Debit at XTB is left as it is, whole investment is new money
+        let change_request = portfolio
+            .balance(Amount::new(Currency::USD, 100.0))
+            .unwrap();
+        let changes: Vec<_> = change_request
+            .changes
+            .iter()
+            .map(|change| change.amount.value.round())
+            .collect();
+        assert_eq!(changes, vec![100.0, 0.0]);
+    }
+
+    #[test]
+    fn test_encrypted_contents_roundtrip() {
+        let file = PortfolioFile::new();
+        let filename = file.path();
+        let contents = serde_yaml::to_string(&Portfolio::example(None, None)).unwrap();
+
+        let key = Secret::from("key");
+        Portfolio::write_contents(filename, &contents, Some(&key)).unwrap();
+        assert!(Portfolio::is_encrypted_file(filename).unwrap());
+        assert!(!std::fs::read_to_string(filename)
+            .unwrap()
+            .contains("base_currency"));
+        assert_eq!(Portfolio::read_contents(filename, &key).unwrap(), contents);
+
+        Portfolio::write_contents(filename, &contents, None).unwrap();
+        assert!(!Portfolio::is_encrypted_file(filename).unwrap());
+        assert_eq!(
+            Portfolio::read_contents(filename, &Secret::from("")).unwrap(),
+            contents
+        );
+    }
+
+    #[tokio::test]
+    async fn test_change_key_with_history() {
+        let dir = tempfile::tempdir().unwrap();
+        let filename = dir.path().join("portfolio.yaml");
+        let filename = filename.to_str().unwrap();
+        let history_file = History::path(filename);
+        let (old_key, new_key) = (Secret::from("old"), Secret::from("new"));
+        let portfolio = Portfolio::example(None, None);
+        let mut history = History::default();
+        history.merge(
+            "xtb:111",
+            vec![crate::history::Transaction {
+                order: "1".to_string(),
+                kind: crate::history::TransactionKind::Deposit,
+                time: "2024-01-01T10:00:00Z".parse().unwrap(),
+                symbol: None,
+                volume: None,
+                price: None,
+                amount: Amount::new(Currency::USD, 500.0),
+            }],
+        );
+        portfolio
+            .to_file_with_history(filename, &history, Some(&old_key))
+            .await
+            .unwrap();
+
+        portfolio
+            .to_file_with_history(filename, &history, Some(&new_key))
+            .await
+            .unwrap();
+        assert!(Portfolio::read_contents(filename, &new_key).is_ok());
+        assert_eq!(
+            History::from_file(&history_file, &new_key).unwrap(),
+            history
+        );
+        assert!(History::from_file(&history_file, &old_key).is_err());
+
+        // History can't be moved in place, so portfolio is put back under the key it had
+        std::fs::remove_file(&history_file).unwrap();
+        std::fs::create_dir(&history_file).unwrap();
+        std::fs::write(Path::new(&history_file).join("file"), "").unwrap();
+        assert!(portfolio
+            .to_file_with_history(filename, &history, Some(&old_key))
+            .await
+            .is_err());
+        assert!(Portfolio::read_contents(filename, &new_key).is_ok());
+        assert!(Portfolio::read_contents(filename, &old_key).is_err());
+    }
+
+    #[test]
+    fn test_use_xtb_server() {
+        let portfolio = |xtb: &str| -> Portfolio {
+            serde_yaml::from_str(&format!(
+                "config:\n  base_currency: USD\n  xtb: {}\ngroups: []\npositions: []\n",
+                xtb
+            ))
+            .unwrap()
+        };
+
+        let mut preset = portfolio("{server: demo}");
+        preset.use_xtb_server(XtbServer::Real);
+        assert_eq!(preset.config.xtb.unwrap().server(), Some(XtbServer::Real));
+
+        // Custom host stays custom, so execute still needs --custom-endpoints to trade there
+        let mut custom = portfolio("{host: localhost, port: 6000}");
+        custom.use_xtb_server(XtbServer::Real);
+        assert_eq!(custom.config.xtb.unwrap().server(), None);
+
+        let mut demo = portfolio("{host: xapi.xtb.com, port: 5124}");
+        demo.use_xtb_server(XtbServer::Real);
+        assert_eq!(demo.config.xtb.unwrap().server(), Some(XtbServer::Demo));
+    }
+
+    #[test]
+    fn test_change_key() {
+        let (old_key, new_key) = (Secret::from("old"), Secret::from("new"));
+        let xtb_account = XtbAccount::new("123456".to_string(), None, Some("password".into()))
+            .encrypt(&old_key)
+            .unwrap();
+        let mut portfolio = Portfolio::example(None, Some(xtb_account));
+
+        assert!(portfolio.change_key(&old_key, &new_key).is_ok());
+        let Some(SourceConfig::Xtb(xtb_account)) = &portfolio.groups[0].source else {
+            panic!("XTB source expected");
+        };
+        assert_eq!(
+            xtb_account.decrypt(&new_key).unwrap().password(),
+            Some(&Secret::from("password"))
+        );
+        assert!(portfolio.groups[1].source.is_none());
+    }
+
+    #[test]
+    fn test_group_source() {
+        let group: Group = serde_yaml::from_str(
+            "id: xtb_usd\ncurrency: USD\nsource:\n  type: xtb\n  account_id: '123456'\n  encrypted_password: abc\n",
+        )
+        .unwrap();
+        assert!(matches!(group.source, Some(SourceConfig::Xtb(_))));
+
+        // Legacy XTB account is read as XTB source
+        let group: Group = serde_yaml::from_str(
+            "id: xtb_usd\ncurrency: USD\nxtb:\n  account_id: '123456'\n  encrypted_password: abc\n",
+        )
+        .unwrap();
+        assert!(matches!(group.source, Some(SourceConfig::Xtb(_))));
+        assert!(serde_yaml::to_string(&group).unwrap().contains("type: xtb"));
+
+        let group: Group = serde_yaml::from_str("id: cash\ncurrency: USD\n").unwrap();
+        assert!(group.source.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_from_file_named_endpoints() {
+        // Same account id at demo and real server
+        let demo = crate::xtb::mock::MockXtb::new()
+            .account("111", "password", "USD")
+            .position("111", "SPXS.UK", 1.0)
+            .stock("SPXS.UK", 100.0, "USD")
+            .start()
+            .await;
+        let real = crate::xtb::mock::MockXtb::new()
+            .account("111", "password", "USD")
+            .position("111", "SPXS.UK", 3.0)
+            .stock("SPXS.UK", 100.0, "USD")
+            .start()
+            .await;
+        let file = PortfolioFile::new();
+        let contents = format!(
+            r#"
+config:
+  base_currency: USD
+  endpoints:
+    demo: {demo}
+    real: {real}
+groups:
+- id: demo
+  currency: USD
+  source:
+    type: xtb
+    endpoint: demo
+    account_id: '111'
+    encrypted_password: {password}
+- id: real
+  currency: USD
+  source:
+    type: xtb
+    endpoint: real
+    account_id: '111'
+    encrypted_password: {password}
+positions:
+- name: S&P 500 (demo)
+  group: demo
+  ticker: SPXS.UK
+  target: 0.5
+- name: S&P 500
+  group: real
+  ticker: SPXS.UK
+  target: 0.5
+"#,
+            demo = demo.endpoint(),
+            real = real.endpoint(),
+            password = file.encrypt("password"),
+        );
+        file.write(&contents);
+
+        let portfolio = file.read(mock_rates()).await.unwrap();
+        let amounts: Vec<_> = portfolio
+            .positions
+            .iter()
+            .map(|position| position.amount.clone().unwrap())
+            .collect();
+        assert_eq!(
+            amounts,
+            vec![
+                Amount::new(Currency::USD, 100.0),
+                Amount::new(Currency::USD, 300.0)
+            ]
+        );
+        let sessions: Vec<_> = portfolio
+            .sources()
+            .iter()
+            .map(|source_config| source_config.session_id())
+            .collect();
+        assert_eq!(sessions, vec!["xtb:demo:111", "xtb:real:111"]);
+
+        /* Account at unknown endpoint can't be read */
+        file.write(&contents.replace("endpoint: real", "endpoint: live"));
+        assert!(matches!(
+            file.read(mock_rates()).await,
+            Err(error::PortfolioReadError::SourceError(
+                error::SourceError::NotConfigured(endpoint)
+            )) if endpoint == "live"
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_from_file_ibkr() {
+        let file = PortfolioFile::new();
+        let flex_file = file.dir().join("flex.xml");
+        std::fs::write(
+            &flex_file,
+            r#"<FlexQueryResponse queryName="portfel" type="AF">
+<FlexStatements count="1">
+<FlexStatement accountId="U1111111">
+<OpenPositions>
+<OpenPosition currency="USD" symbol="CSPX" isin="IE00B5BMR087" position="10" markPrice="500" positionValue="5000" levelOfDetail="SUMMARY" />
+<OpenPosition currency="EUR" symbol="EXSA" isin="DE0002635307" position="20" markPrice="50" positionValue="1000" levelOfDetail="SUMMARY" />
+</OpenPositions>
+<CashReport>
+<CashReportCurrency currency="BASE_SUMMARY" endingCash="200" />
+<CashReportCurrency currency="USD" endingCash="200" />
+</CashReport>
+</FlexStatement>
+</FlexStatements>
+</FlexQueryResponse>
+"#,
+        )
+        .unwrap();
+        file.write(&format!(
+            r#"
+config:
+  base_currency: USD
+groups:
+- id: ibkr
+  currency: USD
+  source:
+    type: ibkr
+    account_id: U1111111
+    flex_file: {flex_file}
+positions:
+- name: S&P 500
+  group: ibkr
+  ticker: CSPX
+  target: 0.8
+- name: Europe
+  group: ibkr
+  ticker: STOXX600
+  isin: DE0002635307
+  target: 0.2
+"#,
+            flex_file = flex_file.display(),
+        ));
+
+        let portfolio = file.read(mock_rates()).await.unwrap();
+        let amounts: Vec<_> = portfolio
+            .positions
+            .iter()
+            .map(|position| (position.ticker.as_str(), position.amount.clone().unwrap()))
+            .collect();
+        assert_eq!(
+            amounts,
+            vec![
+                ("CSPX", Amount::new(Currency::USD, 5000.0)),
+                // Matched by ISIN, converted to group currency
+                ("STOXX600", Amount::new(Currency::USD, 1200.0)),
+                ("CASH", Amount::new(Currency::USD, 200.0)),
+            ]
+        );
+        assert_eq!(portfolio.sources()[0].session_id(), "ibkr:U1111111");
+    }
+
+    #[tokio::test]
+    async fn test_from_file_leveraged_valuation() {
+        let server = crate::xtb::mock::MockXtb::new()
+            .account("111", "password", "USD")
+            .position("111", "SPXS.UK", 10.0)
+            .stock("SPXS.UK", 100.0, "USD")
+            .symbol(serde_json::json!({
+                "symbol": "US500",
+                "bid": 5000.0,
+                "ask": 5001.0,
+                "currency": "USD",
+                "currencyProfit": "USD",
+                "categoryName": "IND",
+                "contractSize": 50,
+                "leverage": 5.0,
+            }))
+            .trade(
+                "111",
+                serde_json::json!({
+                    "symbol": "US500",
+                    "volume": 0.01,
+                    "cmd": 0,
+                    "order": 2,
+                    "closed": false,
+                    "profit": 200.0,
+                    "nominalValue": 2500.0,
+                }),
+            )
+            .start()
+            .await;
+        let file = PortfolioFile::new();
+        let contents = format!(
+            r#"
+config:
+  base_currency: USD
+  xtb: {endpoint}
+groups:
+- id: xtb
+  currency: USD
+  source:
+    type: xtb
+    account_id: '111'
+    encrypted_password: {password}
+positions:
+- name: S&P 500 ETF
+  group: xtb
+  ticker: SPXS.UK
+  target: 0.5
+- name: S&P 500 CFD
+  group: xtb
+  ticker: US500
+  target: 0.5
+"#,
+            endpoint = server.endpoint(),
+            password = file.encrypt("password"),
+        );
+        let amounts = |portfolio: &Portfolio| -> Vec<f64> {
+            portfolio.positions[..2]
+                .iter()
+                .map(|position| position.amount.clone().unwrap().value)
+                .collect()
+        };
+
+        /* CFD counts by its profit by default, bought outright ETF by its market value */
+        file.write(&contents);
+        let mut portfolio = file.read(mock_rates()).await.unwrap();
+        assert_eq!(amounts(&portfolio), vec![1000.0, 200.0]);
+        // 0.5 contract of 50 units gains 25 USD on 50 USD price change
+        assert!(portfolio.update_bid_price("xtb:111", "US500", 5050.0));
+        assert_eq!(amounts(&portfolio), vec![1000.0, 225.0]);
+
+        /* Or by its nominal value */
+        file.write(&contents.replace(
+            "base_currency: USD",
+            "base_currency: USD\n  valuation: exposure",
+        ));
+        let mut portfolio = file.read(mock_rates()).await.unwrap();
+        assert_eq!(amounts(&portfolio), vec![1000.0, 2500.0]);
+        assert!(portfolio.update_bid_price("xtb:111", "US500", 5050.0));
+        assert_eq!(amounts(&portfolio), vec![1000.0, 2525.0]);
+    }
+
+    #[tokio::test]
+    async fn test_from_file_xtb() {
+        let server = crate::xtb::mock::MockXtb::new()
+            .account("111", "password1", "USD")
+            .account("222", "password2", "EUR")
+            .position("111", "SPXS.UK", 2.0)
+            .position("111", "SPXS.UK", 1.0)
+            .position("111", "UNUSED.US", 1.0)
+            .position("222", "IMAE.NL", 10.0)
+            .stock("SPXS.UK", 100.0, "USD")
+            .stock("IMAE.NL", 50.0, "EUR")
+            .stock("UNUSED.US", 1.0, "USD")
+            .start()
+            .await;
+        let file = PortfolioFile::new();
+        let filename = file.write(&format!(
+            r#"
+config:
+  base_currency: USD
+  xtb: {endpoint}
+groups:
+- id: xtb_usd
+  currency: USD
+  source:
+    type: xtb
+    account_id: '111'
+    encrypted_password: {password1}
+- id: xtb_eur
+  currency: EUR
+  xtb:
+    account_id: '222'
+    encrypted_password: {password2}
+- id: cash
+  currency: USD
+positions:
+- name: S&P 500
+  group: xtb_usd
+  ticker: SPXS.UK
+  target: 0.5
+- name: Europe
+  group: xtb_eur
+  ticker: IMAE.NL
+  target: 0.3
+- name: Cash
+  group: cash
+  ticker: CASH
+  amount:
+    currency: USD
+    value: 100.0
+  target: 0.2
+"#,
+            endpoint = server.endpoint(),
+            password1 = file.encrypt("password1"),
+            password2 = file.encrypt("password2"),
+        ));
+
+        let portfolio = file.read(mock_rates()).await.unwrap();
+        let amounts: Vec<_> = portfolio
+            .positions
+            .iter()
+            .map(|position| position.amount.clone().unwrap())
+            .collect();
+        assert_eq!(
+            amounts,
+            vec![
+                Amount::new(Currency::USD, 300.0),
+                Amount::new(Currency::EUR, 500.0),
+                Amount::new(Currency::USD, 100.0),
+                Amount::new(Currency::USD, 1.0),
+            ]
+        );
+        // Holding of no position is reported as unallocated and counted in total value
+        assert_eq!(
+            portfolio.positions[3].automatic,
+            Some(Automatic::Unallocated)
+        );
+        assert_eq!(portfolio.positions[3].group, "xtb_usd");
+        assert_eq!(
+            portfolio.total_value(Currency::USD),
+            Amount::new(Currency::USD, 300.0 + 500.0 * 1.2 + 100.0 + 1.0)
+        );
+        assert!(portfolio.to_string().contains("Unallocated"));
+
+        /* Unallocated holding is adopted with target 0 */
+        let mut saved = Portfolio::parse_file(filename, &file.key).unwrap();
+        assert_eq!(
+            saved.adopt(&portfolio, Some("OTHER.US")),
+            Vec::<String>::new()
+        );
+        assert_eq!(saved.adopt(&portfolio, None), vec!["UNUSED.US"]);
+        saved.to_file(filename, None).await.unwrap();
+        let portfolio = file.read(mock_rates()).await.unwrap();
+        assert_eq!(portfolio.positions.len(), 4);
+        assert!(portfolio.positions[3].automatic.is_none());
+        assert_eq!(portfolio.positions[3].target, Target::Share(0.0));
+        assert_eq!(
+            portfolio.positions[3].amount,
+            Some(Amount::new(Currency::USD, 1.0))
+        );
+
+        /* Wrong key can't be used to log in */
+        let result =
+            Portfolio::from_file_with_rates(filename, &Secret::from("wrong"), mock_rates()).await;
+        assert!(result.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_from_file_xtb_single_session() {
+        let server = crate::xtb::mock::MockXtb::new()
+            .account("111", "password", "USD")
+            .balance("111", 50.0)
+            .position("111", "SPXS.UK", 2.0)
+            .position("111", "IMAE.NL", 10.0)
+            .stock("SPXS.UK", 100.0, "USD")
+            .stock("IMAE.NL", 50.0, "EUR")
+            .start()
+            .await;
+        let file = PortfolioFile::new();
+        let account = format!(
+            "account_id: '111'\n    encrypted_password: {}",
+            file.encrypt("password")
+        );
+        file.write(&format!(
+            r#"
+config:
+  base_currency: USD
+  xtb: {endpoint}
+groups:
+- id: us
+  currency: USD
+  source:
+    type: xtb
+    {account}
+- id: europe
+  currency: EUR
+  source:
+    type: xtb
+    {account}
+positions:
+- name: S&P 500
+  group: us
+  ticker: SPXS.UK
+  target: 0.5
+- name: Europe
+  group: europe
+  ticker: IMAE.NL
+  target: 0.5
+"#,
+            endpoint = server.endpoint(),
+        ));
+
+        let portfolio = file.read(mock_rates()).await.unwrap();
+        assert_eq!(
+            portfolio.total_value(Currency::USD),
+            Amount::new(Currency::USD, 200.0 + 500.0 * 1.2 + 50.0)
+        );
+        /* Both groups are served by one session, prices fetched only for held symbols */
+        assert_eq!(
+            server.received_commands(),
+            vec![
+                "login",
+                "getTrades",
+                "getSymbol",
+                "getSymbol",
+                "getMarginLevel",
+                "logout"
+            ]
+        );
+
+        /* Account cash is added once, to the first group using the account */
+        let cash_positions: Vec<_> = portfolio
+            .positions
+            .iter()
+            .filter(|position| position.cash_session().is_some())
+            .collect();
+        assert_eq!(cash_positions.len(), 1);
+        assert_eq!(cash_positions[0].group, "us");
+        assert_eq!(
+            cash_positions[0].amount,
+            Some(Amount::new(Currency::USD, 50.0))
+        );
+
+        /* Automatic cash positions are not saved */
+        let saved = serde_yaml::to_string(&portfolio).unwrap();
+        assert!(!saved.contains("CASH"));
+        assert_eq!(
+            serde_yaml::from_str::<Portfolio>(&saved)
+                .unwrap()
+                .positions
+                .len(),
+            2
+        );
+    }
+
+    #[test]
+    fn test_position_holds() {
+        let position = Position {
+            ticker: "SPX500".to_string(),
+            group: "xtb".to_string(),
+            isin: Some("IE00B3YCGJ38".to_string()),
+            symbols: BTreeMap::from([
+                ("xtb".to_string(), vec!["SPXS.UK_9".to_string()]),
+                ("ibkr".to_string(), vec!["CSPX".to_string()]),
+            ]),
+            ..Default::default()
+        };
+        let holding = |symbol: &str, isin: Option<&str>| Holding {
+            symbol: symbol.to_string(),
+            isin: isin.map(str::to_string),
+            quantity: 1.0,
+            price: Amount::new(Currency::USD, 1.0),
+            market_value: Amount::new(Currency::USD, 1.0),
+            equity: Amount::new(Currency::USD, 1.0),
+        };
+
+        assert_eq!(position.symbols_in("xtb"), vec!["SPXS.UK_9", "SPX500"]);
+        assert!(position.holds("xtb", &holding("SPX500", None)));
+        assert!(position.holds("xtb", &holding("SPXS.UK_9", None)));
+        assert!(position.holds("ibkr", &holding("CSPX", None)));
+        assert!(position.holds("ibkr", &holding("SXR8", Some("IE00B3YCGJ38"))));
+        // Symbols and ISIN only match in mapped groups
+        assert!(!position.holds("ibkr", &holding("SPX500", None)));
+        assert!(!position.holds("bank", &holding("CSPX", Some("IE00B3YCGJ38"))));
+        assert!(!position.holds("xtb", &holding("SXR8", Some("IE00B4L5Y983"))));
+    }
+
+    #[tokio::test]
+    async fn test_from_file_quoted_positions() {
+        let file = PortfolioFile::new();
+        let quotes_file = file.dir().join("quotes.csv");
+        std::fs::write(
+            &quotes_file,
+            "symbol,date,price,currency\nGLD,2024-05-10,200.0,USD\n",
+        )
+        .unwrap();
+        let contents = format!(
+            r#"
+config:
+  base_currency: USD
+groups:
+- id: bank
+  currency: USD
+positions:
+- name: Gold
+  group: bank
+  ticker: GLD
+  quantity: 3
+  quote:
+    type: file
+    path: {quotes_file}
+  target: 0.5
+- name: Cash
+  group: bank
+  ticker: CASH
+  amount:
+    currency: USD
+    value: 600.0
+  target: 0.5
+"#,
+            quotes_file = quotes_file.display(),
+        );
+        file.write(&contents);
+
+        let portfolio = file.read(mock_rates()).await.unwrap();
+        assert_eq!(
+            portfolio.positions[0].amount,
+            Some(Amount::new(Currency::USD, 600.0))
+        );
+        let shown = portfolio.to_string();
+        assert!(shown.contains("[GLD     ] Gold"));
+        assert!(shown.contains("price of 2024-05-10"));
+
+        file.write(&contents.replace("  quote:\n    type: file", "  unquoted:\n    type: file"));
+        assert!(matches!(
+            file.read(mock_rates()).await,
+            Err(error::PortfolioReadError::QuoteMissing(position)) if position == "Gold"
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_from_file_bonds() {
+        let file = PortfolioFile::new();
+        // Bonds in their first year need no CPI table
+        let purchase_date = Local::now().date_naive() - chrono::Days::new(100);
+        file.write(&format!(
+            r#"
+config:
+  base_currency: PLN
+groups:
+- id: bonds
+  currency: PLN
+positions:
+- name: EDO
+  group: bonds
+  ticker: EDO
+  bonds:
+  - series: EDO0135
+    purchase_date: {purchase_date}
+    count: 10
+    first_year_rate: 0.0655
+    margin: 0.02
+  - series: EDO0135
+    purchase_date: {purchase_date}
+    count: 5
+    first_year_rate: 0.0655
+    margin: 0.02
+  target: 0.5
+- name: Cash
+  group: bonds
+  ticker: CASH
+  amount:
+    currency: PLN
+    value: 1000.0
+  target: 0.5
+"#
+        ));
+
+        let portfolio = file.read(mock_rates()).await.unwrap();
+        let bond = &portfolio.positions[0].bonds[0];
+        let value = bond
+            .value(&CpiTable::default(), Local::now().date_naive())
+            .unwrap();
+        let amount = portfolio.positions[0].amount.clone().unwrap();
+        assert_eq!(amount.currency, Currency::PLN);
+        assert!((amount.value - value.value.value * 1.5).abs() < 0.01);
+        assert!(amount.value > 1500.0);
+        assert!(portfolio.to_string().contains("redeemed now"));
+
+        // Bonds are balanced like any other position
+        let balanced = portfolio
+            .balance(Amount::new(Currency::PLN, 1000.0))
+            .unwrap();
+        assert_eq!(balanced.changes.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_from_file_deposits() {
+        let file = PortfolioFile::new();
+        let today = Local::now().date_naive();
+        let start = today - chrono::Days::new(365);
+        let matured = today - chrono::Days::new(30);
+        let maturity = today + chrono::Days::new(10);
+        file.write(&format!(
+            r#"
+config:
+  base_currency: PLN
+groups:
+- id: cash
+  currency: PLN
+positions:
+- name: Term deposit
+  group: cash
+  ticker: TERM
+  deposit:
+    principal:
+      currency: PLN
+      value: 10000.0
+    rate: 0.05
+    start: {start}
+    maturity: {maturity}
+  target: 0.5
+- name: Old deposit
+  group: cash
+  ticker: OLD
+  deposit:
+    principal:
+      currency: PLN
+      value: 1000.0
+    rate: 0.05
+    start: {start}
+    maturity: {matured}
+  target: 0.5
+"#
+        ));
+
+        let portfolio = file.read(mock_rates()).await.unwrap();
+        let deposit = portfolio.positions[0].deposit.as_ref().unwrap();
+        assert_eq!(portfolio.positions[0].amount, Some(deposit.value(today)));
+        assert!(portfolio.positions[0].amount.clone().unwrap().value > 10000.0);
+
+        // Matured deposits are listed first
+        let shown = portfolio.to_string();
+        let maturities = shown.split("Upcoming maturities:").nth(1).unwrap();
+        let old = maturities.find(&format!("{} [OLD", matured)).unwrap();
+        let term = maturities.find(&format!("{} [TERM", maturity)).unwrap();
+        assert!(old < term);
+        assert!(maturities.contains("matured"));
+        assert!(maturities.contains("in 10 days"));
+    }
+
+    #[tokio::test]
+    async fn test_from_file_net_worth() {
+        let file = PortfolioFile::new();
+        let start = Local::now().date_naive() - chrono::Months::new(12);
+        file.write(&format!(
+            r#"
+config:
+  base_currency: PLN
+groups:
+- id: pln
+  currency: PLN
+positions:
+- name: Stocks
+  group: pln
+  ticker: STOCKS
+  amount:
+    currency: PLN
+    value: 1000.0
+  target: 0.5
+- name: Bonds
+  group: pln
+  ticker: BONDS
+  amount:
+    currency: PLN
+    value: 1000.0
+  target: 0.5
+- name: Flat
+  group: pln
+  ticker: FLAT
+  kind: asset
+  amount:
+    currency: PLN
+    value: 500000.0
+  target: 0.0
+- name: Mortgage
+  group: pln
+  ticker: MORTGAGE
+  kind: liability
+  loan:
+    principal:
+      currency: PLN
+      value: 300000.0
+    rate: 0.07
+    start: {start}
+    months: 300
+  target: 0.0
+- name: Car loan
+  group: pln
+  ticker: CAR
+  kind: liability
+  amount:
+    currency: PLN
+    value: 20000.0
+  target: 0.0
+"#
+        ));
+
+        let portfolio = file.read(mock_rates()).await.unwrap();
+        // Twelve installments of the mortgage were repaid
+        let mortgage = portfolio.positions[3].amount.clone().unwrap();
+        let loan = portfolio.positions[3].loan.as_ref().unwrap();
+        assert_eq!(mortgage.value, -loan.schedule()[11].balance);
+        assert_eq!(
+            portfolio.positions[4].amount,
+            Some(Amount::new(Currency::PLN, -20000.0))
+        );
+
+        assert_eq!(portfolio.value(), Amount::new(Currency::PLN, 2000.0));
+        assert_eq!(
+            portfolio.net_worth(),
+            Amount::new(Currency::PLN, 2000.0 + 500000.0 - 20000.0 + mortgage.value)
+        );
+        assert_eq!(portfolio.allocation().len(), 2);
+        let shown = portfolio.to_string();
+        assert!(shown.contains("Assets and liabilities"));
+        assert!(shown.contains("next installment"));
+
+        // Only investable positions are balanced
+        let balanced = portfolio
+            .balance(Amount::new(Currency::PLN, 1000.0))
+            .unwrap();
+        assert_eq!(balanced.changes.len(), 2);
+        assert_eq!(
+            balanced.total_change(portfolio.rates(), Currency::PLN),
+            Amount::new(Currency::PLN, 1000.0)
+        );
+    }
+
+    #[test]
+    fn test_import_snapshot() {
+        let mut portfolio: Portfolio = serde_yaml::from_str(
+            r#"
+config:
+  base_currency: EUR
+  csv_formats:
+    degiro:
+      kind: holdings
+      group: degiro
+      columns:
+        symbol: Symbol
+        isin: Symbol
+        value: Value
+        currency: Currency
+groups:
+- id: degiro
+  currency: EUR
+- id: bank
+  currency: PLN
+positions:
+- name: All World
+  group: degiro
+  ticker: VWCE
+  isin: IE00BK5BQT80
+  amount:
+    currency: EUR
+    value: 1000.0
+  target: 0.6
+- name: Bonds
+  group: degiro
+  ticker: AGGH
+  amount:
+    currency: EUR
+    value: 500.0
+  target: 0.3
+- name: Savings
+  group: bank
+  ticker: SAVINGS
+  amount:
+    currency: PLN
+    value: 100.0
+  target: 0.1
+"#,
+        )
+        .unwrap();
+        let format = portfolio.csv_format("degiro").unwrap().clone();
+        let holdings = format
+            .read_holdings(
+                "Symbol,Value,Currency
+IE00BK5BQT80,1100,EUR
+XDWD,50,EUR
+",
+            )
+            .unwrap();
+
+        let (changes, unmatched) = portfolio.import_snapshot("degiro", &holdings).unwrap();
+        // Bonds are no longer held, positions of other groups stay as they were
+        assert_eq!(
+            changes,
+            vec![
+                AmountChange {
+                    position: "[VWCE    ] All World".to_string(),
+                    old: Some(Amount::new(Currency::EUR, 1000.0)),
+                    new: Amount::new(Currency::EUR, 1100.0),
+                },
+                AmountChange {
+                    position: "[AGGH    ] Bonds".to_string(),
+                    old: Some(Amount::new(Currency::EUR, 500.0)),
+                    new: Amount::new(Currency::EUR, 0.0),
+                },
+            ]
+        );
+        assert_eq!(unmatched, vec!["XDWD"]);
+        assert_eq!(
+            portfolio.positions[2].amount,
+            Some(Amount::new(Currency::PLN, 100.0))
+        );
+        // Importing the same snapshot again changes nothing
+        assert!(portfolio
+            .import_snapshot("degiro", &holdings)
+            .unwrap()
+            .0
+            .is_empty());
+        assert!(matches!(
+            portfolio.import_snapshot("mbank", &holdings),
+            Err(error::ImportError::UnknownGroup(group)) if group == "mbank"
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_from_file_symbol_mapping() {
+        let server = crate::xtb::mock::MockXtb::new()
+            .account("111", "password1", "USD")
+            .account("222", "password2", "EUR")
+            .position("111", "SPXS.UK_9", 2.0)
+            .position("222", "SPXS.DE", 1.0)
+            .stock("SPXS.UK_9", 100.0, "USD")
+            .stock("SPXS.DE", 100.0, "EUR")
+            .start()
+            .await;
+        let file = PortfolioFile::new();
+        let write_portfolio = |positions: &str| {
+            file.write(&format!(
+                r#"
+config:
+  base_currency: USD
+  xtb: {endpoint}
+groups:
+- id: xtb_usd
+  currency: USD
+  source:
+    type: xtb
+    account_id: '111'
+    encrypted_password: {password1}
+- id: xtb_eur
+  currency: EUR
+  source:
+    type: xtb
+    account_id: '222'
+    encrypted_password: {password2}
+positions:
+{positions}"#,
+                endpoint = server.endpoint(),
+                password1 = file.encrypt("password1"),
+                password2 = file.encrypt("password2"),
+            ));
+        };
+
+        /* Same ETF held at both accounts under different symbols is one position */
+        write_portfolio(
+            r#"
+- name: S&P 500
+  group: xtb_usd
+  ticker: SPX500
+  symbols:
+    xtb_usd: [SPXS.UK_9]
+    xtb_eur: [SPXS.DE]
+  target: 1.0
+"#,
+        );
+        let portfolio = file.read(mock_rates()).await.unwrap();
+        assert_eq!(portfolio.positions.len(), 1);
+        assert_eq!(
+            portfolio.positions[0].amount,
+            Some(Amount::new(Currency::USD, 200.0 + 100.0 * 1.2))
+        );
+
+        /* Single holding can't be counted as two positions */
+        write_portfolio(
+            r#"
+- name: S&P 500
+  group: xtb_usd
+  ticker: SPXS.UK_9
+  target: 0.5
+- name: S&P 500 (EUR)
+  group: xtb_eur
+  ticker: SPXS.DE
+  symbols:
+    xtb_usd: [SPXS.UK_9]
+  target: 0.5
+"#,
+        );
+        assert!(matches!(
+            file.read(mock_rates()).await,
+            Err(error::PortfolioReadError::DuplicateSymbolError(symbol)) if symbol == "SPXS.UK_9"
+        ));
+    }
 }
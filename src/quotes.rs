@@ -0,0 +1,364 @@
+//! Prices of positions given by quantity instead of amount.
+//!
+//! A position may hold a `quantity` of an instrument priced by a `quote` source: a local quotes
+//! file maintained by hand or by a script, or daily prices downloaded from Stooq. Downloads are
+//! cached, so that the portfolio is shown with the last known price when offline, and Stooq is
+//! asked at most once a day per symbol. Stooq reports errors (unknown symbol, daily limit
+//! exceeded) as successful plain text responses, so only downloads that parse as prices are
+//! cached.
+
+use std::path::{Path, PathBuf};
+
+use chrono::{Local, NaiveDate};
+use serde::{Deserialize, Deserializer, Serialize};
+
+use crate::{
+    amount::{Amount, Currency},
+    error,
+};
+
+/// Where the price of a position comes from, selected by `type` in YAML.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum QuoteSource {
+    /// Quotes file, CSV with `symbol,date,price,currency` header or JSON list of such objects
+    File {
+        path: String,
+        /// Symbol in the file, position's ticker if not given
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        symbol: Option<String>,
+    },
+    /// Daily close prices of stooq.com symbol, e.g. `gld.us`
+    Stooq {
+        #[serde(deserialize_with = "stooq_symbol")]
+        symbol: String,
+        currency: Currency,
+    },
+}
+
+/// Stooq symbol, limited to characters Stooq uses, as it becomes part of the cache file name and
+/// download URL.
+fn stooq_symbol<'de, D: Deserializer<'de>>(deserializer: D) -> Result<String, D::Error> {
+    let symbol = String::deserialize(deserializer)?;
+    let valid = !symbol.is_empty()
+        && symbol
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "._^-".contains(c));
+    match valid {
+        true => Ok(symbol),
+        false => Err(serde::de::Error::custom(format!(
+            "invalid Stooq symbol: '{}'",
+            symbol
+        ))),
+    }
+}
+
+/// Last known price of an instrument.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Quote {
+    pub price: Amount,
+    pub date: NaiveDate,
+}
+
+/// Row of a quotes file.
+#[derive(Debug, Deserialize)]
+struct QuoteRecord {
+    symbol: String,
+    date: NaiveDate,
+    price: f64,
+    currency: Currency,
+}
+
+/// Row of a Stooq daily prices CSV.
+#[derive(Debug, Deserialize)]
+struct StooqRecord {
+    #[serde(rename = "Date")]
+    date: NaiveDate,
+    #[serde(rename = "Close")]
+    close: f64,
+}
+
+/// Fetches quotes, caching downloaded ones in `cache_dir`.
+#[derive(Debug, Clone)]
+pub struct Quotes {
+    cache_dir: PathBuf,
+    stooq_url: String,
+}
+
+impl Default for Quotes {
+    fn default() -> Self {
+        let dirs = directories::ProjectDirs::from("pl", "slawekgonet", "portfel").unwrap();
+        Self::new(dirs.cache_dir().join("quotes"), "https://stooq.com")
+    }
+}
+
+impl Quotes {
+    pub fn new(cache_dir: PathBuf, stooq_url: &str) -> Self {
+        Self {
+            cache_dir,
+            stooq_url: stooq_url.to_string(),
+        }
+    }
+
+    /// Last price of `ticker` from `source`.
+    pub async fn quote(
+        &self,
+        source: &QuoteSource,
+        ticker: &str,
+    ) -> Result<Quote, error::QuoteError> {
+        match source {
+            QuoteSource::File { path, symbol } => {
+                read_quotes_file(Path::new(path), symbol.as_deref().unwrap_or(ticker))
+            }
+            QuoteSource::Stooq { symbol, currency } => self.stooq_quote(symbol, *currency).await,
+        }
+    }
+
+    /// Last close price of `symbol`, from daily prices downloaded unless cached today.
+    async fn stooq_quote(
+        &self,
+        symbol: &str,
+        currency: Currency,
+    ) -> Result<Quote, error::QuoteError> {
+        let cache_file = self.cache_dir.join(format!("stooq-{}.csv", symbol));
+        let cached_today = std::fs::metadata(&cache_file)
+            .and_then(|metadata| metadata.modified())
+            .is_ok_and(|modified| {
+                chrono::DateTime::<Local>::from(modified).date_naive() == Local::now().date_naive()
+            });
+        if cached_today {
+            return last_stooq_quote(&std::fs::read_to_string(&cache_file)?, symbol, currency);
+        }
+
+        let url = format!("{}/q/d/l/?s={}&i=d", self.stooq_url, symbol);
+        let downloaded = match reqwest::get(&url).await {
+            Ok(response) => match response.error_for_status() {
+                Ok(response) => response.text().await,
+                Err(e) => Err(e),
+            },
+            Err(e) => Err(e),
+        };
+        let downloaded = match downloaded {
+            Ok(contents) => {
+                last_stooq_quote(&contents, symbol, currency).map(|quote| (quote, contents))
+            }
+            Err(e) => Err(e.into()),
+        };
+        match downloaded {
+            Ok((quote, contents)) => {
+                std::fs::create_dir_all(&self.cache_dir)?;
+                std::fs::write(&cache_file, &contents)?;
+                Ok(quote)
+            }
+            // Stale price is better than none, the price date tells how stale it is
+            Err(e) if cache_file.exists() => {
+                log::warn!("Using cached prices of {}, download failed: {}", symbol, e);
+                last_stooq_quote(&std::fs::read_to_string(&cache_file)?, symbol, currency)
+            }
+            Err(e) => Err(e),
+        }
+    }
+}
+
+/// Last close price in Stooq daily prices CSV `contents` of `symbol`.
+fn last_stooq_quote(
+    contents: &str,
+    symbol: &str,
+    currency: Currency,
+) -> Result<Quote, error::QuoteError> {
+    let last = csv::Reader::from_reader(contents.as_bytes())
+        .deserialize::<StooqRecord>()
+        .filter_map(Result::ok)
+        .max_by_key(|record| record.date);
+    match last {
+        Some(last) => Ok(Quote {
+            price: Amount::new(currency, last.close),
+            date: last.date,
+        }),
+        // Stooq answers unknown symbols with "No data" instead of an error
+        None if contents.trim() == "No data" => {
+            Err(error::QuoteError::NotFound(symbol.to_string()))
+        }
+        None => Err(error::QuoteError::InvalidResponse(
+            symbol.to_string(),
+            contents.lines().next().unwrap_or_default().to_string(),
+        )),
+    }
+}
+
+/// Latest quote of `symbol` in a quotes file, CSV or JSON by extension.
+fn read_quotes_file(path: &Path, symbol: &str) -> Result<Quote, error::QuoteError> {
+    let contents = std::fs::read_to_string(path)?;
+    let records: Vec<QuoteRecord> = match path.extension().and_then(|extension| extension.to_str())
+    {
+        Some("json") => serde_json::from_str(&contents)?,
+        _ => csv::Reader::from_reader(contents.as_bytes())
+            .deserialize()
+            .collect::<Result<_, _>>()?,
+    };
+    records
+        .into_iter()
+        .filter(|record| record.symbol == symbol)
+        .max_by_key(|record| record.date)
+        .map(|record| Quote {
+            price: Amount::new(record.currency, record.price),
+            date: record.date,
+        })
+        .ok_or(error::QuoteError::NotFound(symbol.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn quotes_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let csv_file = dir.path().join("quotes.csv");
+        std::fs::write(
+            &csv_file,
+            "symbol,date,price,currency\nGLD,2024-05-09,200.5,USD\nGLD,2024-05-10,201.0,USD\nEXSA,2024-05-10,50,EUR\n",
+        )
+        .unwrap();
+        let json_file = dir.path().join("quotes.json");
+        std::fs::write(
+            &json_file,
+            r#"[{"symbol": "GLD", "date": "2024-05-11", "price": 202.0, "currency": "USD"}]"#,
+        )
+        .unwrap();
+        let quotes = Quotes::new(dir.path().join("cache"), "http://localhost:1");
+
+        let source = QuoteSource::File {
+            path: csv_file.to_str().unwrap().to_string(),
+            symbol: None,
+        };
+        assert_eq!(
+            quotes.quote(&source, "GLD").await.unwrap(),
+            Quote {
+                price: Amount::new(Currency::USD, 201.0),
+                date: NaiveDate::from_ymd_opt(2024, 5, 10).unwrap(),
+            }
+        );
+        assert!(matches!(
+            quotes.quote(&source, "SLV").await,
+            Err(error::QuoteError::NotFound(symbol)) if symbol == "SLV"
+        ));
+        let source = QuoteSource::File {
+            path: json_file.to_str().unwrap().to_string(),
+            symbol: Some("GLD".to_string()),
+        };
+        assert_eq!(
+            quotes.quote(&source, "Gold").await.unwrap().price,
+            Amount::new(Currency::USD, 202.0)
+        );
+    }
+
+    #[test]
+    fn stooq_symbol_characters() {
+        let source: QuoteSource =
+            serde_yaml::from_str("{type: stooq, symbol: ^spx, currency: USD}").unwrap();
+        assert_eq!(
+            source,
+            QuoteSource::Stooq {
+                symbol: "^spx".to_string(),
+                currency: Currency::USD
+            }
+        );
+        for symbol in ["../../x", "gld.us&i=w", "gld.us#", "''"] {
+            let yaml = format!("{{type: stooq, symbol: {}, currency: USD}}", symbol);
+            assert!(
+                serde_yaml::from_str::<QuoteSource>(&yaml).is_err(),
+                "{}",
+                symbol
+            );
+        }
+    }
+
+    /// Serve `body` to a single HTTP request, returning the server's URL.
+    async fn serve_once(body: &'static str) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut request = vec![0; 4096];
+            let _ = stream.read(&mut request).await.unwrap();
+            let response = format!(
+                "HTTP/1.1 200 OK\r\nContent-Type: text/csv\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                body.len(),
+                body
+            );
+            stream.write_all(response.as_bytes()).await.unwrap();
+        });
+        url
+    }
+
+    #[tokio::test]
+    async fn stooq_cached() {
+        let dir = tempfile::tempdir().unwrap();
+        let url = serve_once(
+            "Date,Open,High,Low,Close,Volume\n2024-05-09,199,201,198,200.5,1000\n2024-05-10,200,202,199,201.25,1200\n",
+        )
+        .await;
+        let quotes = Quotes::new(dir.path().to_path_buf(), &url);
+        let source = QuoteSource::Stooq {
+            symbol: "gld.us".to_string(),
+            currency: Currency::USD,
+        };
+        let quote = Quote {
+            price: Amount::new(Currency::USD, 201.25),
+            date: NaiveDate::from_ymd_opt(2024, 5, 10).unwrap(),
+        };
+        assert_eq!(quotes.quote(&source, "GLD").await.unwrap(), quote);
+        // Server answers once only, so the second quote is read from cache
+        assert_eq!(quotes.quote(&source, "GLD").await.unwrap(), quote);
+        assert!(dir.path().join("stooq-gld.us.csv").exists());
+
+        let source = QuoteSource::Stooq {
+            symbol: "nope.us".to_string(),
+            currency: Currency::USD,
+        };
+        let quotes = Quotes::new(dir.path().to_path_buf(), &serve_once("No data").await);
+        assert!(matches!(
+            quotes.quote(&source, "NOPE").await,
+            Err(error::QuoteError::NotFound(_))
+        ));
+        assert!(!dir.path().join("stooq-nope.us.csv").exists());
+    }
+
+    #[tokio::test]
+    async fn stooq_error_keeps_stale_cache() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache_file = dir.path().join("stooq-gld.us.csv");
+        let cached = "Date,Open,High,Low,Close,Volume\n2024-05-10,200,202,199,201.25,1200\n";
+        std::fs::write(&cache_file, cached).unwrap();
+        std::fs::File::options()
+            .write(true)
+            .open(&cache_file)
+            .unwrap()
+            .set_modified(std::time::SystemTime::now() - std::time::Duration::from_secs(2 * 86400))
+            .unwrap();
+        let source = QuoteSource::Stooq {
+            symbol: "gld.us".to_string(),
+            currency: Currency::USD,
+        };
+
+        let url = serve_once("Exceeded the daily hits limit").await;
+        let quotes = Quotes::new(dir.path().to_path_buf(), &url);
+        assert_eq!(
+            quotes.quote(&source, "GLD").await.unwrap().price,
+            Amount::new(Currency::USD, 201.25)
+        );
+        assert_eq!(std::fs::read_to_string(&cache_file).unwrap(), cached);
+
+        std::fs::remove_file(&cache_file).unwrap();
+        let url = serve_once("Exceeded the daily hits limit").await;
+        let quotes = Quotes::new(dir.path().to_path_buf(), &url);
+        assert!(matches!(
+            quotes.quote(&source, "GLD").await,
+            Err(error::QuoteError::InvalidResponse(_, message)) if message == "Exceeded the daily hits limit"
+        ));
+    }
+}
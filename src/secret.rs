@@ -0,0 +1,55 @@
+use zeroize::Zeroizing;
+
+/// Sensitive text, like the portfolio key or broker password, kept in memory only.
+///
+/// Contents are zeroized on drop and redacted in `Debug`. `Secret` deliberately implements
+/// neither `Display` nor `Serialize`, so it can't end up in logs or portfolio file by accident.
+#[derive(Clone, PartialEq, Eq)]
+pub struct Secret(Zeroizing<String>);
+
+impl Secret {
+    pub fn new(secret: String) -> Secret {
+        Secret(Zeroizing::new(secret))
+    }
+
+    /// Access the secret text. Keep the borrow short and avoid copying it around.
+    pub fn expose(&self) -> &str {
+        self.0.as_str()
+    }
+}
+
+impl From<String> for Secret {
+    fn from(secret: String) -> Self {
+        Secret::new(secret)
+    }
+}
+
+impl From<&str> for Secret {
+    fn from(secret: &str) -> Self {
+        Secret::new(secret.to_string())
+    }
+}
+
+impl std::fmt::Debug for Secret {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Secret([REDACTED])")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_debug_redacted() {
+        let secret = Secret::from("password");
+        assert_eq!(format!("{:?}", secret), "Secret([REDACTED])");
+        assert_eq!(format!("{:?}", Some(secret)), "Some(Secret([REDACTED]))");
+    }
+
+    #[test]
+    fn test_expose() {
+        let secret = Secret::from("password".to_string());
+        assert_eq!(secret.expose(), "password");
+    }
+}
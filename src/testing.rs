@@ -0,0 +1,57 @@
+//! Fixtures shared by tests reading portfolio files.
+
+use std::path::Path;
+
+use crate::error;
+use crate::fx::Rates;
+use crate::portfolio::Portfolio;
+use crate::secret::Secret;
+
+/// Portfolio file in a temporary directory, removed when dropped, with the key of its secrets.
+pub struct PortfolioFile {
+    dir: tempfile::TempDir,
+    path: String,
+    pub key: Secret,
+}
+
+impl PortfolioFile {
+    pub fn new() -> Self {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir
+            .path()
+            .join("portfolio.yaml")
+            .to_str()
+            .unwrap()
+            .to_string();
+        PortfolioFile {
+            dir,
+            path,
+            key: Secret::from("key"),
+        }
+    }
+
+    /// Directory of the file, for other files the portfolio refers to.
+    pub fn dir(&self) -> &Path {
+        self.dir.path()
+    }
+
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    /// `password` encrypted with the portfolio key, for `encrypted_password` of accounts.
+    pub fn encrypt(&self, password: &str) -> String {
+        crate::crypt::encrypt_text(password, self.key.expose()).unwrap()
+    }
+
+    /// Write YAML `contents` to the file, returning its path.
+    pub fn write(&self, contents: &str) -> &str {
+        std::fs::write(&self.path, contents).unwrap();
+        &self.path
+    }
+
+    /// Read and load portfolio from the file, converting currencies at `rates`.
+    pub async fn read(&self, rates: Rates) -> Result<Portfolio, error::PortfolioReadError> {
+        Portfolio::from_file_with_rates(&self.path, &self.key, rates).await
+    }
+}
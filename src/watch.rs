@@ -0,0 +1,329 @@
+//! Live allocation of a portfolio, revalued with prices streamed from XTB.
+//!
+//! Every XTB account keeps its own session for as long as its price stream is read. A stream that
+//! fails is opened again, in a new session if the old one is gone, and resubscribed. Allocation is
+//! rendered again whenever a price changes value of any position, and positions whose share is
+//! further from target than their drift threshold are highlighted.
+
+use std::collections::{BTreeSet, HashSet};
+
+use tokio::sync::mpsc;
+
+use crate::{
+    broker::{PositionSource, SourceConfig},
+    error,
+    portfolio::{Allocation, Portfolio},
+    secret::Secret,
+    xtb::{PriceStream, Stream, TickPrice, XtbConfig},
+};
+
+/// Number of times in a row a failed stream is opened again before watching gives up.
+const REOPEN_ATTEMPTS: u32 = 3;
+
+/// Price pushed by the stream of position source with given session id.
+pub type Tick = (String, TickPrice);
+
+/// Subscribe to prices of all holdings read from XTB accounts of `portfolio`.
+///
+/// Ticks of all accounts are forwarded to the returned channel. A failed stream is opened again,
+/// and only if that keeps failing, it sends its error and stops.
+pub async fn subscribe(
+    portfolio: &Portfolio,
+    encryption_key: &Secret,
+) -> Result<mpsc::Receiver<Result<Tick, error::XtbError>>, error::SourceError> {
+    let (sender, receiver) = mpsc::channel(64);
+    for source_config in portfolio.sources() {
+        // Other sources have no price streams
+        let SourceConfig::Xtb(account) = source_config else {
+            continue;
+        };
+        let session_id = source_config.session_id();
+        let symbols = portfolio.held_symbols(&session_id);
+        if symbols.is_empty() {
+            continue;
+        }
+        let mut xtb = portfolio
+            .xtb_config(account)
+            .ok_or(error::SourceError::NotConfigured(
+                account.endpoint().unwrap_or("xtb").to_string(),
+            ))?
+            .for_account(account.clone());
+        PositionSource::connect(&mut xtb).await?;
+        PositionSource::authenticate(&mut xtb, encryption_key).await?;
+        let mut stream = open_stream(&xtb, &symbols).await?;
+
+        let sender = sender.clone();
+        tokio::spawn(async move {
+            let mut failures = 0;
+            loop {
+                let error = match stream.next().await {
+                    Ok(tick) => {
+                        failures = 0;
+                        if sender.send(Ok((session_id.clone(), tick))).await.is_err() {
+                            return;
+                        }
+                        continue;
+                    }
+                    Err(e) => e,
+                };
+                failures += 1;
+                if failures > REOPEN_ATTEMPTS {
+                    let _ = sender.send(Err(error)).await;
+                    return;
+                }
+                log::warn!(
+                    "Price stream of {} failed: {}, reopening",
+                    session_id,
+                    error
+                );
+                // Stream is closed by the server once its session is gone, so the session is
+                // checked first, which logs in again if needed
+                let reopened = match xtb.ping().await {
+                    Ok(()) => open_stream(&xtb, &symbols).await,
+                    Err(e) => Err(e),
+                };
+                match reopened {
+                    Ok(reopened) => stream = reopened,
+                    Err(e) => {
+                        let _ = sender.send(Err(e)).await;
+                        return;
+                    }
+                }
+            }
+        });
+    }
+    Ok(receiver)
+}
+
+/// Open price stream of `xtb` session subscribed to prices of `symbols`.
+async fn open_stream(
+    xtb: &XtbConfig,
+    symbols: &BTreeSet<String>,
+) -> Result<PriceStream<Stream>, error::XtbError> {
+    let mut stream = xtb.open_price_stream().await?;
+    for symbol in symbols {
+        stream.subscribe(symbol).await?;
+    }
+    Ok(stream)
+}
+
+/// Positions beyond their drift threshold, to tell when a position crosses it.
+#[derive(Debug, Default)]
+pub struct DriftMonitor {
+    drifted: HashSet<String>,
+}
+
+impl DriftMonitor {
+    /// Record current `allocation`. Returns positions which drifted since the last update.
+    pub fn update(&mut self, allocation: &[Allocation]) -> Vec<String> {
+        let drifted: HashSet<String> = allocation
+            .iter()
+            .filter(|allocation| allocation.drifted)
+            .map(|allocation| allocation.position.clone())
+            .collect();
+        let mut crossed: Vec<String> = drifted.difference(&self.drifted).cloned().collect();
+        crossed.sort();
+        self.drifted = drifted;
+        crossed
+    }
+}
+
+/// Full screen view of `portfolio` allocation, drifted positions highlighted in red.
+pub fn render(portfolio: &Portfolio, allocation: &[Allocation], crossed: &[String]) -> String {
+    let value = portfolio.value();
+    let mut screen = format!(
+        "\x1b[2J\x1b[HTotal value: {:.2} {:?}\nPositions:\n",
+        value.value, value.currency
+    );
+    for allocation in allocation {
+        let line = format!(
+            "- {} [{:4.2} ({:4.2})]",
+            allocation.position, allocation.share, allocation.target
+        );
+        match allocation.drifted {
+            true => screen += &format!("\x1b[1;31m{} drifted\x1b[0m\n", line),
+            false => screen += &format!("{}\n", line),
+        }
+    }
+    if !crossed.is_empty() {
+        // Bell, so that crossing is noticed even if the terminal is in background
+        screen += &format!(
+            "\x07\nDrift threshold crossed:\n- {}\n",
+            crossed.join("\n- ")
+        );
+    }
+    screen
+}
+
+/// Watch `portfolio` until interrupted or a price stream fails.
+pub async fn run(
+    mut portfolio: Portfolio,
+    encryption_key: &Secret,
+) -> Result<(), error::SourceError> {
+    let mut ticks = subscribe(&portfolio, encryption_key).await?;
+    let mut monitor = DriftMonitor::default();
+    let mut allocation = portfolio.allocation();
+    let mut crossed = monitor.update(&allocation);
+    print!("{}", render(&portfolio, &allocation, &crossed));
+
+    while let Some(tick) = ticks.recv().await {
+        let (session_id, tick) = tick?;
+        if !portfolio.update_bid_price(&session_id, &tick.symbol, tick.bid) {
+            continue;
+        }
+        allocation = portfolio.allocation();
+        let newly_crossed = monitor.update(&allocation);
+        if !newly_crossed.is_empty() {
+            crossed = newly_crossed;
+        }
+        print!("{}", render(&portfolio, &allocation, &crossed));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::amount::Currency;
+    use crate::fx::Rates;
+    use crate::testing::PortfolioFile;
+    use crate::xtb::mock::MockXtb;
+
+    #[tokio::test]
+    async fn watch_streamed_prices() {
+        let server = MockXtb::new()
+            .account("111", "password", "USD")
+            .position("111", "SPXS.UK", 5.0)
+            .position("111", "EIMI.UK", 50.0)
+            .stock("SPXS.UK", 100.0, "USD")
+            .stock("EIMI.UK", 10.0, "USD")
+            .start()
+            .await;
+        let file = PortfolioFile::new();
+        let key = &file.key;
+        file.write(&format!(
+            r#"
+config:
+  base_currency: USD
+  drift_threshold: 0.1
+  xtb: {endpoint}
+groups:
+- id: xtb
+  currency: USD
+  source:
+    type: xtb
+    account_id: '111'
+    encrypted_password: {password}
+positions:
+- name: S&P 500
+  group: xtb
+  ticker: SPXS.UK
+  target: 0.5
+- name: Emerging markets
+  group: xtb
+  ticker: EIMI.UK
+  target: 0.5
+  drift_threshold: 0.2
+"#,
+            endpoint = server.endpoint(),
+            password = file.encrypt("password"),
+        ));
+        let rates = Rates {
+            rates: vec![(Currency::USD, 1.0)].into_iter().collect(),
+        };
+        let mut portfolio = file.read(rates).await.unwrap();
+        let mut monitor = DriftMonitor::default();
+        assert!(monitor.update(&portfolio.allocation()).is_empty());
+
+        let mut ticks = subscribe(&portfolio, key).await.unwrap();
+        // Current prices are pushed on subscription and change nothing
+        for _ in 0..2 {
+            let (session_id, tick) = ticks.recv().await.unwrap().unwrap();
+            assert_eq!(session_id, "xtb:111");
+            assert!(!portfolio.update_bid_price(&session_id, &tick.symbol, tick.bid));
+        }
+
+        // 1000 USD of S&P 500 and 500 USD of emerging markets, 0.17 from target
+        server.tick("SPXS.UK", 200.0);
+        let (session_id, tick) = ticks.recv().await.unwrap().unwrap();
+        assert_eq!(tick.symbol, "SPXS.UK");
+        assert!(portfolio.update_bid_price(&session_id, &tick.symbol, tick.bid));
+        assert_eq!(portfolio.value().value, 1500.0);
+
+        let allocation = portfolio.allocation();
+        let drifted: Vec<_> = allocation
+            .iter()
+            .map(|allocation| allocation.drifted)
+            .collect();
+        assert_eq!(drifted, vec![true, false]);
+        let crossed = monitor.update(&allocation);
+        assert_eq!(crossed.len(), 1);
+        assert!(crossed[0].contains("S&P 500"));
+        // Crossing is reported once only
+        assert!(monitor.update(&allocation).is_empty());
+
+        let screen = render(&portfolio, &allocation, &crossed);
+        assert!(screen.contains("Total value: 1500.00 USD"));
+        assert!(screen.contains("\x1b[1;31m- [SPXS.UK ]"));
+    }
+
+    #[tokio::test]
+    async fn watch_reopens_dropped_stream() {
+        let server = MockXtb::new()
+            .account("111", "password", "USD")
+            .position("111", "SPXS.UK", 5.0)
+            .stock("SPXS.UK", 100.0, "USD")
+            .start()
+            .await;
+        let file = PortfolioFile::new();
+        file.write(&format!(
+            r#"
+config:
+  base_currency: USD
+  xtb: {endpoint}
+groups:
+- id: xtb
+  currency: USD
+  source:
+    type: xtb
+    account_id: '111'
+    encrypted_password: {password}
+positions:
+- name: S&P 500
+  group: xtb
+  ticker: SPXS.UK
+  target: 1.0
+"#,
+            endpoint = server.endpoint(),
+            password = file.encrypt("password"),
+        ));
+        let rates = Rates {
+            rates: vec![(Currency::USD, 1.0)].into_iter().collect(),
+        };
+        let portfolio = file.read(rates).await.unwrap();
+        let mut ticks = subscribe(&portfolio, &file.key).await.unwrap();
+        let (_, tick) = ticks.recv().await.unwrap().unwrap();
+        assert_eq!(tick.bid, 100.0);
+
+        let count = |command: &str| {
+            server
+                .received_commands()
+                .iter()
+                .filter(|name| *name == command)
+                .count()
+        };
+        let logins = count("login");
+
+        // Both connections go down and the session with them, so the stream is opened again in
+        // a new session and current price is pushed on resubscription
+        server.drop_all_connections();
+        let (session_id, tick) = ticks.recv().await.unwrap().unwrap();
+        assert_eq!((session_id.as_str(), tick.bid), ("xtb:111", 100.0));
+        server.tick("SPXS.UK", 120.0);
+        let (_, tick) = ticks.recv().await.unwrap().unwrap();
+        assert_eq!(tick.bid, 120.0);
+
+        assert_eq!(count("login"), logins + 1);
+        assert_eq!(count("stream:getTickPrices"), 2);
+    }
+}
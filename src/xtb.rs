@@ -1,16 +1,33 @@
+use std::collections::HashMap;
 use std::str::FromStr;
-use std::sync::{Arc, Mutex, PoisonError};
+use std::sync::{Arc, Weak};
+use std::time::Duration;
 
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
-use tokio::io::BufReader;
+use tokio::io::{AsyncRead, AsyncWrite};
 use tokio::net::TcpStream;
+use tokio::sync::Mutex;
 use tokio_native_tls::TlsConnector;
 use tokio_native_tls::{native_tls, TlsStream};
 
 use crate::amount::{Amount, Currency};
+use crate::broker::{Holding, PositionSource};
 use crate::error;
+use crate::history::{Transaction, TransactionKind};
+use crate::secret::Secret;
+use zeroize::{Zeroize, Zeroizing};
 
 use self::command::get_trades::Trade;
+use self::connection::Connection;
+pub use self::connection::Timing;
+pub use self::stream::{PriceStream, TickPrice};
+
+mod connection;
+#[cfg(test)]
+pub mod mock;
+mod stream;
 
 pub mod command {
     use std::collections::HashMap;
@@ -48,10 +65,10 @@ pub mod command {
         use serde::Deserialize;
         use std::collections::HashMap;
 
-        pub fn login(account_id: &String, password: &String) -> Command {
+        pub fn login(account_id: &str, password: &str) -> Command {
             let mut arguments = HashMap::new();
-            arguments.insert("userId".to_string(), account_id.clone().into());
-            arguments.insert("password".to_string(), password.clone().into());
+            arguments.insert("userId".to_string(), account_id.into());
+            arguments.insert("password".to_string(), password.into());
             Command {
                 command: "login".to_string(),
                 arguments,
@@ -61,6 +78,8 @@ pub mod command {
         #[derive(Debug, Deserialize)]
         pub struct Response {
             pub status: bool,
+            #[serde(rename = "streamSessionId")]
+            pub stream_session_id: Option<String>,
         }
     }
 
@@ -83,6 +102,18 @@ pub mod command {
         }
     }
 
+    pub mod ping {
+        use super::Command;
+        use std::collections::HashMap;
+
+        pub fn ping() -> Command {
+            Command {
+                command: "ping".to_string(),
+                arguments: HashMap::new(),
+            }
+        }
+    }
+
     pub mod get_trades {
         use serde::Deserialize;
 
@@ -98,6 +129,7 @@ pub mod command {
             }
         }
 
+        #[allow(dead_code)]
         #[derive(Debug, Deserialize)]
         pub struct Response {
             pub status: bool,
@@ -105,10 +137,59 @@ pub mod command {
             pub return_data: Option<Vec<Trade>>,
         }
 
+        /// Trade operation codes (`cmd`) of trade records
+        pub const BUY: i64 = 0;
+        pub const SELL: i64 = 1;
+        /// Cash deposit or withdrawal, reported among closed trades
+        pub const BALANCE: i64 = 6;
+
+        /// Trade record (xAPI `TRADE_RECORD`), fields not used by all commands are optional.
         #[derive(Debug, Deserialize)]
         pub struct Trade {
             pub symbol: Option<String>,
             pub volume: f64,
+            #[serde(default)]
+            pub cmd: Option<i64>,
+            /// Order number of the opening transaction
+            #[serde(default)]
+            pub order: Option<i64>,
+            /// Order number of the closing transaction
+            #[serde(default)]
+            pub order2: Option<i64>,
+            #[serde(default)]
+            pub open_price: Option<f64>,
+            /// Milliseconds since epoch
+            #[serde(default)]
+            pub open_time: Option<i64>,
+            #[serde(default)]
+            pub close_price: Option<f64>,
+            #[serde(default)]
+            pub close_time: Option<i64>,
+            #[serde(default)]
+            pub closed: Option<bool>,
+            /// Profit of an open trade, in account currency
+            #[serde(default)]
+            pub profit: Option<f64>,
+            /// Value of the traded instruments in account currency, 0 if not applicable
+            #[serde(default, rename = "nominalValue")]
+            pub nominal_value: Option<f64>,
+        }
+    }
+
+    pub mod get_trades_history {
+        use super::Command;
+        use std::collections::HashMap;
+
+        /// Trades closed since `start` (milliseconds since epoch) until now.
+        pub fn get_trades_history(start: i64) -> Command {
+            let mut arguments = HashMap::new();
+            arguments.insert("start".to_string(), start.into());
+            // 0 means current time
+            arguments.insert("end".to_string(), 0.into());
+            Command {
+                command: "getTradesHistory".to_string(),
+                arguments,
+            }
         }
     }
 
@@ -133,12 +214,180 @@ pub mod command {
             pub return_data: Vec<SymbolRecord>,
         }
 
+        #[allow(dead_code)]
         #[derive(Debug, Deserialize)]
         pub struct SymbolRecord {
+            pub ask: f64,
             pub bid: f64,
             pub symbol: String,
             #[serde(rename = "currencyProfit")]
             pub currency_profit_symbol: String,
+            /// E.g. `STC` (stocks), `ETF`, `IND` (indices), `FX`, `CMD` (commodities)
+            #[serde(default, rename = "categoryName")]
+            pub category_name: Option<String>,
+            /// Units of the instrument in one lot
+            #[serde(default, rename = "contractSize")]
+            pub contract_size: Option<f64>,
+            /// Margin requirement in percent, 100 for instruments bought outright
+            #[serde(default)]
+            pub leverage: Option<f64>,
+        }
+
+        impl SymbolRecord {
+            /// Whether trades of the symbol are CFDs bought on margin rather than the
+            /// instruments themselves.
+            pub fn leveraged(&self) -> bool {
+                let category = self.category_name.as_deref().unwrap_or("STC");
+                !matches!(category, "STC" | "ETF")
+                    || self.leverage.is_some_and(|leverage| leverage < 100.0)
+            }
+
+            pub fn contract_size(&self) -> f64 {
+                self.contract_size.unwrap_or(1.0)
+            }
+        }
+    }
+
+    pub mod get_symbol {
+        use serde::Deserialize;
+
+        use super::get_all_symbols::SymbolRecord;
+        use super::Command;
+        use std::collections::HashMap;
+
+        pub fn get_symbol(symbol: &str) -> Command {
+            let mut arguments = HashMap::new();
+            arguments.insert("symbol".to_string(), symbol.into());
+            Command {
+                command: "getSymbol".to_string(),
+                arguments,
+            }
+        }
+
+        #[derive(Debug, Deserialize)]
+        pub struct Response {
+            pub status: bool,
+            #[serde(rename = "returnData")]
+            pub return_data: SymbolRecord,
+        }
+    }
+
+    pub mod get_margin_level {
+        use serde::Deserialize;
+
+        use super::Command;
+        use std::collections::HashMap;
+
+        pub fn get_margin_level() -> Command {
+            Command {
+                command: "getMarginLevel".to_string(),
+                arguments: HashMap::new(),
+            }
+        }
+
+        #[derive(Debug, Deserialize)]
+        pub struct Response {
+            pub status: bool,
+            #[serde(rename = "returnData")]
+            pub return_data: MarginLevel,
+        }
+
+        #[allow(dead_code)]
+        #[derive(Debug, Deserialize)]
+        pub struct MarginLevel {
+            pub balance: f64,
+            pub currency: String,
+            pub equity: f64,
+            pub margin_free: f64,
+        }
+    }
+
+    pub mod trade_transaction {
+        use serde::Deserialize;
+        use serde_json::json;
+
+        use super::Command;
+        use std::collections::HashMap;
+
+        /// `cmd` of a buy order
+        pub const BUY: u8 = 0;
+        /// `type` of an order opening a position
+        pub const OPEN: u8 = 0;
+
+        pub fn trade_transaction(cmd: u8, symbol: &str, volume: f64, price: f64) -> Command {
+            let mut arguments = HashMap::new();
+            arguments.insert(
+                "tradeTransInfo".to_string(),
+                json!({
+                    "cmd": cmd,
+                    "customComment": "portfel",
+                    "expiration": 0,
+                    "offset": 0,
+                    "order": 0,
+                    "price": price,
+                    "sl": 0.0,
+                    "symbol": symbol,
+                    "tp": 0.0,
+                    "type": OPEN,
+                    "volume": volume,
+                }),
+            );
+            Command {
+                command: "tradeTransaction".to_string(),
+                arguments,
+            }
+        }
+
+        #[derive(Debug, Deserialize)]
+        pub struct Response {
+            pub status: bool,
+            #[serde(rename = "returnData")]
+            pub return_data: TradeTransaction,
+        }
+
+        #[derive(Debug, Deserialize)]
+        pub struct TradeTransaction {
+            pub order: i64,
+        }
+    }
+
+    pub mod trade_transaction_status {
+        use serde::Deserialize;
+
+        use super::Command;
+        use std::collections::HashMap;
+
+        #[allow(dead_code)]
+        pub const ERROR: u8 = 0;
+        pub const PENDING: u8 = 1;
+        pub const ACCEPTED: u8 = 3;
+        pub const REJECTED: u8 = 4;
+
+        pub fn trade_transaction_status(order: i64) -> Command {
+            let mut arguments = HashMap::new();
+            arguments.insert("order".to_string(), order.into());
+            Command {
+                command: "tradeTransactionStatus".to_string(),
+                arguments,
+            }
+        }
+
+        #[derive(Debug, Deserialize)]
+        pub struct Response {
+            pub status: bool,
+            #[serde(rename = "returnData")]
+            pub return_data: TradeTransactionStatus,
+        }
+
+        #[allow(dead_code)]
+        #[derive(Debug, Deserialize)]
+        pub struct TradeTransactionStatus {
+            pub order: i64,
+            #[serde(rename = "requestStatus")]
+            pub request_status: u8,
+            pub message: Option<String>,
+            pub ask: f64,
+            pub bid: f64,
         }
     }
 
@@ -166,35 +415,89 @@ pub mod command {
         #[allow(dead_code)]
         #[derive(Debug, Deserialize)]
         pub struct CurrentUserData {
+            #[serde(rename = "currency")]
             pub currency_symbol: String,
         }
     }
 }
 
-#[derive(Debug)]
-pub struct PositionMarketValue {
-    pub symbol: String,
-    pub volume: f64,
-    pub bid_price: Amount,
-    pub market_value: Amount,
+/// Transport of xAPI connections.
+pub type Stream = TlsStream<TcpStream>;
+
+/// Maximum number of `tradeTransactionStatus` polls while waiting for an order to leave pending state.
+const ORDER_STATUS_POLLS: u32 = 50;
+
+/// Public xAPI servers of XTB.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum XtbServer {
+    Demo,
+    Real,
 }
 
-type Stream = BufReader<TlsStream<TcpStream>>;
+impl XtbServer {
+    pub const HOST: &'static str = "xapi.xtb.com";
+
+    pub fn port(self) -> u16 {
+        match self {
+            XtbServer::Demo => 5124,
+            XtbServer::Real => 5112,
+        }
+    }
 
+    pub fn streaming_port(self) -> u16 {
+        match self {
+            XtbServer::Demo => 5125,
+            XtbServer::Real => 5113,
+        }
+    }
+}
+
+/// Endpoint of xAPI, either one of the public XTB servers or any other host.
+///
+/// Host and ports not given explicitly are those of `server`, or of the real server if that is
+/// not given either.
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct XtbConfig {
-    host: String,
-    port: u16,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    server: Option<XtbServer>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    host: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    port: Option<u16>,
+    /// PEM file with additional trusted root certificate, e.g. of a local test server
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    ca_certificate: Option<String>,
+    /// Port of the streaming server, next to `port` if only that is given
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    streaming_port: Option<u16>,
+    #[serde(skip)]
+    stream: Option<Arc<Mutex<Connection<Stream>>>>,
+    /// Keepalive of `stream`, once logged in
+    #[serde(skip)]
+    keepalive: Option<Arc<Keepalive>>,
     #[serde(skip)]
-    stream: Option<Arc<Mutex<Stream>>>,
+    timing: Timing,
+    /// Account logged in on `stream`, with decrypted password, used to log in again on reconnect
+    #[serde(skip)]
+    session: Option<XtbAccount>,
+    /// Account used when acting as a `PositionSource`
+    #[serde(skip)]
+    account: Option<XtbAccount>,
+    /// Identifies the logged in session to the streaming server, replaced on reconnect
+    #[serde(skip)]
+    stream_session_id: Arc<std::sync::Mutex<Option<String>>>,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct XtbAccount {
+    /// Name of the endpoint in portfolio config the account is at, legacy `xtb` config if not given
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    endpoint: Option<String>,
     account_id: String,
     encrypted_password: Option<String>,
     #[serde(skip)]
-    password: Option<String>,
+    password: Option<Secret>,
 }
 
 impl XtbAccount {
@@ -202,132 +505,398 @@ impl XtbAccount {
     pub fn new(
         account_id: String,
         encrypted_password: Option<String>,
-        password: Option<String>,
+        password: Option<Secret>,
     ) -> Self {
         Self {
-            account_id: account_id,
-            encrypted_password: encrypted_password,
-            password: password,
+            endpoint: None,
+            account_id,
+            encrypted_password,
+            password,
         }
     }
 
-    pub fn decrypt(&self, key: &str) -> Result<Self, error::XtbError> {
+    /// Same account, at endpoint named `endpoint`.
+    pub fn at_endpoint(self, endpoint: String) -> Self {
+        Self {
+            endpoint: Some(endpoint),
+            ..self
+        }
+    }
+
+    pub fn account_id(&self) -> &str {
+        &self.account_id
+    }
+
+    pub fn endpoint(&self) -> Option<&str> {
+        self.endpoint.as_deref()
+    }
+
+    #[allow(dead_code)]
+    pub fn password(&self) -> Option<&Secret> {
+        self.password.as_ref()
+    }
+
+    pub fn decrypt(&self, key: &Secret) -> Result<Self, error::XtbError> {
         match &self.encrypted_password {
-            Some(encrypted_password) => {
-                let password = crate::crypt::decrypt_text(&encrypted_password, key)?;
-                Ok(Self {
-                    password: Some(password),
-                    encrypted_password: None,
-                    ..self.clone()
-                })
-            }
+            Some(encrypted_password) => Ok(Self {
+                endpoint: self.endpoint.clone(),
+                account_id: self.account_id.clone(),
+                encrypted_password: None,
+                password: Some(Secret::new(crate::crypt::decrypt_text(
+                    encrypted_password,
+                    key.expose(),
+                )?)),
+            }),
             None => Err(error::XtbError::PasswordMissing),
         }
     }
 
-    pub fn encrypt(&self, key: &str) -> Result<Self, error::XtbError> {
+    pub fn encrypt(&self, key: &Secret) -> Result<Self, error::XtbError> {
         match &self.password {
-            Some(password) => {
-                let encrypted_password = crate::crypt::encrypt_text(&password, key)?;
-                Ok(Self {
-                    password: None,
-                    encrypted_password: Some(encrypted_password),
-                    ..self.clone()
-                })
-            }
+            Some(password) => Ok(Self {
+                endpoint: self.endpoint.clone(),
+                account_id: self.account_id.clone(),
+                encrypted_password: Some(crate::crypt::encrypt_text(
+                    password.expose(),
+                    key.expose(),
+                )?),
+                password: None,
+            }),
             None => Err(error::XtbError::PasswordMissing),
         }
     }
 }
 
+/* Log in on `connection`, wiping the password from the command once sent */
+async fn login_on<S: AsyncRead + AsyncWrite + Unpin>(
+    connection: &mut Connection<S>,
+    account: &XtbAccount,
+) -> Result<Option<String>, error::XtbError> {
+    let password = account
+        .password
+        .as_ref()
+        .ok_or(error::XtbError::PasswordMissing)?;
+    let mut command = command::login::login(&account.account_id, password.expose());
+    let json_string = Zeroizing::new(serde_json::to_string(&command)?);
+    if let Some(serde_json::Value::String(password)) = command.arguments.get_mut("password") {
+        password.zeroize();
+    }
+
+    let response = connection
+        .request(&command.command, &json_string)
+        .await
+        .map_err(|e| match e {
+            error::XtbError::XtbApiError(_, _) => error::XtbError::AuthenticationError,
+            e => e,
+        })?;
+    let response: command::login::Response = serde_json::from_str(&response)?;
+    match response.status {
+        false => Err(error::XtbError::AuthenticationError),
+        true => Ok(response.stream_session_id),
+    }
+}
+
+/// Keepalive task of a connection, aborted once the last handle to it is dropped.
+#[derive(Debug)]
+struct Keepalive(tokio::task::JoinHandle<()>);
+
+impl Drop for Keepalive {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+/* Ping server whenever connection is idle for `ping_interval`, until the connection is dropped */
+fn spawn_keepalive<S: AsyncRead + AsyncWrite + Unpin + Send + 'static>(
+    connection: Weak<Mutex<Connection<S>>>,
+    ping_interval: Duration,
+) -> Keepalive {
+    Keepalive(tokio::spawn(async move {
+        let mut idle_for = Duration::ZERO;
+        loop {
+            tokio::time::sleep(ping_interval - idle_for).await;
+            let Some(connection) = connection.upgrade() else {
+                return;
+            };
+            let mut connection = connection.lock().await;
+            idle_for = connection.idle_for();
+            if idle_for >= ping_interval {
+                let command = command::ping::ping();
+                // Failed connection is re-established by the next regular command
+                if let Err(e) = connection
+                    .request(&command.command, &serde_json::to_string(&command).unwrap())
+                    .await
+                {
+                    log::warn!("XTB keepalive failed: {}", e);
+                }
+                idle_for = Duration::ZERO;
+            }
+        }
+    }))
+}
+
 impl XtbConfig {
     #[allow(dead_code)]
     pub fn new(host: String, port: u16) -> Self {
         Self {
-            host,
-            port,
+            server: None,
+            host: Some(host),
+            port: Some(port),
+            ca_certificate: None,
+            streaming_port: None,
             stream: None,
+            keepalive: None,
+            timing: Timing::default(),
+            session: None,
+            account: None,
+            stream_session_id: Arc::default(),
         }
     }
 
-    /* Send arbitrary command that implements Serialize */
-    async fn send_command<T: Serialize>(&self, command: T) -> Result<String, error::XtbError> {
-        if let Some(stream) = self.stream.clone() {
-            let mut stream = stream.lock().unwrap_or_else(PoisonError::into_inner);
+    #[allow(dead_code)]
+    pub fn with_ca_certificate(self, ca_certificate: String) -> Self {
+        Self {
+            ca_certificate: Some(ca_certificate),
+            ..self
+        }
+    }
 
-            // Serialize the JSON command to a string
-            let json_string = serde_json::to_string(&command).unwrap();
+    /// Config of one of the public XTB servers.
+    pub fn for_server(server: XtbServer) -> Self {
+        Self::new(XtbServer::HOST.to_string(), server.port()).with_server(server)
+    }
 
-            // Send the JSON command to the server
-            tokio::io::AsyncWriteExt::write_all(&mut *stream, json_string.as_bytes()).await?;
+    /// Same config, but connecting to one of the public XTB servers.
+    pub fn with_server(self, server: XtbServer) -> Self {
+        Self {
+            server: Some(server),
+            host: None,
+            port: None,
+            streaming_port: None,
+            ..self
+        }
+    }
 
-            // Read the response from the server until two newline characters are encountered
-            let mut response_str = String::new();
-            loop {
-                let bytes_read =
-                    tokio::io::AsyncBufReadExt::read_line(&mut *stream, &mut response_str).await?;
-                if bytes_read == 0 || response_str.ends_with("\n\n") {
-                    break;
-                }
-            }
+    /// Public XTB server this config connects to, judged by the host and port it resolves to, so
+    /// explicit host and port override the `server` preset.
+    pub fn server(&self) -> Option<XtbServer> {
+        if !self.host().eq_ignore_ascii_case(XtbServer::HOST) {
+            return None;
+        }
+        [XtbServer::Demo, XtbServer::Real]
+            .into_iter()
+            .find(|server| server.port() == self.port())
+    }
+
+    /// Whether host or port is given explicitly rather than by a `server` preset.
+    pub fn has_address(&self) -> bool {
+        self.host.is_some() || self.port.is_some()
+    }
+
+    fn host(&self) -> &str {
+        self.host.as_deref().unwrap_or(XtbServer::HOST)
+    }
+
+    fn port(&self) -> u16 {
+        self.port
+            .unwrap_or(self.server.unwrap_or(XtbServer::Real).port())
+    }
+
+    fn streaming_port(&self) -> u16 {
+        match (self.streaming_port, self.port) {
+            (Some(streaming_port), _) => streaming_port,
+            (None, Some(port)) => port + 1,
+            (None, None) => self.server.unwrap_or(XtbServer::Real).streaming_port(),
+        }
+    }
+
+    #[allow(dead_code)]
+    pub fn with_streaming_port(self, streaming_port: u16) -> Self {
+        Self {
+            streaming_port: Some(streaming_port),
+            ..self
+        }
+    }
+
+    #[allow(dead_code)]
+    pub fn with_timing(self, timing: Timing) -> Self {
+        Self { timing, ..self }
+    }
 
-            log::debug!("Response: {}", response_str);
+    /// Copy of this config acting as a position source for `account`.
+    pub fn for_account(&self, account: XtbAccount) -> Self {
+        Self {
+            server: self.server,
+            host: self.host.clone(),
+            port: self.port,
+            ca_certificate: self.ca_certificate.clone(),
+            streaming_port: self.streaming_port,
+            stream: None,
+            keepalive: None,
+            timing: self.timing.clone(),
+            session: None,
+            account: Some(account),
+            stream_session_id: Arc::default(),
+        }
+    }
 
-            /* Parse response into generic API response and check the status - if error, fail.
-            Otherwise return original string */
-            let generic_api_response =
-                serde_json::from_str::<command::GenericResponse>(&response_str)?;
-            match generic_api_response.status {
-                true => Ok(response_str),
-                false => Err(generic_api_response.to_xtb_api_error()),
+    /* Send command, reconnecting and sending it again if the connection fails */
+    async fn send_command(&self, command: &command::Command) -> Result<String, error::XtbError> {
+        let stream = self.stream.clone().ok_or(error::XtbError::NotConnected)?;
+        let mut connection = stream.lock().await;
+        let json_string = Zeroizing::new(serde_json::to_string(command)?);
+
+        match connection.request(&command.command, &json_string).await {
+            Err(e) if e.is_transient() && self.session.is_some() => {
+                log::warn!("XTB connection failed: {}, reconnecting", e);
+                *connection = self.reconnect().await?;
+                connection.request(&command.command, &json_string).await
             }
-        } else {
-            return Err(error::XtbError::NotConnected);
+            result => result,
         }
     }
 
-    pub async fn connect(&mut self) -> Result<(), error::XtbError> {
-        // Connect to the server
-        let tcp_stream = TcpStream::connect((self.host.clone(), self.port)).await?;
+    /* Send command exactly once, for commands that must not be repeated on a new connection.
+     * Connection broken by an earlier command is replaced first, since this command was not
+     * sent on it yet. */
+    async fn send_command_once(
+        &self,
+        command: &command::Command,
+    ) -> Result<String, error::XtbError> {
+        let stream = self.stream.clone().ok_or(error::XtbError::NotConnected)?;
+        let mut connection = stream.lock().await;
+        let json_string = Zeroizing::new(serde_json::to_string(command)?);
+        if connection.is_broken() && self.session.is_some() {
+            log::warn!("XTB connection broken by an earlier command, reconnecting");
+            *connection = self.reconnect().await?;
+        }
+        connection.request(&command.command, &json_string).await
+    }
+
+    async fn open_connection(&self) -> Result<Connection<Stream>, error::XtbError> {
+        self.open_connection_to(self.port()).await
+    }
 
-        // Use a TlsConnector to establish an SSL/TLS connection
-        let tls_connector = TlsConnector::from(native_tls::TlsConnector::new()?);
-        let tls_stream = tls_connector.connect(&self.host, tcp_stream).await?;
+    async fn open_connection_to(&self, port: u16) -> Result<Connection<Stream>, error::XtbError> {
+        let open = async {
+            let tcp_stream = TcpStream::connect((self.host(), port)).await?;
 
-        // Wrap the stream in a BufReader and BufWriter for efficient reading and writing
-        let reader = BufReader::new(tls_stream);
-        self.stream = Some(Arc::new(Mutex::new(reader)));
+            let mut tls_connector = native_tls::TlsConnector::builder();
+            if let Some(ca_certificate) = &self.ca_certificate {
+                tls_connector.add_root_certificate(native_tls::Certificate::from_pem(
+                    &std::fs::read(ca_certificate)?,
+                )?);
+            }
+            let tls_connector = TlsConnector::from(tls_connector.build()?);
+            let tls_stream = tls_connector.connect(self.host(), tcp_stream).await?;
+            Ok(Connection::new(tls_stream, self.timing.clone()))
+        };
+        tokio::time::timeout(self.timing.connect_timeout, open)
+            .await
+            .map_err(|_| error::XtbError::ConnectTimeout)?
+    }
+
+    /* Open new connection and log in again as the session account, with exponential backoff.
+     * Stream session id of the new session replaces the old one. */
+    async fn reconnect(&self) -> Result<Connection<Stream>, error::XtbError> {
+        let attempts = self.timing.reconnect_attempts;
+        let mut backoff = self.timing.reconnect_backoff;
+        let mut last_error = error::XtbError::NotConnected;
+        for attempt in 1..=attempts {
+            tokio::time::sleep(backoff).await;
+            backoff *= 2;
+
+            let session = async {
+                let mut connection = self.open_connection().await?;
+                let stream_session_id = match &self.session {
+                    Some(account) => login_on(&mut connection, account).await?,
+                    None => None,
+                };
+                Ok::<_, error::XtbError>((connection, stream_session_id))
+            };
+            match session.await {
+                Ok((connection, stream_session_id)) => {
+                    // Streams of the old session end with it and must be opened with the new id
+                    *self.stream_session_id.lock().unwrap() = stream_session_id;
+                    return Ok(connection);
+                }
+                Err(e) if e.is_transient() => {
+                    log::warn!("XTB reconnect attempt {} failed: {}", attempt, e);
+                    last_error = e;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        Err(error::XtbError::ReconnectFailed(
+            attempts,
+            Box::new(last_error),
+        ))
+    }
 
+    pub async fn connect(&mut self) -> Result<(), error::XtbError> {
+        let connection = self.open_connection().await?;
+        self.stream = Some(Arc::new(Mutex::new(connection)));
+        self.keepalive = None;
+        self.session = None;
         Ok(())
     }
 
     pub async fn disconnect(&mut self) -> Result<(), error::XtbError> {
-        self.logout().await?;
+        let result = self.logout().await;
         self.stream = None;
-        Ok(())
+        self.keepalive = None;
+        self.session = None;
+        match result {
+            // Session is gone either way
+            Err(e) if e.is_transient() => {
+                log::warn!("XTB logout failed: {}", e);
+                Ok(())
+            }
+            result => result,
+        }
     }
 
     pub async fn login(&mut self, account: &XtbAccount) -> Result<(), error::XtbError> {
-        match &account.password {
-            Some(password) => {
-                let command = command::login::login(&account.account_id, &password);
-                let response = self
-                    .send_command(command)
-                    .await
-                    .map_err(|_| error::XtbError::AuthenticationError)?;
-                let response: command::login::Response = serde_json::from_str(&response)?;
-                match response.status {
-                    false => Err(error::XtbError::AuthenticationError),
-                    true => Ok(()),
-                }
-            }
-            None => Err(error::XtbError::PasswordMissing),
-        }
+        let stream = self.stream.clone().ok_or(error::XtbError::NotConnected)?;
+        *self.stream_session_id.lock().unwrap() =
+            login_on(&mut *stream.lock().await, account).await?;
+        self.session = Some(account.clone());
+        // Replaces keepalive of an earlier login on this connection
+        self.keepalive = Some(Arc::new(spawn_keepalive(
+            Arc::downgrade(&stream),
+            self.timing.ping_interval,
+        )));
+        Ok(())
+    }
+
+    /// Open streaming connection of the logged in session.
+    ///
+    /// Stream lasts only as long as the session, so this config must stay connected meanwhile.
+    /// Once the session is replaced on reconnect, a new stream has to be opened.
+    pub async fn open_price_stream(&self) -> Result<PriceStream<Stream>, error::XtbError> {
+        let stream_session_id = self
+            .stream_session_id
+            .lock()
+            .unwrap()
+            .clone()
+            .ok_or(error::XtbError::NotConnected)?;
+        let connection = self.open_connection_to(self.streaming_port()).await?;
+        Ok(PriceStream::new(
+            connection,
+            stream_session_id,
+            self.timing.ping_interval,
+        ))
+    }
+
+    /// Check that the session is alive, logging in again on a new connection if it is not.
+    pub async fn ping(&self) -> Result<(), error::XtbError> {
+        self.send_command(&command::ping::ping()).await?;
+        Ok(())
     }
 
     pub async fn logout(&mut self) -> Result<(), error::XtbError> {
         let command = command::logout::logout();
-        let response = self.send_command(command).await?;
+        let response = self.send_command_once(&command).await?;
         let response: command::logout::Response = serde_json::from_str(&response)?;
         match response.status {
             false => Err(error::XtbError::UnknownError),
@@ -336,7 +905,19 @@ impl XtbConfig {
     }
 
     async fn get_trades(&self, opened_only: bool) -> Result<Vec<Trade>, error::XtbError> {
-        let command = command::get_trades::get_trades(opened_only);
+        self.get_trade_records(&command::get_trades::get_trades(opened_only))
+            .await
+    }
+
+    async fn get_trades_history(&self, start: i64) -> Result<Vec<Trade>, error::XtbError> {
+        self.get_trade_records(&command::get_trades_history::get_trades_history(start))
+            .await
+    }
+
+    async fn get_trade_records(
+        &self,
+        command: &command::Command,
+    ) -> Result<Vec<Trade>, error::XtbError> {
         let response_str = self.send_command(command).await?;
         let response: command::GenericResponse = serde_json::from_str(&response_str)?;
         match response.status {
@@ -354,11 +935,12 @@ impl XtbConfig {
         }
     }
 
+    #[allow(dead_code)]
     async fn get_all_symbols(
         &self,
     ) -> Result<Vec<command::get_all_symbols::SymbolRecord>, error::XtbError> {
         let command = command::get_all_symbols::get_all_symbols();
-        let response_str = self.send_command(command).await?;
+        let response_str = self.send_command(&command).await?;
         let response: command::get_all_symbols::Response = serde_json::from_str(&response_str)?;
         match response.status {
             false => Err(error::XtbError::UnknownError),
@@ -366,12 +948,25 @@ impl XtbConfig {
         }
     }
 
+    pub async fn get_symbol(
+        &self,
+        symbol: &str,
+    ) -> Result<command::get_all_symbols::SymbolRecord, error::XtbError> {
+        let command = command::get_symbol::get_symbol(symbol);
+        let response_str = self.send_command(&command).await?;
+        let response: command::get_symbol::Response = serde_json::from_str(&response_str)?;
+        match response.status {
+            false => Err(error::XtbError::UnknownError),
+            true => Ok(response.return_data),
+        }
+    }
+
     #[allow(dead_code)]
     async fn get_current_user_data(
         &self,
     ) -> Result<command::get_current_user_data::CurrentUserData, error::XtbError> {
         let command = command::get_current_user_data::get_current_user_data();
-        let response = self.send_command(command).await?;
+        let response = self.send_command(&command).await?;
         let response: command::get_current_user_data::Response = serde_json::from_str(&response)?;
         match response.status {
             false => Err(error::XtbError::UnknownError),
@@ -379,51 +974,695 @@ impl XtbConfig {
         }
     }
 
-    pub async fn get_position_market_values(
+    async fn get_margin_level(
         &self,
-    ) -> Result<Vec<PositionMarketValue>, error::XtbError> {
-        let trades = self.get_trades(true).await?;
+    ) -> Result<command::get_margin_level::MarginLevel, error::XtbError> {
+        let command = command::get_margin_level::get_margin_level();
+        let response = self.send_command(&command).await?;
+        let response: command::get_margin_level::Response = serde_json::from_str(&response)?;
+        match response.status {
+            false => Err(error::XtbError::UnknownError),
+            true => Ok(response.return_data),
+        }
+    }
 
-        let symbol_records = self.get_all_symbols().await?;
+    /// Place market buy order of `volume` lots of `symbol` at current `price`.
+    ///
+    /// Returns order number. The command is sent only once, since repeating it after a lost
+    /// connection could place the order twice.
+    pub async fn buy_market(
+        &self,
+        symbol: &str,
+        volume: f64,
+        price: f64,
+    ) -> Result<i64, error::XtbError> {
+        let command = command::trade_transaction::trade_transaction(
+            command::trade_transaction::BUY,
+            symbol,
+            volume,
+            price,
+        );
+        let response = self.send_command_once(&command).await?;
+        let response: command::trade_transaction::Response = serde_json::from_str(&response)?;
+        match response.status {
+            false => Err(error::XtbError::UnknownError),
+            true => Ok(response.return_data.order),
+        }
+    }
 
-        let mut position_market_values = vec![];
+    pub async fn get_trade_status(
+        &self,
+        order: i64,
+    ) -> Result<command::trade_transaction_status::TradeTransactionStatus, error::XtbError> {
+        let command = command::trade_transaction_status::trade_transaction_status(order);
+        let response = self.send_command(&command).await?;
+        let response: command::trade_transaction_status::Response =
+            serde_json::from_str(&response)?;
+        match response.status {
+            false => Err(error::XtbError::UnknownError),
+            true => Ok(response.return_data),
+        }
+    }
 
-        for trade in trades {
-            let symbol_record = symbol_records
-                .iter()
-                .find(|tick_price| tick_price.symbol == trade.symbol.clone().unwrap())
-                .unwrap();
-
-            if let Ok(currency) = Currency::from_str(&symbol_record.currency_profit_symbol) {
-                position_market_values.push(PositionMarketValue {
-                    symbol: trade.symbol.unwrap(),
-                    volume: trade.volume,
-                    bid_price: Amount::new(currency.clone(), symbol_record.bid),
-                    market_value: Amount::new(currency.clone(), trade.volume * symbol_record.bid),
+    /// Poll status of `order` until it is no longer pending, giving up after `ORDER_STATUS_POLLS`.
+    pub async fn wait_for_order(
+        &self,
+        order: i64,
+    ) -> Result<command::trade_transaction_status::TradeTransactionStatus, error::XtbError> {
+        let mut status = self.get_trade_status(order).await?;
+        for _ in 1..ORDER_STATUS_POLLS {
+            if status.request_status != command::trade_transaction_status::PENDING {
+                break;
+            }
+            status = self.get_trade_status(order).await?;
+        }
+        Ok(status)
+    }
+
+    /// Cash balance of the account, in account currency.
+    pub async fn get_cash_balance(&self) -> Result<Amount, error::XtbError> {
+        let margin_level = self.get_margin_level().await?;
+        let currency = Currency::from_str(&margin_level.currency)
+            .map_err(|_| error::XtbError::UnknownCurrency(margin_level.currency.clone()))?;
+        Ok(Amount::new(currency, margin_level.balance))
+    }
+
+    /// Deposits, withdrawals, buys and sells since `since`, or during the whole account history.
+    ///
+    /// Open trades are reported with their opening transaction, closed trades also with the
+    /// closing one. Opening transactions are numbered by `order`, closing ones by `order2`.
+    pub async fn get_transactions(
+        &self,
+        since: Option<DateTime<Utc>>,
+    ) -> Result<Vec<Transaction>, error::XtbError> {
+        use command::get_trades::{BALANCE, BUY, SELL};
+
+        let account_currency = self.get_cash_balance().await?.currency;
+        let mut records = self.get_trades(true).await?;
+        // Start 0 would return the last month only
+        let start = since.map_or(1, |since| since.timestamp_millis().max(1));
+        records.extend(self.get_trades_history(start).await?);
+
+        let mut currencies = HashMap::new();
+        let mut transactions = vec![];
+        for record in records {
+            let cmd = required(record.cmd, "cmd")?;
+            if cmd == BALANCE {
+                let profit = required(record.profit, "profit")?;
+                transactions.push(Transaction {
+                    order: required(record.order, "order")?.to_string(),
+                    kind: match profit >= 0.0 {
+                        true => TransactionKind::Deposit,
+                        false => TransactionKind::Withdrawal,
+                    },
+                    time: timestamp(record.close_time.or(record.open_time))?,
+                    symbol: None,
+                    volume: None,
+                    price: None,
+                    amount: Amount::new(account_currency, profit.abs()),
                 });
-            } else {
-                log::warn!("Unknown currency: {}", symbol_record.currency_profit_symbol)
+                continue;
+            }
+            // Pending orders and other operations did not move any cash
+            if cmd != BUY && cmd != SELL {
+                continue;
+            }
+            let symbol = required(record.symbol, "symbol")?;
+            if !currencies.contains_key(&symbol) {
+                let currency_symbol = self.get_symbol(&symbol).await?.currency_profit_symbol;
+                let currency = Currency::from_str(&currency_symbol)
+                    .map_err(|_| error::XtbError::UnknownCurrency(currency_symbol))?;
+                currencies.insert(symbol.clone(), currency);
+            }
+            let currency = currencies[&symbol];
+            let trade = |order, kind, time, price: f64| Transaction {
+                order,
+                kind,
+                time,
+                symbol: Some(symbol.clone()),
+                volume: Some(record.volume),
+                price: Some(Amount::new(currency, price)),
+                amount: Amount::new(currency, price * record.volume),
+            };
+            let (opening, closing) = match cmd == BUY {
+                true => (TransactionKind::Buy, TransactionKind::Sell),
+                false => (TransactionKind::Sell, TransactionKind::Buy),
+            };
+            transactions.push(trade(
+                required(record.order, "order")?.to_string(),
+                opening,
+                timestamp(record.open_time)?,
+                required(record.open_price, "open_price")?,
+            ));
+            if record.closed == Some(true) {
+                transactions.push(trade(
+                    required(record.order2, "order2")?.to_string(),
+                    closing,
+                    timestamp(record.close_time)?,
+                    required(record.close_price, "close_price")?,
+                ));
             }
         }
+        Ok(transactions)
+    }
+
+    /// Open trades valued by their market exposure and by their contribution to account equity.
+    ///
+    /// Both are the same for instruments bought outright. A leveraged trade (CFD) contributes only
+    /// its profit to equity, since its margin stays in cash balance, while its exposure is its
+    /// nominal value.
+    pub async fn get_position_market_values(&self) -> Result<Vec<Holding>, error::XtbError> {
+        use command::get_trades::SELL;
+
+        let trades = self.get_trades(true).await?;
+
+        let mut position_market_values = vec![];
+        // Fetch prices only for held symbols, once per symbol
+        let mut symbol_records = HashMap::new();
+        // Profit and nominal value are in account currency, needed for leveraged trades only
+        let mut account_currency = None;
+        for trade in trades {
+            let symbol = trade.symbol.ok_or(error::XtbError::InvalidResponse(
+                "open trade without symbol".to_string(),
+            ))?;
+            if !symbol_records.contains_key(&symbol) {
+                symbol_records.insert(symbol.clone(), self.get_symbol(&symbol).await?);
+            }
+            let symbol_record = &symbol_records[&symbol];
+
+            // Holding priced in unknown currency can't be valued, the rest of the account still can
+            let Ok(currency) = Currency::from_str(&symbol_record.currency_profit_symbol) else {
+                log::warn!(
+                    "Skipping XTB holding of {} priced in unsupported currency {}",
+                    symbol,
+                    symbol_record.currency_profit_symbol
+                );
+                continue;
+            };
+            // Short trades (possible with CFDs only) decrease both exposure and quantity held
+            let direction = match trade.cmd {
+                Some(SELL) => -1.0,
+                _ => 1.0,
+            };
+            let quantity = direction * trade.volume * symbol_record.contract_size();
+            let price = Amount::new(currency, symbol_record.bid);
+            let mut market_value = Amount::new(currency, quantity * symbol_record.bid);
+            let mut equity = market_value.clone();
+            if symbol_record.leveraged() {
+                let account_currency = match account_currency {
+                    Some(account_currency) => account_currency,
+                    None => *account_currency.insert(self.get_cash_balance().await?.currency),
+                };
+                equity = Amount::new(account_currency, required(trade.profit, "profit")?);
+                if let Some(nominal_value) = trade.nominal_value.filter(|value| *value != 0.0) {
+                    market_value = Amount::new(account_currency, direction * nominal_value.abs());
+                }
+            }
+            position_market_values.push(Holding {
+                symbol,
+                isin: None,
+                quantity,
+                price,
+                market_value,
+                equity,
+            });
+        }
         Ok(position_market_values)
     }
 }
+
+/// Field of xAPI record that must be present.
+fn required<T>(value: Option<T>, field: &str) -> Result<T, error::XtbError> {
+    value.ok_or(error::XtbError::InvalidResponse(format!(
+        "trade record without {}",
+        field
+    )))
+}
+
+/// Time of xAPI record, given in milliseconds since epoch.
+fn timestamp(millis: Option<i64>) -> Result<DateTime<Utc>, error::XtbError> {
+    DateTime::from_timestamp_millis(required(millis, "time")?).ok_or(
+        error::XtbError::InvalidResponse("trade record time out of range".to_string()),
+    )
+}
+
+#[async_trait]
+impl PositionSource for XtbConfig {
+    async fn connect(&mut self) -> Result<(), error::SourceError> {
+        Ok(XtbConfig::connect(self).await?)
+    }
+
+    async fn authenticate(&mut self, key: &Secret) -> Result<(), error::SourceError> {
+        let account = self
+            .account
+            .as_ref()
+            .ok_or(error::XtbError::PasswordMissing)?
+            .decrypt(key)?;
+        Ok(self.login(&account).await?)
+    }
+
+    async fn holdings(&mut self) -> Result<Vec<Holding>, error::SourceError> {
+        Ok(self.get_position_market_values().await?)
+    }
+
+    async fn cash_balance(&mut self) -> Result<Option<Amount>, error::SourceError> {
+        Ok(Some(self.get_cash_balance().await?))
+    }
+
+    async fn transactions(
+        &mut self,
+        since: Option<DateTime<Utc>>,
+    ) -> Result<Vec<Transaction>, error::SourceError> {
+        Ok(self.get_transactions(since).await?)
+    }
+
+    async fn disconnect(&mut self) -> Result<(), error::SourceError> {
+        Ok(XtbConfig::disconnect(self).await?)
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use std::env;
 
+    use super::mock::MockXtb;
     use super::*;
 
     #[tokio::test]
     async fn failed_login_attempt() {
-        let account = XtbAccount::new("123456".to_owned(), None, Some("password".to_owned()));
-        let mut xtb = XtbConfig::new("xapi.xtb.com".to_string(), 5124);
+        let server = MockXtb::new()
+            .account("123456", "secret", "PLN")
+            .start()
+            .await;
+        let account = XtbAccount::new("123456".to_owned(), None, Some("password".into()));
+        let mut xtb = server.config();
         xtb.connect().await.unwrap();
         let result = xtb.login(&account).await;
+        assert!(matches!(result, Err(error::XtbError::AuthenticationError)));
+    }
+
+    #[test]
+    fn server_presets() {
+        let xtb: XtbConfig = serde_yaml::from_str("server: demo\n").unwrap();
+        assert_eq!(
+            (xtb.host(), xtb.port(), xtb.streaming_port()),
+            ("xapi.xtb.com", 5124, 5125)
+        );
+        assert_eq!(xtb.server(), Some(XtbServer::Demo));
+        assert_eq!(serde_yaml::to_string(&xtb).unwrap(), "server: demo\n");
+
+        // Explicit host and port take precedence over the preset
+        let xtb: XtbConfig =
+            serde_yaml::from_str("server: real\nhost: localhost\nport: 6000\n").unwrap();
+        assert_eq!(
+            (xtb.host(), xtb.port(), xtb.streaming_port()),
+            ("localhost", 6000, 6001)
+        );
+        assert_eq!(xtb.server(), None);
+        let xtb: XtbConfig = serde_yaml::from_str("server: demo\nport: 5112\n").unwrap();
+        assert_eq!(xtb.server(), Some(XtbServer::Real));
+        let xtb = XtbConfig::new("xapi.xtb.com".to_string(), 5124);
+        assert_eq!(xtb.server(), Some(XtbServer::Demo));
+
+        let xtb = XtbConfig::new("localhost".to_string(), 6000).with_server(XtbServer::Real);
+        assert_eq!((xtb.port(), xtb.streaming_port()), (5112, 5113));
+    }
+
+    #[tokio::test]
+    async fn untrusted_certificate() {
+        let server = MockXtb::new().start().await;
+        let mut xtb = XtbConfig::new("localhost".to_string(), server.port());
+        assert!(matches!(
+            xtb.connect().await,
+            Err(error::XtbError::NativeTlsError(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn mock_session() {
+        let server = MockXtb::new()
+            .account("123456", "password", "PLN")
+            .position("123456", "SPXS.UK", 2.0)
+            .position("123456", "SPXS.UK", 1.5)
+            .stock("SPXS.UK", 100.0, "USD")
+            .stock("IMAE.NL", 80.0, "EUR")
+            .start()
+            .await;
+        let account = XtbAccount::new("123456".to_owned(), None, Some("password".into()));
+        let mut xtb = server.config();
+        xtb.connect().await.unwrap();
+
+        /* Data commands require login */
+        assert!(matches!(
+            xtb.get_trades(true).await,
+            Err(error::XtbError::XtbApiError(code, _)) if code == "BE103"
+        ));
+
+        xtb.login(&account).await.unwrap();
+        assert_eq!(
+            xtb.get_current_user_data().await.unwrap().currency_symbol,
+            "PLN"
+        );
+        assert_eq!(xtb.get_trades(true).await.unwrap().len(), 2);
+        assert_eq!(
+            xtb.get_position_market_values().await.unwrap(),
+            vec![
+                Holding {
+                    symbol: "SPXS.UK".to_string(),
+                    isin: None,
+                    quantity: 2.0,
+                    price: Amount::new(Currency::USD, 100.0),
+                    market_value: Amount::new(Currency::USD, 200.0),
+                    equity: Amount::new(Currency::USD, 200.0),
+                },
+                Holding {
+                    symbol: "SPXS.UK".to_string(),
+                    isin: None,
+                    quantity: 1.5,
+                    price: Amount::new(Currency::USD, 100.0),
+                    market_value: Amount::new(Currency::USD, 150.0),
+                    equity: Amount::new(Currency::USD, 150.0),
+                },
+            ]
+        );
+        xtb.disconnect().await.unwrap();
+
+        assert_eq!(
+            server.received_commands(),
+            vec![
+                "getTrades",
+                "login",
+                "getCurrentUserData",
+                "getTrades",
+                "getTrades",
+                "getSymbol",
+                "logout"
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn api_error() {
+        let server = MockXtb::new()
+            .account("123456", "password", "PLN")
+            .position("123456", "SPXS.UK", 1.0)
+            .fail("getSymbol", "EX001", "Internal error")
+            .start()
+            .await;
+        let account = XtbAccount::new("123456".to_owned(), None, Some("password".into()));
+        let mut xtb = server.config();
+        xtb.connect().await.unwrap();
+        xtb.login(&account).await.unwrap();
+        assert!(matches!(
+            xtb.get_position_market_values().await,
+            Err(error::XtbError::XtbApiError(code, description))
+                if code == "EX001" && description == "Internal error"
+        ));
+    }
+
+    #[tokio::test]
+    async fn transactions() {
+        use serde_json::json;
+
+        // 2024-01-01T00:00:00Z
+        const DAY: i64 = 24 * 60 * 60 * 1000;
+        const START: i64 = 1_704_067_200_000;
+        let server = MockXtb::new()
+            .account("123456", "password", "PLN")
+            .stock("SPXS.UK", 100.0, "USD")
+            .trade(
+                "123456",
+                json!({"symbol": "SPXS.UK", "volume": 2.0, "cmd": 0, "order": 11,
+                       "open_price": 90.0, "open_time": START + 2 * DAY, "closed": false}),
+            )
+            .history(
+                "123456",
+                json!({"symbol": null, "volume": 0.0, "cmd": 6, "order": 10, "profit": 1000.0,
+                       "open_time": START, "close_time": START, "closed": true}),
+            )
+            .history(
+                "123456",
+                json!({"symbol": "SPXS.UK", "volume": 1.0, "cmd": 0, "order": 12,
+                       "order2": 13, "open_price": 80.0, "open_time": START + DAY,
+                       "close_price": 95.0, "close_time": START + 3 * DAY, "closed": true}),
+            )
+            .history(
+                "123456",
+                json!({"symbol": null, "volume": 0.0, "cmd": 6, "order": 14, "profit": -200.0,
+                       "open_time": START + 4 * DAY, "close_time": START + 4 * DAY,
+                       "closed": true}),
+            )
+            .start()
+            .await;
+        let account = XtbAccount::new("123456".to_owned(), None, Some("password".into()));
+        let mut xtb = server.config();
+        xtb.connect().await.unwrap();
+        xtb.login(&account).await.unwrap();
+
+        let transactions = xtb.get_transactions(None).await.unwrap();
+        let summary: Vec<_> = transactions
+            .iter()
+            .map(|transaction| {
+                (
+                    transaction.order.as_str(),
+                    transaction.kind,
+                    transaction.time.timestamp_millis(),
+                    transaction.amount.clone(),
+                )
+            })
+            .collect();
         assert_eq!(
-            matches!(result, Err(error::XtbError::AuthenticationError)),
-            true
+            summary,
+            vec![
+                (
+                    "11",
+                    TransactionKind::Buy,
+                    START + 2 * DAY,
+                    Amount::new(Currency::USD, 180.0)
+                ),
+                (
+                    "10",
+                    TransactionKind::Deposit,
+                    START,
+                    Amount::new(Currency::PLN, 1000.0)
+                ),
+                (
+                    "12",
+                    TransactionKind::Buy,
+                    START + DAY,
+                    Amount::new(Currency::USD, 80.0)
+                ),
+                (
+                    "13",
+                    TransactionKind::Sell,
+                    START + 3 * DAY,
+                    Amount::new(Currency::USD, 95.0)
+                ),
+                (
+                    "14",
+                    TransactionKind::Withdrawal,
+                    START + 4 * DAY,
+                    Amount::new(Currency::PLN, 200.0)
+                ),
+            ]
         );
+
+        // Only trades closed since then are asked for, open trades are always reported
+        let since = DateTime::from_timestamp_millis(START + 4 * DAY);
+        let orders: Vec<_> = xtb
+            .get_transactions(since)
+            .await
+            .unwrap()
+            .iter()
+            .map(|transaction| transaction.order.clone())
+            .collect();
+        assert_eq!(orders, vec!["11", "14"]);
+    }
+
+    #[tokio::test]
+    async fn unknown_currency() {
+        let server = MockXtb::new()
+            .account("123456", "password", "PLN")
+            .position("123456", "GOLD.X", 1.0)
+            .position("123456", "SPXS.UK", 2.0)
+            .stock("GOLD.X", 100.0, "XAU")
+            .stock("SPXS.UK", 100.0, "USD")
+            .start()
+            .await;
+        let account = XtbAccount::new("123456".to_owned(), None, Some("password".into()));
+        let mut xtb = server.config();
+        xtb.connect().await.unwrap();
+        xtb.login(&account).await.unwrap();
+        // Holding in unsupported currency is skipped, others are still read
+        let symbols: Vec<_> = xtb
+            .get_position_market_values()
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|holding| holding.symbol)
+            .collect();
+        assert_eq!(symbols, vec!["SPXS.UK"]);
+    }
+
+    #[tokio::test]
+    async fn leveraged_trades() {
+        use serde_json::json;
+
+        let server = MockXtb::new()
+            .account("123456", "password", "PLN")
+            .symbol(json!({
+                "symbol": "US500",
+                "bid": 5000.0,
+                "ask": 5001.0,
+                "currency": "USD",
+                "currencyProfit": "USD",
+                "categoryName": "IND",
+                "contractSize": 50,
+                "leverage": 5.0,
+            }))
+            .trade(
+                "123456",
+                json!({
+                    "symbol": "US500",
+                    "volume": 0.1,
+                    "cmd": 0,
+                    "order": 1,
+                    "closed": false,
+                    "profit": 400.0,
+                    "nominalValue": 100000.0,
+                }),
+            )
+            .trade(
+                "123456",
+                json!({
+                    "symbol": "US500",
+                    "volume": 0.02,
+                    "cmd": 1,
+                    "order": 2,
+                    "closed": false,
+                    "profit": -40.0,
+                    "nominalValue": 0.0,
+                }),
+            )
+            .start()
+            .await;
+        let account = XtbAccount::new("123456".to_owned(), None, Some("password".into()));
+        let mut xtb = server.config();
+        xtb.connect().await.unwrap();
+        xtb.login(&account).await.unwrap();
+        assert_eq!(
+            xtb.get_position_market_values().await.unwrap(),
+            vec![
+                Holding {
+                    symbol: "US500".to_string(),
+                    isin: None,
+                    quantity: 5.0,
+                    price: Amount::new(Currency::USD, 5000.0),
+                    market_value: Amount::new(Currency::PLN, 100000.0),
+                    equity: Amount::new(Currency::PLN, 400.0),
+                },
+                // Short trade without nominal value is exposed by its quantity at bid price
+                Holding {
+                    symbol: "US500".to_string(),
+                    isin: None,
+                    quantity: -1.0,
+                    price: Amount::new(Currency::USD, 5000.0),
+                    market_value: Amount::new(Currency::USD, -5000.0),
+                    equity: Amount::new(Currency::PLN, -40.0),
+                },
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn reconnect_after_dropped_connection() {
+        let server = MockXtb::new()
+            .account("123456", "password", "PLN")
+            .position("123456", "SPXS.UK", 2.0)
+            .start()
+            .await;
+        let account = XtbAccount::new("123456".to_owned(), None, Some("password".into()));
+        let mut xtb = server.config();
+        xtb.connect().await.unwrap();
+        xtb.login(&account).await.unwrap();
+
+        server.drop_connection("getTrades", 1);
+        assert_eq!(xtb.get_trades(true).await.unwrap().len(), 1);
+        assert_eq!(
+            server.received_commands(),
+            vec!["login", "getTrades", "login", "getTrades"]
+        );
+    }
+
+    #[tokio::test]
+    async fn reconnect_failed() {
+        let server = MockXtb::new()
+            .account("123456", "password", "PLN")
+            .start()
+            .await;
+        let account = XtbAccount::new("123456".to_owned(), None, Some("password".into()));
+        let mut xtb = server.config();
+        xtb.connect().await.unwrap();
+        xtb.login(&account).await.unwrap();
+
+        server.drop_connection("getTrades", 1);
+        server.drop_connection("login", 3);
+        assert!(matches!(
+            xtb.get_trades(true).await,
+            Err(error::XtbError::ReconnectFailed(3, e)) if matches!(*e, error::XtbError::ConnectionClosed)
+        ));
+    }
+
+    #[tokio::test]
+    async fn command_interval() {
+        let server = MockXtb::new()
+            .account("123456", "password", "PLN")
+            .start()
+            .await;
+        let account = XtbAccount::new("123456".to_owned(), None, Some("password".into()));
+        let mut xtb = server.config().with_timing(Timing {
+            command_interval: Duration::from_millis(100),
+            ..mock::test_timing()
+        });
+        xtb.connect().await.unwrap();
+        xtb.login(&account).await.unwrap();
+        xtb.get_trades(true).await.unwrap();
+        xtb.get_trades(true).await.unwrap();
+
+        let times = server.received_times();
+        assert_eq!(times.len(), 3);
+        for pair in times.windows(2) {
+            // Allow for network jitter between client and mock server
+            assert!(pair[1] - pair[0] >= Duration::from_millis(90));
+        }
+    }
+
+    #[tokio::test]
+    async fn keepalive() {
+        let server = MockXtb::new()
+            .account("123456", "password", "PLN")
+            .start()
+            .await;
+        let account = XtbAccount::new("123456".to_owned(), None, Some("password".into()));
+        let mut xtb = server.config().with_timing(Timing {
+            ping_interval: Duration::from_millis(50),
+            ..mock::test_timing()
+        });
+        xtb.connect().await.unwrap();
+        xtb.login(&account).await.unwrap();
+        // Logging in again replaces the keepalive task instead of adding another one
+        let first_keepalive = Arc::downgrade(xtb.keepalive.as_ref().unwrap());
+        xtb.login(&account).await.unwrap();
+        assert!(first_keepalive.upgrade().is_none());
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        let keepalive = Arc::downgrade(xtb.keepalive.as_ref().unwrap());
+        xtb.disconnect().await.unwrap();
+        assert!(keepalive.upgrade().is_none());
+
+        let commands = server.received_commands();
+        assert_eq!(commands.first().unwrap(), "login");
+        assert_eq!(commands.last().unwrap(), "logout");
+        assert!(commands.iter().filter(|command| *command == "ping").count() >= 2);
     }
 
     #[tokio::test]
@@ -432,12 +1671,12 @@ mod tests {
         let password = env::var("XTB_TEST_DEMO_PASSWORD").ok();
 
         /* Enable this test only if env-vars are set */
-        if account_id.is_some() && password.is_some() {
-            let account = XtbAccount::new(account_id.unwrap(), None, Some(password.unwrap()));
+        if let (Some(account_id), Some(password)) = (account_id, password) {
+            let account = XtbAccount::new(account_id, None, Some(password.into()));
             let mut xtb = XtbConfig::new("xapi.xtb.com".to_string(), 5124);
             xtb.connect().await.unwrap();
             let result = xtb.login(&account).await;
-            assert_eq!(matches!(result, Ok(())), true);
+            assert!(matches!(result, Ok(())));
         }
     }
 
@@ -447,14 +1686,14 @@ mod tests {
         let password = env::var("XTB_TEST_DEMO_PASSWORD").ok();
 
         /* Enable this test only if env-vars are set */
-        if account_id.is_some() && password.is_some() {
-            let account = XtbAccount::new(account_id.unwrap(), None, Some(password.unwrap()));
+        if let (Some(account_id), Some(password)) = (account_id, password) {
+            let account = XtbAccount::new(account_id, None, Some(password.into()));
             // let mut xtb = XtbConfig::new("xapi.xtb.com".to_string(), 5112);
             let mut xtb = XtbConfig::new("xapi.xtb.com".to_string(), 5124);
             xtb.connect().await.unwrap();
             xtb.login(&account).await.unwrap();
             let result = xtb.get_trades(true).await;
-            assert_eq!(matches!(result, Ok(_)), true);
+            assert!(result.is_ok());
         }
     }
 
@@ -464,8 +1703,8 @@ mod tests {
         let password = env::var("XTB_TEST_DEMO_PASSWORD").ok();
 
         /* Enable this test only if env-vars are set */
-        if account_id.is_some() && password.is_some() {
-            let account = XtbAccount::new(account_id.unwrap(), None, Some(password.unwrap()));
+        if let (Some(account_id), Some(password)) = (account_id, password) {
+            let account = XtbAccount::new(account_id, None, Some(password.into()));
             // let mut xtb = XtbConfig::new("xapi.xtb.com".to_string(), 5112);
             let mut xtb = XtbConfig::new("xapi.xtb.com".to_string(), 5124);
             xtb.connect().await.unwrap();
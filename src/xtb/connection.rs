@@ -0,0 +1,289 @@
+//! Transport of xAPI commands over a single connection.
+//!
+//! The client sends bare JSON commands and the server terminates every response with `"\n\n"`.
+//! Responses may arrive split over several reads (`getAllSymbols` is megabytes long), so bytes are
+//! buffered until a complete frame is available. XTB disconnects clients sending more than one
+//! command per 200 ms, so commands are spaced out by `Timing::command_interval`.
+//!
+//! Once a command fails in transport (e.g. its response times out), a late or partial response may
+//! still arrive, which would be taken for the response to the next command. Such a connection is
+//! marked broken and refuses further commands, so it gets replaced by a new one.
+
+use std::time::Duration;
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::time::Instant;
+
+use super::command;
+use crate::error;
+
+/// Terminator of every xAPI response.
+const FRAME_TERMINATOR: &[u8] = b"\n\n";
+
+/// Responses larger than this are treated as a broken stream rather than buffered forever.
+const MAX_FRAME_SIZE: usize = 64 * 1024 * 1024;
+
+/// Timeouts and limits of an xAPI session.
+#[derive(Debug, Clone)]
+pub struct Timing {
+    /// Time allowed for TCP connect and TLS handshake
+    pub connect_timeout: Duration,
+    /// Time allowed for a complete response to arrive
+    pub response_timeout: Duration,
+    /// Minimum time between two commands
+    pub command_interval: Duration,
+    /// Idle time after which `ping` is sent to keep the session alive
+    pub ping_interval: Duration,
+    /// Number of reconnect attempts after a transient failure
+    pub reconnect_attempts: u32,
+    /// Delay before the first reconnect attempt, doubled after every failed attempt
+    pub reconnect_backoff: Duration,
+}
+
+impl Default for Timing {
+    fn default() -> Self {
+        Self {
+            connect_timeout: Duration::from_secs(10),
+            response_timeout: Duration::from_secs(30),
+            command_interval: Duration::from_millis(200),
+            ping_interval: Duration::from_secs(5 * 60),
+            reconnect_attempts: 3,
+            reconnect_backoff: Duration::from_millis(500),
+        }
+    }
+}
+
+/// Connection carrying one command at a time.
+pub struct Connection<S> {
+    stream: S,
+    buffer: Vec<u8>,
+    timing: Timing,
+    last_command: Option<Instant>,
+    /// Earlier command failed in transport, responses can't be matched to commands anymore
+    broken: bool,
+}
+
+impl<S> std::fmt::Debug for Connection<S> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Connection")
+            .field("buffered", &self.buffer.len())
+            .field("last_command", &self.last_command)
+            .field("broken", &self.broken)
+            .finish()
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> Connection<S> {
+    pub fn new(stream: S, timing: Timing) -> Self {
+        Self {
+            stream,
+            buffer: Vec::new(),
+            timing,
+            last_command: None,
+            broken: false,
+        }
+    }
+
+    /// Whether an earlier command failed in transport, so no more commands can be sent.
+    pub fn is_broken(&self) -> bool {
+        self.broken
+    }
+
+    /// Time since the last command was sent.
+    pub fn idle_for(&self) -> Duration {
+        self.last_command
+            .map(|last_command| last_command.elapsed())
+            .unwrap_or_default()
+    }
+
+    /// Send serialized `command` without waiting for any response.
+    pub async fn send(&mut self, command: &str) -> Result<(), error::XtbError> {
+        if self.broken {
+            return Err(error::XtbError::ConnectionBroken);
+        }
+        if let Some(last_command) = self.last_command {
+            tokio::time::sleep_until(last_command + self.timing.command_interval).await;
+        }
+        self.stream.write_all(command.as_bytes()).await?;
+        self.stream.flush().await?;
+        self.last_command = Some(Instant::now());
+        Ok(())
+    }
+
+    /// Send serialized `command` and return its response, failing on xAPI error status.
+    ///
+    /// `name` is only used to report errors.
+    pub async fn request(&mut self, name: &str, command: &str) -> Result<String, error::XtbError> {
+        let exchange = async {
+            self.send(command).await?;
+            tokio::time::timeout(self.timing.response_timeout, self.read_frame())
+                .await
+                .map_err(|_| error::XtbError::ResponseTimeout(name.to_string()))?
+        };
+        let response = match exchange.await {
+            Ok(response) => response,
+            Err(e) => {
+                self.broken = true;
+                return Err(e);
+            }
+        };
+        log::debug!("Response: {}", response);
+
+        let generic_api_response = serde_json::from_str::<command::GenericResponse>(&response)?;
+        match generic_api_response.status {
+            true => Ok(response),
+            false => Err(generic_api_response.to_xtb_api_error()),
+        }
+    }
+
+    /// Read next complete frame, waiting for it as long as necessary.
+    ///
+    /// Cancelling the returned future never loses data already read.
+    pub async fn read_frame(&mut self) -> Result<String, error::XtbError> {
+        let mut searched = 0;
+        loop {
+            if let Some(end) = self.buffer[searched..]
+                .windows(FRAME_TERMINATOR.len())
+                .position(|window| window == FRAME_TERMINATOR)
+                .map(|position| searched + position)
+            {
+                let frame: Vec<u8> = self
+                    .buffer
+                    .drain(..end + FRAME_TERMINATOR.len())
+                    .take(end)
+                    .collect();
+                return String::from_utf8(frame)
+                    .map_err(|_| error::XtbError::InvalidFrame("not UTF-8".to_string()));
+            }
+            if self.buffer.len() > MAX_FRAME_SIZE {
+                return Err(error::XtbError::InvalidFrame(format!(
+                    "response exceeds {} bytes",
+                    MAX_FRAME_SIZE
+                )));
+            }
+            // Terminator may straddle the boundary of two reads
+            searched = self.buffer.len().saturating_sub(FRAME_TERMINATOR.len() - 1);
+            if self.stream.read_buf(&mut self.buffer).await? == 0 {
+                return Err(error::XtbError::ConnectionClosed);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_timing() -> Timing {
+        Timing {
+            response_timeout: Duration::from_millis(100),
+            command_interval: Duration::from_millis(50),
+            ..Timing::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn frames_split_and_joined() {
+        let (client, mut server) = tokio::io::duplex(1024);
+        let mut connection = Connection::new(client, test_timing());
+        tokio::spawn(async move {
+            // Every command sent in tests is `{}`
+            let mut command = [0; 2];
+            server.read_exact(&mut command).await.unwrap();
+            // First response split in the middle of its terminator, second follows immediately
+            server
+                .write_all(b"{\"status\": true, \"a\": 1}\n")
+                .await
+                .unwrap();
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            server
+                .write_all(b"\n{\"status\": true, \"b\": 2}\n\n")
+                .await
+                .unwrap();
+            server.read_exact(&mut command).await.unwrap();
+        });
+
+        assert_eq!(
+            connection.request("a", "{}").await.unwrap(),
+            "{\"status\": true, \"a\": 1}"
+        );
+        assert_eq!(
+            connection.request("b", "{}").await.unwrap(),
+            "{\"status\": true, \"b\": 2}"
+        );
+    }
+
+    #[tokio::test]
+    async fn command_interval() {
+        let (client, mut server) = tokio::io::duplex(1024);
+        let mut connection = Connection::new(client, test_timing());
+        tokio::spawn(async move {
+            let mut buffer = [0; 1024];
+            while server.read(&mut buffer).await.unwrap_or(0) > 0 {
+                server.write_all(b"{\"status\": true}\n\n").await.unwrap();
+            }
+        });
+
+        let start = Instant::now();
+        for _ in 0..3 {
+            connection.request("ping", "{}").await.unwrap();
+        }
+        assert!(start.elapsed() >= Duration::from_millis(100));
+    }
+
+    #[tokio::test]
+    async fn response_timeout() {
+        let (client, mut server) = tokio::io::duplex(1024);
+        let mut connection = Connection::new(client, test_timing());
+        tokio::spawn(async move {
+            let mut command = [0; 2];
+            server.read_exact(&mut command).await.unwrap();
+            tokio::time::sleep(Duration::from_millis(150)).await;
+            server.write_all(b"{\"status\": true}\n\n").await.unwrap();
+            let _ = server.read_exact(&mut command).await;
+        });
+        assert!(matches!(
+            connection.request("getTrades", "{}").await,
+            Err(error::XtbError::ResponseTimeout(command)) if command == "getTrades"
+        ));
+        // Late response to getTrades must not be taken for the response to ping
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(matches!(
+            connection.request("ping", "{}").await,
+            Err(error::XtbError::ConnectionBroken)
+        ));
+    }
+
+    #[tokio::test]
+    async fn connection_closed() {
+        let (client, mut server) = tokio::io::duplex(1024);
+        let mut connection = Connection::new(client, test_timing());
+        tokio::spawn(async move {
+            let mut command = [0; 2];
+            server.read_exact(&mut command).await.unwrap();
+            server.write_all(b"{\"status\": tr").await.unwrap();
+        });
+        assert!(matches!(
+            connection.request("getTrades", "{}").await,
+            Err(error::XtbError::ConnectionClosed)
+        ));
+    }
+
+    #[tokio::test]
+    async fn api_error_status() {
+        let (client, mut server) = tokio::io::duplex(1024);
+        let mut connection = Connection::new(client, test_timing());
+        tokio::spawn(async move {
+            let mut command = [0; 2];
+            server.read_exact(&mut command).await.unwrap();
+            server
+                .write_all(b"{\"status\": false, \"errorCode\": \"BE118\", \"errorDescr\": \"User already logged\"}\n\n")
+                .await
+                .unwrap();
+        });
+        assert!(matches!(
+            connection.request("login", "{}").await,
+            Err(error::XtbError::XtbApiError(code, _)) if code == "BE118"
+        ));
+    }
+}
@@ -0,0 +1,686 @@
+//! Local stand-in for the XTB xAPI server, used to test XTB integration offline.
+//!
+//! The server speaks the same JSON-over-TLS protocol as `xapi.xtb.com`, using a self-signed
+//! certificate that `XtbConfig` is configured to trust. Records are plain `serde_json::Value`s
+//! in the xAPI format, so tests can shape responses exactly like the real server does.
+//!
+//! Streaming server listens on a port of its own and pushes prices of subscribed symbols, on
+//! subscription and whenever a test sets a new one with `MockXtbServer::tick`. Every login starts
+//! a new stream session, which ends together with the connection it was started on.
+
+use std::collections::{HashMap, HashSet};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use openssl::asn1::Asn1Time;
+use openssl::bn::BigNum;
+use openssl::ec::{EcGroup, EcKey};
+use openssl::hash::MessageDigest;
+use openssl::nid::Nid;
+use openssl::pkey::{PKey, Private};
+use openssl::x509::extension::{BasicConstraints, SubjectAlternativeName};
+use openssl::x509::{X509NameBuilder, X509};
+use serde_json::{json, Value};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpListener;
+use tokio::sync::broadcast;
+use tokio_native_tls::{native_tls, TlsAcceptor};
+
+use super::{Timing, XtbConfig};
+
+#[derive(Clone, Default)]
+struct Account {
+    password: String,
+    currency: String,
+    balance: f64,
+    trades: Vec<Value>,
+    /// Closed trades and balance operations
+    history: Vec<Value>,
+}
+
+#[derive(Default)]
+struct State {
+    accounts: HashMap<String, Account>,
+    symbols: Vec<Value>,
+    /// Command name -> (error code, error description) to respond with instead
+    failures: HashMap<String, (String, String)>,
+    /// Command name -> number of times to close connection instead of responding
+    drops: HashMap<String, u32>,
+    /// Symbol -> message to reject its orders with
+    rejections: HashMap<String, String>,
+    /// Received orders (xAPI `TRADE_TRANS_INFO`) with their order number and number of status
+    /// polls answered so far
+    orders: Vec<(Value, i64, u32)>,
+    /// Names of all received commands with time of arrival, in order
+    received: Vec<(String, Instant)>,
+    /// Number of logins so far, to issue unique stream session ids
+    logins: u32,
+    /// Stream session ids of sessions still logged in
+    sessions: HashSet<String>,
+}
+
+/// Builder of a mock xAPI server.
+#[derive(Default)]
+pub struct MockXtb {
+    state: State,
+}
+
+impl MockXtb {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add account accepting `password`, with `currency` as its base currency.
+    pub fn account(mut self, account_id: &str, password: &str, currency: &str) -> Self {
+        self.state.accounts.insert(
+            account_id.to_string(),
+            Account {
+                password: password.to_string(),
+                currency: currency.to_string(),
+                balance: 0.0,
+                trades: vec![],
+                history: vec![],
+            },
+        );
+        self
+    }
+
+    /// Set cash balance of account, in its currency.
+    pub fn balance(mut self, account_id: &str, balance: f64) -> Self {
+        self.state
+            .accounts
+            .get_mut(account_id)
+            .expect("Unknown mock account")
+            .balance = balance;
+        self
+    }
+
+    /// Add open trade (xAPI `TRADE_RECORD`) to account.
+    pub fn trade(mut self, account_id: &str, trade: Value) -> Self {
+        self.state
+            .accounts
+            .get_mut(account_id)
+            .expect("Unknown mock account")
+            .trades
+            .push(trade);
+        self
+    }
+
+    /// Add closed trade or balance operation (xAPI `TRADE_RECORD`) to account history.
+    pub fn history(mut self, account_id: &str, trade: Value) -> Self {
+        self.state
+            .accounts
+            .get_mut(account_id)
+            .expect("Unknown mock account")
+            .history
+            .push(trade);
+        self
+    }
+
+    /// Add open position of `volume` in `symbol` to account, as a minimal trade record.
+    pub fn position(self, account_id: &str, symbol: &str, volume: f64) -> Self {
+        let order = 1000
+            + self
+                .state
+                .accounts
+                .values()
+                .map(|account| account.trades.len() as u64)
+                .sum::<u64>();
+        self.trade(
+            account_id,
+            json!({
+                "symbol": symbol,
+                "volume": volume,
+                "order": order,
+                "position": order,
+                "cmd": 0,
+                "closed": false,
+            }),
+        )
+    }
+
+    /// Add symbol (xAPI `SYMBOL_RECORD`) known to the server.
+    pub fn symbol(mut self, symbol: Value) -> Self {
+        self.state.symbols.push(symbol);
+        self
+    }
+
+    /// Add stock-like symbol with given bid price and currency.
+    pub fn stock(self, symbol: &str, bid: f64, currency: &str) -> Self {
+        self.symbol(json!({
+            "symbol": symbol,
+            "bid": bid,
+            "ask": bid,
+            "currency": currency,
+            "currencyProfit": currency,
+            "categoryName": "STC",
+            "contractSize": 1,
+            "leverage": 100.0,
+        }))
+    }
+
+    /// Make every `command` fail with given xAPI error.
+    pub fn fail(mut self, command: &str, error_code: &str, error_description: &str) -> Self {
+        self.state.failures.insert(
+            command.to_string(),
+            (error_code.to_string(), error_description.to_string()),
+        );
+        self
+    }
+
+    /// Reject all orders of `symbol` with `message`.
+    pub fn reject(mut self, symbol: &str, message: &str) -> Self {
+        self.state
+            .rejections
+            .insert(symbol.to_string(), message.to_string());
+        self
+    }
+
+    /// Start server on a random local port.
+    pub async fn start(self) -> MockXtbServer {
+        let (certificate, key) = self_signed_certificate();
+        let dir = tempfile::tempdir().unwrap();
+        let certificate_path = dir.path().join("xtb-mock.pem");
+        std::fs::write(&certificate_path, certificate.to_pem().unwrap()).unwrap();
+
+        let identity = native_tls::Identity::from_pkcs8(
+            &certificate.to_pem().unwrap(),
+            &key.private_key_to_pem_pkcs8().unwrap(),
+        )
+        .unwrap();
+        let acceptor = TlsAcceptor::from(native_tls::TlsAcceptor::new(identity).unwrap());
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let streaming_listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let streaming_port = streaming_listener.local_addr().unwrap().port();
+        let state = Arc::new(Mutex::new(self.state));
+        let (ticks, _) = broadcast::channel(64);
+        let (disconnects, _) = broadcast::channel(1);
+
+        let server_state = state.clone();
+        let server_acceptor = acceptor.clone();
+        let server_disconnects = disconnects.clone();
+        tokio::spawn(async move {
+            while let Ok((tcp_stream, _)) = listener.accept().await {
+                let acceptor = server_acceptor.clone();
+                let state = server_state.clone();
+                let disconnect = server_disconnects.subscribe();
+                tokio::spawn(async move {
+                    if let Ok(tls_stream) = acceptor.accept(tcp_stream).await {
+                        serve_connection(tls_stream, state, disconnect).await;
+                    }
+                });
+            }
+        });
+        let server_state = state.clone();
+        let server_ticks = ticks.clone();
+        let server_disconnects = disconnects.clone();
+        tokio::spawn(async move {
+            while let Ok((tcp_stream, _)) = streaming_listener.accept().await {
+                let acceptor = acceptor.clone();
+                let state = server_state.clone();
+                let ticks = server_ticks.subscribe();
+                let disconnect = server_disconnects.subscribe();
+                tokio::spawn(async move {
+                    if let Ok(tls_stream) = acceptor.accept(tcp_stream).await {
+                        serve_stream(tls_stream, state, ticks, disconnect).await;
+                    }
+                });
+            }
+        });
+
+        MockXtbServer {
+            port,
+            streaming_port,
+            certificate_path,
+            state,
+            ticks,
+            disconnects,
+            _dir: dir,
+        }
+    }
+}
+
+/// Running mock xAPI server. Stops accepting connections once the test runtime shuts down.
+pub struct MockXtbServer {
+    port: u16,
+    streaming_port: u16,
+    certificate_path: PathBuf,
+    state: Arc<Mutex<State>>,
+    /// Tick records pushed to streams subscribed to their symbol
+    ticks: broadcast::Sender<Value>,
+    /// Closes all open connections, of commands and of streams
+    disconnects: broadcast::Sender<()>,
+    _dir: tempfile::TempDir,
+}
+
+impl MockXtbServer {
+    /// `XtbConfig` connecting to this server and trusting its certificate.
+    ///
+    /// Command interval and reconnect backoff are shortened to keep tests fast.
+    pub fn config(&self) -> XtbConfig {
+        XtbConfig::new("localhost".to_string(), self.port)
+            .with_ca_certificate(self.certificate_path.to_str().unwrap().to_string())
+            .with_streaming_port(self.streaming_port)
+            .with_timing(test_timing())
+    }
+
+    /// Portfolio config of an XTB endpoint at this server, as a YAML flow mapping.
+    pub fn endpoint(&self) -> String {
+        format!(
+            "{{host: localhost, port: {}, streaming_port: {}, ca_certificate: '{}'}}",
+            self.port,
+            self.streaming_port,
+            self.certificate_path.display()
+        )
+    }
+
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+
+    /// Set new price of `symbol` and push it to all streams subscribed to it.
+    pub fn tick(&self, symbol: &str, bid: f64) {
+        let mut state = self.state.lock().unwrap();
+        let Some(record) = state
+            .symbols
+            .iter_mut()
+            .find(|record| record["symbol"] == symbol)
+        else {
+            panic!("Unknown mock symbol {}", symbol);
+        };
+        record["bid"] = bid.into();
+        record["ask"] = bid.into();
+        let _ = self.ticks.send(tick_record(record));
+    }
+
+    /// Close connection without responding the next `times` `command`s are received.
+    pub fn drop_connection(&self, command: &str, times: u32) {
+        let mut state = self.state.lock().unwrap();
+        state.drops.insert(command.to_string(), times);
+    }
+
+    /// Close all open connections, ending their sessions, as if the network went down.
+    pub fn drop_all_connections(&self) {
+        let _ = self.disconnects.send(());
+    }
+
+    /// Names of all commands received so far, in order.
+    pub fn received_commands(&self) -> Vec<String> {
+        let state = self.state.lock().unwrap();
+        state
+            .received
+            .iter()
+            .map(|(name, _)| name.clone())
+            .collect()
+    }
+
+    /// All orders (xAPI `TRADE_TRANS_INFO`) received so far, in order.
+    pub fn orders(&self) -> Vec<Value> {
+        let state = self.state.lock().unwrap();
+        state
+            .orders
+            .iter()
+            .map(|(order, _, _)| order.clone())
+            .collect()
+    }
+
+    /// Time of arrival of all commands received so far, in order.
+    pub fn received_times(&self) -> Vec<Instant> {
+        let state = self.state.lock().unwrap();
+        state.received.iter().map(|(_, time)| *time).collect()
+    }
+}
+
+pub fn test_timing() -> Timing {
+    Timing {
+        command_interval: Duration::from_millis(10),
+        reconnect_backoff: Duration::from_millis(10),
+        ..Timing::default()
+    }
+}
+
+fn self_signed_certificate() -> (X509, PKey<Private>) {
+    let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap();
+    let key = PKey::from_ec_key(EcKey::generate(&group).unwrap()).unwrap();
+
+    let mut name = X509NameBuilder::new().unwrap();
+    name.append_entry_by_text("CN", "localhost").unwrap();
+    let name = name.build();
+
+    let mut builder = X509::builder().unwrap();
+    builder.set_version(2).unwrap();
+    builder
+        .set_serial_number(&BigNum::from_u32(1).unwrap().to_asn1_integer().unwrap())
+        .unwrap();
+    builder.set_subject_name(&name).unwrap();
+    builder.set_issuer_name(&name).unwrap();
+    builder.set_pubkey(&key).unwrap();
+    builder
+        .set_not_before(&Asn1Time::days_from_now(0).unwrap())
+        .unwrap();
+    builder
+        .set_not_after(&Asn1Time::days_from_now(1).unwrap())
+        .unwrap();
+    builder
+        .append_extension(BasicConstraints::new().critical().ca().build().unwrap())
+        .unwrap();
+    let subject_alternative_name = SubjectAlternativeName::new()
+        .dns("localhost")
+        .ip("127.0.0.1")
+        .build(&builder.x509v3_context(None, None))
+        .unwrap();
+    builder.append_extension(subject_alternative_name).unwrap();
+    builder.sign(&key, MessageDigest::sha256()).unwrap();
+
+    (builder.build(), key)
+}
+
+fn error_response(code: &str, description: &str) -> Value {
+    json!({"status": false, "errorCode": code, "errorDescr": description})
+}
+
+fn data_response(return_data: Value) -> Value {
+    json!({"status": true, "returnData": return_data})
+}
+
+/// Respond to single command, or `None` to close the connection.
+///
+/// `logged_in` holds account and stream session id of this connection's session.
+fn respond(
+    state: &Mutex<State>,
+    logged_in: &mut Option<(String, String)>,
+    command: &Value,
+) -> Option<Value> {
+    let mut state = state.lock().unwrap();
+    let name = command["command"].as_str().unwrap_or_default().to_string();
+    let arguments = &command["arguments"];
+    state.received.push((name.clone(), Instant::now()));
+
+    if let Some(times) = state.drops.get_mut(&name).filter(|times| **times > 0) {
+        *times -= 1;
+        return None;
+    }
+    if let Some((code, description)) = state.failures.get(&name) {
+        return Some(error_response(code, description));
+    }
+    if name == "login" {
+        let account_id = arguments["userId"].as_str().unwrap_or_default();
+        let password = arguments["password"].as_str().unwrap_or_default();
+        return Some(match state.accounts.get(account_id) {
+            Some(account) if account.password == password => {
+                state.logins += 1;
+                let stream_session_id = format!("stream-{}-{}", account_id, state.logins);
+                if let Some((_, old)) = logged_in.take() {
+                    state.sessions.remove(&old);
+                }
+                state.sessions.insert(stream_session_id.clone());
+                *logged_in = Some((account_id.to_string(), stream_session_id.clone()));
+                json!({"status": true, "streamSessionId": stream_session_id})
+            }
+            _ => error_response("BE005", "userPasswordCheck: Invalid login or password"),
+        });
+    }
+
+    let Some(account_id) = logged_in
+        .clone()
+        .map(|(account_id, _)| account_id)
+        .filter(|id| state.accounts.contains_key(id))
+    else {
+        return Some(error_response("BE103", "User is not logged"));
+    };
+    match name.as_str() {
+        "tradeTransaction" => return Some(trade_transaction(&mut state, &account_id, arguments)),
+        "tradeTransactionStatus" => return Some(trade_transaction_status(&mut state, arguments)),
+        "logout" => {
+            if let Some((_, stream_session_id)) = logged_in.take() {
+                state.sessions.remove(&stream_session_id);
+            }
+            return Some(json!({"status": true}));
+        }
+        _ => {}
+    }
+    let account = &state.accounts[&account_id];
+    Some(match name.as_str() {
+        "ping" => json!({"status": true}),
+        "getTrades" => data_response(Value::from(account.trades.clone())),
+        "getTradesHistory" => data_response(Value::from(
+            account
+                .history
+                .iter()
+                .filter(|trade| trade["close_time"].as_i64() >= arguments["start"].as_i64())
+                .cloned()
+                .collect::<Vec<_>>(),
+        )),
+        "getAllSymbols" => data_response(Value::from(state.symbols.clone())),
+        "getSymbol" => match state
+            .symbols
+            .iter()
+            .find(|symbol| symbol["symbol"] == arguments["symbol"])
+        {
+            Some(symbol) => data_response(symbol.clone()),
+            None => error_response("BE115", "Symbol does not exist"),
+        },
+        "getMarginLevel" => data_response(json!({
+            "balance": account.balance,
+            "credit": 0.0,
+            "currency": account.currency,
+            "equity": account.balance,
+            "margin": 0.0,
+            "margin_free": account.balance,
+            "margin_level": 0.0,
+        })),
+        "getCurrentUserData" => data_response(json!({
+            "companyUnit": 8,
+            "currency": account.currency,
+            "group": "demo",
+            "ibAccount": false,
+            "leverage": 1,
+            "leverageMultiplier": 0.25,
+            "spreadType": "FLOAT",
+            "trailingStop": false,
+        })),
+        _ => error_response("EX000", "Invalid command"),
+    })
+}
+
+/// Accept order, opening position right away unless its symbol is rejected.
+fn trade_transaction(state: &mut State, account_id: &str, arguments: &Value) -> Value {
+    let order = arguments["tradeTransInfo"].clone();
+    let symbol = order["symbol"].as_str().unwrap_or_default().to_string();
+    if !state
+        .symbols
+        .iter()
+        .any(|record| record["symbol"] == symbol)
+    {
+        return error_response("BE115", "Symbol does not exist");
+    }
+    let order_number = 5000 + state.orders.len() as i64;
+    if !state.rejections.contains_key(&symbol) {
+        state
+            .accounts
+            .get_mut(account_id)
+            .unwrap()
+            .trades
+            .push(json!({
+                "symbol": symbol,
+                "volume": order["volume"],
+                "order": order_number,
+                "position": order_number,
+                "cmd": order["cmd"],
+                "closed": false,
+            }));
+    }
+    state.orders.push((order, order_number, 0));
+    data_response(json!({ "order": order_number }))
+}
+
+/// Report order as pending on the first poll, then accepted or rejected.
+fn trade_transaction_status(state: &mut State, arguments: &Value) -> Value {
+    let rejections = state.rejections.clone();
+    let Some((order, order_number, polls)) = state
+        .orders
+        .iter_mut()
+        .find(|(_, order_number, _)| arguments["order"] == *order_number)
+    else {
+        return error_response("BE004", "Order does not exist");
+    };
+    *polls += 1;
+    let price = order["price"].clone();
+    let (request_status, message) = match rejections.get(order["symbol"].as_str().unwrap_or("")) {
+        _ if *polls == 1 => (1, Value::Null),
+        Some(message) => (4, Value::from(message.clone())),
+        None => (3, Value::Null),
+    };
+    data_response(json!({
+        "ask": price,
+        "bid": price,
+        "customComment": order["customComment"],
+        "message": message,
+        "order": *order_number,
+        "requestStatus": request_status,
+    }))
+}
+
+fn tick_record(symbol: &Value) -> Value {
+    json!({
+        "command": "tickPrices",
+        "data": {
+            "symbol": symbol["symbol"],
+            "ask": symbol["ask"],
+            "bid": symbol["bid"],
+            "level": 0,
+            "timestamp": 0,
+        },
+    })
+}
+
+async fn serve_stream<S>(
+    mut stream: S,
+    state: Arc<Mutex<State>>,
+    mut ticks: broadcast::Receiver<Value>,
+    mut disconnect: broadcast::Receiver<()>,
+) where
+    S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin,
+{
+    let mut subscribed = HashSet::new();
+    let mut buffer = Vec::new();
+    loop {
+        let mut commands = serde_json::Deserializer::from_slice(&buffer).into_iter::<Value>();
+        if let Some(Ok(command)) = commands.next() {
+            let offset = commands.byte_offset();
+            buffer.drain(..offset);
+            let current_tick = {
+                let mut state = state.lock().unwrap();
+                let name = command["command"].as_str().unwrap_or_default();
+                state
+                    .received
+                    .push((format!("stream:{}", name), Instant::now()));
+                // Stream session ids are issued by login of the main connection
+                let session = command["streamSessionId"].as_str().unwrap_or_default();
+                if !state.sessions.contains(session) {
+                    return;
+                }
+                if name != "getTickPrices" {
+                    continue;
+                }
+                subscribed.insert(command["symbol"].as_str().unwrap_or_default().to_string());
+                state
+                    .symbols
+                    .iter()
+                    .find(|symbol| symbol["symbol"] == command["symbol"])
+                    .map(tick_record)
+            };
+            if let Some(tick) = current_tick {
+                if stream
+                    .write_all(format!("{}\n\n", tick).as_bytes())
+                    .await
+                    .is_err()
+                {
+                    return;
+                }
+            }
+            continue;
+        }
+        tokio::select! {
+            read = stream.read_buf(&mut buffer) => match read {
+                Ok(0) | Err(_) => return,
+                Ok(_) => {}
+            },
+            _ = disconnect.recv() => return,
+            tick = ticks.recv() => {
+                let tick = match tick {
+                    Ok(tick) => tick,
+                    Err(broadcast::error::RecvError::Closed) => return,
+                    Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                };
+                let symbol = tick["data"]["symbol"].as_str().unwrap_or_default();
+                if subscribed.contains(symbol)
+                    && stream
+                        .write_all(format!("{}\n\n", tick).as_bytes())
+                        .await
+                        .is_err()
+                {
+                    return;
+                }
+            }
+        }
+    }
+}
+
+async fn serve_connection<S>(
+    stream: S,
+    state: Arc<Mutex<State>>,
+    mut disconnect: broadcast::Receiver<()>,
+) where
+    S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin,
+{
+    let mut logged_in = None;
+    tokio::select! {
+        _ = serve_commands(stream, &state, &mut logged_in) => {}
+        _ = disconnect.recv() => {}
+    }
+    // Session ends with its connection
+    if let Some((_, stream_session_id)) = logged_in {
+        state.lock().unwrap().sessions.remove(&stream_session_id);
+    }
+}
+
+async fn serve_commands<S>(
+    mut stream: S,
+    state: &Mutex<State>,
+    logged_in: &mut Option<(String, String)>,
+) where
+    S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin,
+{
+    let mut buffer = Vec::new();
+    loop {
+        // Commands are sent as bare JSON objects, without any delimiter
+        let mut commands = serde_json::Deserializer::from_slice(&buffer).into_iter::<Value>();
+        match commands.next() {
+            Some(Ok(command)) => {
+                let offset = commands.byte_offset();
+                buffer.drain(..offset);
+                let Some(response) = respond(state, logged_in, &command) else {
+                    return;
+                };
+                let response = format!("{}\n\n", response);
+                if stream.write_all(response.as_bytes()).await.is_err() {
+                    return;
+                }
+                continue;
+            }
+            Some(Err(e)) if !e.is_eof() => {
+                let response = format!("{}\n\n", error_response("BE000", "Invalid JSON"));
+                let _ = stream.write_all(response.as_bytes()).await;
+                return;
+            }
+            _ => {}
+        }
+        match stream.read_buf(&mut buffer).await {
+            Ok(0) | Err(_) => return,
+            Ok(_) => {}
+        }
+    }
+}
@@ -0,0 +1,142 @@
+//! Streaming xAPI connection, pushing prices of subscribed symbols as they change.
+//!
+//! Stream commands carry `streamSessionId` of a logged in session instead of credentials and get
+//! no responses. The server pushes records terminated with `"\n\n"`, just like responses of the
+//! main connection, and drops streams idle for too long, so `ping` is sent whenever no record
+//! arrived for `Timing::ping_interval`.
+
+use serde::Deserialize;
+use serde_json::json;
+use tokio::io::{AsyncRead, AsyncWrite};
+
+use super::connection::Connection;
+use crate::error;
+
+/// Current price of a symbol (xAPI `STREAMING_TICK_RECORD`).
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct TickPrice {
+    pub symbol: String,
+    pub ask: f64,
+    pub bid: f64,
+    /// Milliseconds since epoch
+    pub timestamp: i64,
+}
+
+#[derive(Debug, Deserialize)]
+struct Record {
+    command: String,
+    data: serde_json::Value,
+}
+
+/// Stream of tick prices of subscribed symbols.
+#[derive(Debug)]
+pub struct PriceStream<S> {
+    connection: Connection<S>,
+    stream_session_id: String,
+    ping_interval: std::time::Duration,
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> PriceStream<S> {
+    pub fn new(
+        connection: Connection<S>,
+        stream_session_id: String,
+        ping_interval: std::time::Duration,
+    ) -> Self {
+        Self {
+            connection,
+            stream_session_id,
+            ping_interval,
+        }
+    }
+
+    /// Subscribe to prices of `symbol`. Current price is pushed right away.
+    pub async fn subscribe(&mut self, symbol: &str) -> Result<(), error::XtbError> {
+        let command = json!({
+            "command": "getTickPrices",
+            "streamSessionId": self.stream_session_id,
+            "symbol": symbol,
+            // Best price only, at most once per second
+            "minArrivalTime": 1000,
+            "maxLevel": 0,
+        });
+        self.connection.send(&command.to_string()).await
+    }
+
+    /// Wait for next price of any subscribed symbol.
+    pub async fn next(&mut self) -> Result<TickPrice, error::XtbError> {
+        loop {
+            let frame = match tokio::time::timeout(self.ping_interval, self.connection.read_frame())
+                .await
+            {
+                Ok(frame) => frame?,
+                Err(_) => {
+                    let command = json!({
+                        "command": "ping",
+                        "streamSessionId": self.stream_session_id,
+                    });
+                    self.connection.send(&command.to_string()).await?;
+                    continue;
+                }
+            };
+            let record: Record = serde_json::from_str(&frame)?;
+            // Other records (e.g. keep alive) are not subscribed to, but may still arrive
+            if record.command == "tickPrices" {
+                return Ok(serde_json::from_value(record.data)?);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use super::*;
+    use crate::xtb::Timing;
+
+    #[tokio::test]
+    async fn ticks_and_ping() {
+        let (client, mut server) = tokio::io::duplex(4096);
+        let mut stream = PriceStream::new(
+            Connection::new(client, Timing::default()),
+            "stream-1".to_string(),
+            Duration::from_millis(50),
+        );
+        let server = tokio::spawn(async move {
+            server
+                .write_all(b"{\"command\": \"keepAlive\", \"data\": {\"timestamp\": 1}}\n\n")
+                .await
+                .unwrap();
+            server
+                .write_all(b"{\"command\": \"tickPrices\", \"data\": {\"symbol\": \"SPXS.UK\", \"ask\": 101.0, \"bid\": 100.0, \"timestamp\": 2, \"level\": 0}}\n\n")
+                .await
+                .unwrap();
+            // Nothing else is pushed, so client has to ping
+            let mut buffer = vec![0; 4096];
+            let read = server.read(&mut buffer).await.unwrap();
+            // Server end stays open, so that the stream is not closed meanwhile
+            (server, String::from_utf8(buffer[..read].to_vec()).unwrap())
+        });
+
+        assert_eq!(
+            stream.next().await.unwrap(),
+            TickPrice {
+                symbol: "SPXS.UK".to_string(),
+                ask: 101.0,
+                bid: 100.0,
+                timestamp: 2,
+            }
+        );
+        tokio::select! {
+            _ = stream.next() => panic!("No more ticks were pushed"),
+            server = server => {
+                let (_server, ping) = server.unwrap();
+                let ping: serde_json::Value = serde_json::from_str(&ping).unwrap();
+                assert_eq!(ping["command"], "ping");
+                assert_eq!(ping["streamSessionId"], "stream-1");
+            }
+        }
+    }
+}